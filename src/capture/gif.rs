@@ -0,0 +1,220 @@
+use std::io;
+use std::io::prelude::*;
+
+//Animated GIF writer for two colour frames.
+//Only the bounding box of the pixels that changed since the previous frame is
+//encoded, and frames that did not change at all just extend the delay of the
+//previous one, so long recordings of mostly static screens stay small.
+
+const MIN_CODE_SIZE: u8 = 2;
+const MAX_CODE: u16 = 4095;
+
+pub struct GifEncoder<W: Write> {
+    out: W,
+    width: u16,
+    height: u16,
+    previous: Vec<bool>,
+    pending: Option<Frame>
+}
+
+struct Frame {
+    left: u16,
+    top: u16,
+    width: u16,
+    height: u16,
+    pixels: Vec<bool>,
+    delay: u16
+}
+
+impl<W: Write> GifEncoder<W> {
+    pub fn new(mut out: W, width: u16, height: u16, palette: [(u8, u8, u8);2]) -> io::Result<GifEncoder<W>> {
+        out.write_all(b"GIF89a")?;
+        out.write_all(&le16(width))?;
+        out.write_all(&le16(height))?;
+        //Global colour table of 2 entries, 1 bit colour resolution
+        out.write_all(&[0x80, 0, 0])?;
+        for &(r, g, b) in palette.iter() {
+            out.write_all(&[r, g, b])?;
+        }
+        //NETSCAPE2.0 extension, loop forever
+        out.write_all(&[0x21, 0xFF, 0x0B])?;
+        out.write_all(b"NETSCAPE2.0")?;
+        out.write_all(&[0x03, 0x01, 0x00, 0x00, 0x00])?;
+        Ok(GifEncoder {
+            out,
+            width,
+            height,
+            previous: vec![],
+            pending: None
+        })
+    }
+
+    //Adds a frame that stays on screen for delay hundredths of a second
+    pub fn add_frame(&mut self, pixels: &[bool], delay: u16) -> io::Result<()> {
+        let width = self.width as usize;
+        if pixels.len() != width * self.height as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame size does not match GIF size"));
+        }
+
+        let bounds = if self.previous.is_empty() {
+            Some((0, 0, width - 1, self.height as usize - 1))
+        } else {
+            changed_bounds(&self.previous, pixels, width)
+        };
+        match bounds {
+            Some((left, top, right, bottom)) => {
+                let mut sub = Vec::with_capacity((right - left + 1) * (bottom - top + 1));
+                for y in top..bottom + 1 {
+                    sub.extend_from_slice(&pixels[y * width + left..y * width + right + 1]);
+                }
+                let frame = Frame {
+                    left: left as u16,
+                    top: top as u16,
+                    width: (right - left + 1) as u16,
+                    height: (bottom - top + 1) as u16,
+                    pixels: sub,
+                    delay
+                };
+                if let Some(previous) = self.pending.take() {
+                    self.write_frame(&previous)?;
+                }
+                self.pending = Some(frame);
+                self.previous = pixels.to_vec();
+            }
+            None => {
+                //Nothing changed, hold the previous frame for longer
+                if let Some(ref mut frame) = self.pending {
+                    frame.delay = frame.delay.saturating_add(delay);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(frame) = self.pending.take() {
+            self.write_frame(&frame)?;
+        }
+        self.out.write_all(&[0x3B])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        //Graphic control extension: keep the previous frame under this one
+        self.out.write_all(&[0x21, 0xF9, 0x04, 0x04])?;
+        self.out.write_all(&le16(frame.delay))?;
+        self.out.write_all(&[0x00, 0x00])?;
+        //Image descriptor, no local colour table
+        self.out.write_all(&[0x2C])?;
+        self.out.write_all(&le16(frame.left))?;
+        self.out.write_all(&le16(frame.top))?;
+        self.out.write_all(&le16(frame.width))?;
+        self.out.write_all(&le16(frame.height))?;
+        self.out.write_all(&[0x00])?;
+
+        self.out.write_all(&[MIN_CODE_SIZE])?;
+        let data = lzw_compress(&frame.pixels);
+        for block in data.chunks(255) {
+            self.out.write_all(&[block.len() as u8])?;
+            self.out.write_all(block)?;
+        }
+        self.out.write_all(&[0x00])
+    }
+}
+
+fn changed_bounds(old: &[bool], new: &[bool], width: usize) -> Option<(usize, usize, usize, usize)> {
+    let mut bounds: Option<(usize, usize, usize, usize)> = None;
+    for (n, (a, b)) in old.iter().zip(new.iter()).enumerate() {
+        if a != b {
+            let (x, y) = (n % width, n / width);
+            bounds = Some(match bounds {
+                Some((left, top, right, bottom)) => (left.min(x), top.min(y), right.max(x), bottom.max(y)),
+                None => (x, y, x, y)
+            });
+        }
+    }
+    bounds
+}
+
+fn le16(value: u16) -> [u8;2] {
+    [value as u8, (value >> 8) as u8]
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    current: u32,
+    bits: u8
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u8) {
+        self.current |= (code as u32) << self.bits;
+        self.bits += size;
+        while self.bits >= 8 {
+            self.bytes.push(self.current as u8);
+            self.current >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.bytes.push(self.current as u8);
+        }
+        self.bytes
+    }
+}
+
+fn lzw_compress(pixels: &[bool]) -> Vec<u8> {
+    let clear = 1u16 << MIN_CODE_SIZE;
+    let end = clear + 1;
+    let mut writer = BitWriter { bytes: vec![], current: 0, bits: 0 };
+    //Dictionary keyed on (prefix code, next index); only indices 0 and 1 are used
+    let mut table: Vec<[u16;2]> = vec![];
+    let mut code_size = MIN_CODE_SIZE + 1;
+    let mut next_code = end + 1;
+
+    let reset = |table: &mut Vec<[u16;2]>| {
+        table.clear();
+        table.resize((MAX_CODE + 1) as usize, [0, 0]);
+    };
+    reset(&mut table);
+    writer.write(clear, code_size);
+
+    let mut prefix: Option<u16> = None;
+    for &lit in pixels {
+        let index = lit as u16;
+        let current = match prefix {
+            None => {
+                prefix = Some(index);
+                continue;
+            }
+            Some(p) => p
+        };
+        let existing = table[current as usize][index as usize];
+        if existing != 0 {
+            prefix = Some(existing);
+            continue;
+        }
+        writer.write(current, code_size);
+        if next_code <= MAX_CODE {
+            table[current as usize][index as usize] = next_code;
+            if next_code == (1 << code_size) && code_size < 12 {
+                code_size += 1;
+            }
+            next_code += 1;
+        } else {
+            writer.write(clear, code_size);
+            reset(&mut table);
+            code_size = MIN_CODE_SIZE + 1;
+            next_code = end + 1;
+        }
+        prefix = Some(index);
+    }
+    if let Some(p) = prefix {
+        writer.write(p, code_size);
+    }
+    writer.write(end, code_size);
+    writer.finish()
+}
@@ -0,0 +1,5 @@
+pub mod gif;
+pub mod png;
+pub mod recorder;
+pub mod screenshot;
+pub mod video;
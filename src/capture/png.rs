@@ -0,0 +1,146 @@
+use std::io;
+use std::io::prelude::*;
+
+//Minimal PNG writer for palette images.
+//Pixels are stored as palette indices and the image data is written
+//with uncompressed deflate blocks, which keeps this free of any zlib dependency.
+
+const SIGNATURE: [u8;8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+pub fn write_indexed<W: Write>(out: &mut W, width: u32, height: u32, palette: [(u8, u8, u8);2], pixels: &[bool]) -> io::Result<()> {
+    let indices: Vec<u8> = pixels.iter().map(|&lit| lit as u8).collect();
+    write_palette(out, width, height, &palette, &indices)
+}
+
+//Pixels are indices into a palette of up to 256 colours, packed into as few
+//bits as the palette needs
+pub fn write_palette<W: Write>(out: &mut W, width: u32, height: u32, palette: &[(u8, u8, u8)], pixels: &[u8]) -> io::Result<()> {
+    if pixels.len() != (width * height) as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "pixel count does not match image size"));
+    }
+    if palette.is_empty() || palette.len() > 256 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "palette must have 1 to 256 colours"));
+    }
+    let bit_depth: u32 = match palette.len() {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8
+    };
+    out.write_all(&SIGNATURE)?;
+
+    let mut ihdr = Vec::new();
+    push_u32(&mut ihdr, width);
+    push_u32(&mut ihdr, height);
+    ihdr.push(bit_depth as u8);
+    ihdr.push(3); //colour type: indexed
+    ihdr.push(0); //compression
+    ihdr.push(0); //filter
+    ihdr.push(0); //interlace
+    write_chunk(out, b"IHDR", &ihdr)?;
+
+    let mut plte = Vec::new();
+    for &(r, g, b) in palette.iter() {
+        plte.push(r);
+        plte.push(g);
+        plte.push(b);
+    }
+    write_chunk(out, b"PLTE", &plte)?;
+
+    //Each scanline is a filter type byte followed by the packed pixels
+    let per_byte = (8 / bit_depth) as usize;
+    let row_bytes = (width as usize).div_ceil(per_byte);
+    let mut raw = Vec::with_capacity((row_bytes + 1) * height as usize);
+    for y in 0..height as usize {
+        raw.push(0);
+        let row = &pixels[y * width as usize..(y + 1) * width as usize];
+        for chunk in row.chunks(per_byte) {
+            let mut byte = 0u8;
+            for (n, &index) in chunk.iter().enumerate() {
+                byte |= index << (8 - bit_depth as usize * (n + 1));
+            }
+            raw.push(byte);
+        }
+    }
+    write_chunk(out, b"IDAT", &zlib_stored(&raw))?;
+    write_chunk(out, b"IEND", &[])
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8;4], data: &[u8]) -> io::Result<()> {
+    let mut len = Vec::new();
+    push_u32(&mut len, data.len() as u32);
+    out.write_all(&len)?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let mut crc = Crc32::new();
+    crc.update(kind);
+    crc.update(data);
+    let mut sum = Vec::new();
+    push_u32(&mut sum, crc.finish());
+    out.write_all(&sum)
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut ret = vec![0x78, 0x01];
+    if data.is_empty() {
+        ret.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    let blocks = data.len().div_ceil(MAX_STORED_BLOCK);
+    for (n, block) in data.chunks(MAX_STORED_BLOCK).enumerate() {
+        let last = n + 1 == blocks;
+        ret.push(if last { 1 } else { 0 });
+        let len = block.len() as u16;
+        ret.push((len & 0xFF) as u8);
+        ret.push((len >> 8) as u8);
+        ret.push((!len & 0xFF) as u8);
+        ret.push((!len >> 8) as u8);
+        ret.extend_from_slice(block);
+    }
+    push_u32(&mut ret, adler32(data));
+    ret
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.push((value >> 24) as u8);
+    buf.push((value >> 16) as u8);
+    buf.push((value >> 8) as u8);
+    buf.push(value as u8);
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+pub struct Crc32 {
+    value: u32
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32 { value: 0xFFFF_FFFF }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.value ^= byte as u32;
+            for _ in 0..8 {
+                if self.value & 1 != 0 {
+                    self.value = (self.value >> 1) ^ 0xEDB8_8320;
+                } else {
+                    self.value >>= 1;
+                }
+            }
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.value
+    }
+}
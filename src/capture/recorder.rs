@@ -0,0 +1,127 @@
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use capture::gif::GifEncoder;
+use capture::screenshot;
+use capture::video::{RawRgbWriter, Y4mWriter};
+use screen::Screen;
+use sound::generator::BeeperConfig;
+use sound::wav::WavCapture;
+
+//Records every emulated frame to a file.
+//The format is picked from the file extension: .gif, .y4m, or .rgb/.raw for raw rgb24.
+//The beeper is recorded alongside into a .wav file with the same name.
+
+//Viewers show GIF frames with a delay under 2 hundredths of a second for
+//a tenth of a second instead, so frames are merged until at least this much
+//time passed, which records 60 fps gameplay at 30-40 fps.
+const MIN_GIF_DELAY: u64 = 2;
+
+enum Output {
+    Gif(GifEncoder<BufWriter<File>>),
+    Y4m(Y4mWriter<BufWriter<File>>),
+    Raw(RawRgbWriter<BufWriter<File>>)
+}
+
+pub struct Recorder {
+    path: PathBuf,
+    output: Output,
+    audio: WavCapture,
+    scale: (u32, u32),
+    fps: u32,
+    frames: u64,
+    //Hundredths of a second already handed out as GIF delays
+    gif_time: u64
+}
+
+impl Recorder {
+    pub fn start(path: &Path, screen: &Screen, scale: (u32, u32), fps: u32, beeper: BeeperConfig) -> io::Result<Recorder> {
+        let scale = (scale.0.max(1), scale.1.max(1));
+        let width = ::screen::WIDTH as u32 * scale.0;
+        let height = ::screen::HEIGHT as u32 * scale.1;
+        let palette = [screen.palette.background, screen.palette.foreground];
+        let extension = match path.extension() {
+            Some(ext) => ext.to_string_lossy().to_lowercase(),
+            None => String::new()
+        };
+        let output = match &extension[..] {
+            "gif" => {
+                if width > u16::MAX as u32 || height > u16::MAX as u32 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("{}x{} is too large for a GIF, use a smaller scale", width, height)));
+                }
+                let file = BufWriter::new(File::create(path)?);
+                Output::Gif(GifEncoder::new(file, width as u16, height as u16, palette)?)
+            }
+            "y4m" => {
+                let file = BufWriter::new(File::create(path)?);
+                Output::Y4m(Y4mWriter::new(file, width, height, fps, palette)?)
+            }
+            "rgb" | "raw" => {
+                let file = BufWriter::new(File::create(path)?);
+                Output::Raw(RawRgbWriter::new(file, palette))
+            }
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "unknown recording format, use a .gif, .y4m, .rgb or .raw file"));
+            }
+        };
+        let audio = WavCapture::create(&path.with_extension("wav"), 44100, beeper)?;
+        Ok(Recorder {
+            path: path.to_path_buf(),
+            output,
+            audio,
+            scale,
+            fps: fps.max(1),
+            frames: 0,
+            gif_time: 0
+        })
+    }
+
+    //Picks a timestamped file name in the working directory
+    pub fn default_path(rom_path: &Path, extension: &str) -> PathBuf {
+        PathBuf::from(format!("{}-{}.{}", screenshot::file_stem(rom_path), screenshot::timestamp(), extension))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    //Size of a frame in the output, for raw streams that carry no header
+    pub fn frame_size(&self) -> (u32, u32) {
+        (::screen::WIDTH as u32 * self.scale.0, ::screen::HEIGHT as u32 * self.scale.1)
+    }
+
+    pub fn add_frame(&mut self, screen: &Screen, beeping: bool) -> io::Result<()> {
+        let (_, _, pixels) = screenshot::scaled_pixels(screen, self.scale);
+        self.audio.add_frame(beeping, self.fps)?;
+        self.frames += 1;
+        match self.output {
+            Output::Gif(ref mut gif) => {
+                //GIF delays are in hundredths of a second, so spread the
+                //rounding error over the frames instead of accumulating it
+                let elapsed = self.frames * 100 / self.fps as u64;
+                let delay = elapsed - self.gif_time;
+                if delay < MIN_GIF_DELAY {
+                    return Ok(());
+                }
+                self.gif_time = elapsed;
+                gif.add_frame(&pixels, delay as u16)
+            }
+            Output::Y4m(ref mut y4m) => y4m.add_frame(&pixels),
+            Output::Raw(ref mut raw) => raw.add_frame(&pixels)
+        }
+    }
+
+    pub fn finish(self) -> io::Result<u64> {
+        self.audio.finish()?;
+        match self.output {
+            Output::Gif(gif) => { gif.finish()?; }
+            Output::Y4m(y4m) => { y4m.finish()?; }
+            Output::Raw(raw) => { raw.finish()?; }
+        }
+        Ok(self.frames)
+    }
+}
@@ -0,0 +1,66 @@
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use capture::png;
+use screen;
+use screen::Screen;
+
+//Writes the screen at native resolution and at the given integer scale.
+//Returns the paths of the files written.
+pub fn save(screen: &Screen, rom_path: &Path, scale: (u32, u32)) -> io::Result<Vec<PathBuf>> {
+    let base = file_stem(rom_path);
+    let stamp = timestamp();
+    let mut written = Vec::new();
+
+    let native = PathBuf::from(format!("{}-{}.png", base, stamp));
+    write(screen, &native, (1, 1))?;
+    written.push(native);
+
+    let (x_scale, y_scale) = (scale.0.max(1), scale.1.max(1));
+    if x_scale > 1 || y_scale > 1 {
+        let scaled = PathBuf::from(format!("{}-{}-{}x{}.png", base, stamp,
+                                           screen::WIDTH as u32 * x_scale, screen::HEIGHT as u32 * y_scale));
+        write(screen, &scaled, (x_scale, y_scale))?;
+        written.push(scaled);
+    }
+    Ok(written)
+}
+
+pub fn write(screen: &Screen, path: &Path, scale: (u32, u32)) -> io::Result<()> {
+    let (width, height, pixels) = scaled_pixels(screen, scale);
+    let palette = [screen.palette.background, screen.palette.foreground];
+    let mut file = BufWriter::new(File::create(path)?);
+    png::write_indexed(&mut file, width, height, palette, &pixels)
+}
+
+//Row major copy of the screen, each pixel repeated scale times in each direction
+pub fn scaled_pixels(screen: &Screen, scale: (u32, u32)) -> (u32, u32, Vec<bool>) {
+    let (x_scale, y_scale) = scale;
+    let width = screen::WIDTH as u32 * x_scale;
+    let height = screen::HEIGHT as u32 * y_scale;
+    let mut pixels = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            pixels.push(screen.get_pixel((x / x_scale) as usize, (y / y_scale) as usize));
+        }
+    }
+    (width, height, pixels)
+}
+
+pub fn file_stem(rom_path: &Path) -> String {
+    match rom_path.file_stem() {
+        Some(stem) => stem.to_string_lossy().into_owned(),
+        None => "chipy8".to_string()
+    }
+}
+
+//Milliseconds since the epoch, enough to keep captures unique and ordered
+pub fn timestamp() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() * 1000 + d.subsec_millis() as u64,
+        Err(_) => 0
+    }
+}
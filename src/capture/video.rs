@@ -0,0 +1,87 @@
+use std::io;
+use std::io::prelude::*;
+
+//Uncompressed video streams for handing off to an external encoder, e.g.
+//  ffmpeg -i capture.y4m capture.mp4
+//  ffmpeg -f rawvideo -pixel_format rgb24 -video_size 64x32 -framerate 60 -i capture.rgb capture.mp4
+
+pub struct Y4mWriter<W: Write> {
+    out: W,
+    colors: [(u8, u8, u8);2]
+}
+
+impl<W: Write> Y4mWriter<W> {
+    pub fn new(mut out: W, width: u32, height: u32, fps: u32, palette: [(u8, u8, u8);2]) -> io::Result<Y4mWriter<W>> {
+        writeln!(out, "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444 XCOLORRANGE=FULL", width, height, fps)?;
+        Ok(Y4mWriter {
+            out,
+            colors: [rgb_to_yuv(palette[0]), rgb_to_yuv(palette[1])]
+        })
+    }
+
+    pub fn add_frame(&mut self, pixels: &[bool]) -> io::Result<()> {
+        self.out.write_all(b"FRAME\n")?;
+        let mut plane = Vec::with_capacity(pixels.len());
+        for channel in 0..3 {
+            plane.clear();
+            for &lit in pixels {
+                let (y, u, v) = self.colors[lit as usize];
+                plane.push(match channel {
+                    0 => y,
+                    1 => u,
+                    _ => v
+                });
+            }
+            self.out.write_all(&plane)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+pub struct RawRgbWriter<W: Write> {
+    out: W,
+    palette: [(u8, u8, u8);2]
+}
+
+impl<W: Write> RawRgbWriter<W> {
+    pub fn new(out: W, palette: [(u8, u8, u8);2]) -> RawRgbWriter<W> {
+        RawRgbWriter {
+            out,
+            palette
+        }
+    }
+
+    pub fn add_frame(&mut self, pixels: &[bool]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(pixels.len() * 3);
+        for &lit in pixels {
+            let (r, g, b) = self.palette[lit as usize];
+            frame.push(r);
+            frame.push(g);
+            frame.push(b);
+        }
+        self.out.write_all(&frame)
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+//Full range BT.601, flagged as such in the stream header
+fn rgb_to_yuv(rgb: (u8, u8, u8)) -> (u8, u8, u8) {
+    let (r, g, b) = (rgb.0 as f32, rgb.1 as f32, rgb.2 as f32);
+    let y = 0.299 * r + 0.587 * g + 0.114 * b;
+    let u = 128.0 - 0.168736 * r - 0.331264 * g + 0.5 * b;
+    let v = 128.0 + 0.5 * r - 0.418688 * g - 0.081312 * b;
+    (clamp(y), clamp(u), clamp(v))
+}
+
+fn clamp(value: f32) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
@@ -0,0 +1,234 @@
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use capture::png::Crc32;
+use cpu::asm::parse_number;
+use cpu::cpu::Cpu;
+
+//Cheat search and freezing over the RAM and the V registers.
+//
+//A search starts from a snapshot of every location, each step keeps the
+//locations that compare as asked against the previous snapshot and takes a
+//new one. Frozen cheats are written back at the start of every frame.
+
+//Where cheats are kept unless --cheat-dir says otherwise
+pub const DEFAULT_DIR: &str = "cheats";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Location {
+    Ram(u16),
+    Register(u8)
+}
+
+impl Location {
+    //"V3", or an address in any of the assembler's number formats
+    pub fn parse(text: &str) -> Option<Location> {
+        let upper = text.trim().to_uppercase();
+        if upper.len() == 2 && upper.starts_with('V') {
+            return u8::from_str_radix(&upper[1..], 16).ok().map(Location::Register);
+        }
+        parse_number(&upper).map(Location::Ram)
+    }
+
+    pub fn read(&self, cpu: &Cpu) -> Option<u8> {
+        match *self {
+            Location::Ram(address) => cpu.ram().get(address as usize).cloned(),
+            Location::Register(x) => Some(cpu.registers().gpr[x as usize])
+        }
+    }
+
+    pub fn write(&self, cpu: &mut Cpu, value: u8) {
+        match *self {
+            Location::Ram(address) => {
+                cpu.write_ram(address as usize, &[value]);
+            }
+            Location::Register(x) => {
+                let mut registers = cpu.registers();
+                registers.gpr[x as usize] = value;
+                cpu.set_registers(&registers);
+            }
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Location::Ram(address) => write!(f, "0x{:04X}", address),
+            Location::Register(x) => write!(f, "V{:X}", x)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compare {
+    Unchanged,
+    Changed,
+    Increased,
+    Decreased,
+    Equal(u8)
+}
+
+pub struct Search {
+    //Locations still matching with their value at the last step
+    candidates: Vec<(Location, u8)>
+}
+
+impl Search {
+    pub fn new(cpu: &Cpu) -> Search {
+        let registers = (0..16).map(Location::Register);
+        let ram = (0..cpu.ram().len()).map(|a| Location::Ram(a as u16));
+        Search {
+            candidates: registers.chain(ram).map(|l| (l, l.read(cpu).unwrap_or(0))).collect()
+        }
+    }
+
+    pub fn filter(&mut self, cpu: &Cpu, compare: Compare) {
+        let mut kept = vec![];
+        for &(location, old) in &self.candidates {
+            let new = location.read(cpu).unwrap_or(0);
+            let keep = match compare {
+                Compare::Unchanged => new == old,
+                Compare::Changed => new != old,
+                Compare::Increased => new > old,
+                Compare::Decreased => new < old,
+                Compare::Equal(value) => new == value
+            };
+            if keep {
+                kept.push((location, new));
+            }
+        }
+        self.candidates = kept;
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    //"location: value" lines for the first few candidates
+    pub fn describe(&self, cpu: &Cpu, max: usize) -> Vec<String> {
+        self.candidates.iter().take(max).map(|&(location, _)| {
+            format!("{}: {:02X}", location, location.read(cpu).unwrap_or(0))
+        }).collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cheat {
+    pub name: String,
+    pub location: Location,
+    pub value: u8
+}
+
+//The cheats for one ROM, saved in a text file named after the ROM's CRC32
+//with one "location value name" line per cheat
+pub struct Cheats {
+    path: PathBuf,
+    pub cheats: Vec<Cheat>
+}
+
+impl Cheats {
+    //No cheats yet, saved to the ROM's file in directory
+    pub fn new(directory: &Path, rom: &[u8]) -> Cheats {
+        let mut crc = Crc32::new();
+        crc.update(rom);
+        Cheats { path: directory.join(format!("{:08X}.txt", crc.finish())), cheats: vec![] }
+    }
+
+    //Loads the saved cheats for the ROM, if there are any
+    pub fn load(directory: &Path, rom: &[u8]) -> Result<Cheats, String> {
+        let mut cheats = Cheats::new(directory, rom);
+        let mut text = String::new();
+        match File::open(&cheats.path).and_then(|mut f| f.read_to_string(&mut text)) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(cheats),
+            Err(why) => return Err(format!("couldn't read cheats from \"{}\": {}", cheats.path.display(), why))
+        }
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            let mut parts = line.splitn(3, char::is_whitespace);
+            let location = parts.next().and_then(Location::parse);
+            let value = parts.next().and_then(parse_number);
+            match (location, value) {
+                (Some(location), Some(value)) if value <= 0xFF => cheats.cheats.push(Cheat {
+                    name: parts.next().unwrap_or("").trim().to_string(),
+                    location,
+                    value: value as u8
+                }),
+                _ => return Err(format!("{}:{}: expected LOCATION VALUE [NAME]", cheats.path.display(), n + 1))
+            }
+        }
+        Ok(cheats)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(directory) = self.path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        let mut file = File::create(&self.path)?;
+        writeln!(file, "; chipy8 cheats, LOCATION VALUE NAME")?;
+        for cheat in &self.cheats {
+            writeln!(file, "{} 0x{:02X} {}", cheat.location, cheat.value, cheat.name)?;
+        }
+        Ok(())
+    }
+
+    //Replaces any cheat on the same location
+    pub fn freeze(&mut self, cheat: Cheat) {
+        self.cheats.retain(|c| c.location != cheat.location);
+        self.cheats.push(cheat);
+    }
+
+    //Removes the cheats whose name or location match, returns how many
+    pub fn unfreeze(&mut self, what: &str) -> usize {
+        let before = self.cheats.len();
+        let location = Location::parse(what);
+        self.cheats.retain(|c| !(c.name.eq_ignore_ascii_case(what) || Some(c.location) == location));
+        before - self.cheats.len()
+    }
+
+    //Writes the frozen values, called once per frame
+    pub fn apply(&self, cpu: &mut Cpu) {
+        for cheat in &self.cheats {
+            cheat.location.write(cpu, cheat.value);
+        }
+    }
+
+    pub fn describe(&self) -> Vec<String> {
+        self.cheats.iter().map(|c| format!("{} = {:02X} {}", c.location, c.value, c.name)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use cpu::config::Config;
+    use cpu::cpu::Cpu;
+    use super::{Cheat, Cheats, Location};
+
+    #[test]
+    fn frozen_ram_reaches_cached_instructions() {
+        //LD V2, #01 then JP back to it
+        let mut cpu = Cpu::new(&vec![0x62, 0x01, 0x12, 0x00], &Config::new()).unwrap();
+        for _ in 0..2 {
+            cpu.execute_next_instruction();
+        }
+        let mut cheats = Cheats { path: PathBuf::new(), cheats: vec![] };
+        cheats.freeze(Cheat { name: "V2".to_string(), location: Location::Ram(0x201), value: 9 });
+        cheats.apply(&mut cpu);
+        cpu.execute_next_instruction();
+        assert_eq!(cpu.registers().gpr[2], 9);
+    }
+}
@@ -0,0 +1,515 @@
+use cpu::asm::parse_number;
+use cpu::config::{Config, Platform, Quirks, PLATFORMS, PROFILES};
+use cpu::trace::TraceFilter;
+use input::Mappings;
+use rom::Patch;
+use screen::{Palette, PALETTES};
+use sound::generator::{BeeperConfig, Waveform};
+use terminal::render::Style;
+
+pub enum Command {
+    Run(RunOptions),
+    //Run in the terminal instead of a window, keys are let go of key_hold ms
+    //after the terminal last sent them
+    Term { options: RunOptions, style: Style, key_hold: u64 },
+    Disasm(RunOptions),
+    Asm { source: String, output: String, base: u16 },
+    Test { options: RunOptions, frames: u32, screenshot: Option<String> },
+    Info(RunOptions),
+    //Control flow graph, or the call graph with calls set, written as DOT
+    Cfg { options: RunOptions, calls: bool, output: Option<String> },
+    Bench { options: RunOptions, frames: u32 },
+    Help
+}
+
+pub struct RunOptions {
+    pub rom: String,
+    pub scale: u32,
+    pub palette: Palette,
+    pub cpu: Config,
+    pub patch: Patch,
+    pub instructions_per_frame: u32,
+    pub mapping: Mappings,
+    pub paused: bool,
+    //Reload the ROM when the file changes
+    pub watch: bool,
+    //Go back to the command line key mapping and palette on reload
+    pub watch_fresh: bool,
+    pub load_state: Option<String>,
+    pub record: Option<String>,
+    pub wav: Option<String>,
+    pub trace: Option<String>,
+    pub trace_filter: TraceFilter,
+    //Profiler report and collapsed call chains for flame graphs
+    pub profile: Option<String>,
+    pub profile_stacks: Option<String>,
+    //Listing or .png image of how each ROM byte was used
+    pub coverage: Option<String>,
+    //Warn about undefined or suspicious behaviour
+    pub strict: bool,
+    //Read cheat search commands from stdin
+    pub console: bool,
+    //Where the cheats for each ROM are saved, "cheats" unless given. A
+    //headless test only loads cheats when it is given.
+    pub cheat_dir: Option<String>,
+    //Open the debug window at startup
+    pub debug: bool,
+    //Local port for the GDB remote protocol server
+    pub gdb: Option<u16>,
+    pub beeper: BeeperConfig,
+    //More copies of the ROM run side by side with the first
+    pub compare: Vec<CompareOptions>
+}
+
+//The settings of an instance added with --compare, the same as the first
+//instance's apart from the options given
+pub struct CompareOptions {
+    //The options as typed, to tell the instances apart
+    pub label: String,
+    pub cpu: Config,
+    pub patch: Patch,
+    pub instructions_per_frame: u32,
+    pub palette: Palette
+}
+
+//What --compare may change
+static COMPARE_OPTIONS: [&str;12] = ["--platform", "--base", "--patch", "--no-patch", "--quirks", "--stack", "--stack-overflow",
+                                             "--stack-in-ram", "--no-decode-cache", "--ipf", "--seed", "--palette"];
+
+impl RunOptions {
+    fn new(rom: String) -> RunOptions {
+        RunOptions {
+            rom,
+            scale: 16,
+            palette: Palette::new(),
+            cpu: Config::new(),
+            patch: Patch::Auto,
+            instructions_per_frame: 30,
+            mapping: Mappings::Default,
+            paused: false,
+            watch: false,
+            watch_fresh: false,
+            load_state: None,
+            record: None,
+            wav: None,
+            trace: None,
+            trace_filter: TraceFilter::new(),
+            profile: None,
+            profile_stacks: None,
+            coverage: None,
+            strict: false,
+            console: false,
+            cheat_dir: None,
+            debug: false,
+            gdb: None,
+            beeper: BeeperConfig::new(),
+            compare: vec![]
+        }
+    }
+}
+
+pub fn usage() -> String {
+    format!("Usage: chipy8 [run] ROM [options]
+       chipy8 disasm ROM [--platform NAME] [--base ADDRESS] [--patch FILE]
+       chipy8 asm SOURCE [-o OUTPUT] [--base ADDRESS]
+       chipy8 term ROM [--braille] [--key-hold MS] [options]
+       chipy8 test ROM [--frames N] [--screenshot FILE] [options]
+       chipy8 info ROM [--platform NAME] [--base ADDRESS] [--patch FILE]
+       chipy8 bench ROM [--frames N] [options]
+       chipy8 cfg ROM [--calls] [-o FILE] [--base ADDRESS] [--quirks PROFILE] [--patch FILE]
+
+Options for run, term and test:
+  --platform NAME        {}, sets the RAM size and so the largest ROM
+  --base ADDRESS         load and start the ROM here, e.g. 0x600 for ETI-660 (default 0x200)
+  --patch FILE           apply an IPS or BPS patch, by default GAME.ips or GAME.bps next to the ROM is used
+  --no-patch             don't apply a patch found next to the ROM
+  --scale N              window size as a multiple of 64x32 (default 16)
+  --palette NAME         {} or BACKGROUND,FOREGROUND as RRGGBB
+  --quirks PROFILE       {}
+  --stack N              call stack entries, or unbounded, after --quirks (default from the quirk profile: unbounded, vip 12, schip 16)
+  --stack-overflow MODE  error or wrap, what CALL on a full stack does, after --quirks
+  --no-decode-cache      decode every instruction as it runs instead of keeping decoded instructions
+  --stack-in-ram         keep the call stack in RAM at 0xEA0 like the COSMAC VIP
+  --ipf N                instructions per 60Hz frame (default 30)
+  --mapping NAME         default, alt or tetris
+  --seed N               seed for the random number generator
+  --paused               start paused, step with N and resume with P
+  --watch                restart whenever the ROM file or its patch changes, keeping the key mapping and palette
+  --watch-fresh          like --watch, but also go back to the key mapping and palette given here
+  --load-state FILE      restore a state saved with F5
+  --record FILE          record to a .gif, .y4m or .rgb file
+  --wav FILE             record the beeper to a .wav file
+  --trace FILE           log every executed instruction and the registers it changed, - for stdout
+  --trace-range A-B      only trace instructions at addresses A to B, e.g. 0x200-0x2FF
+  --trace-op LIST        only trace these mnemonics, e.g. DRW,CALL,RET
+  --profile FILE         count executed instructions and write the busiest subroutines, addresses and opcodes on exit, - for stdout
+  --profile-stacks FILE  write the instructions run by each call chain on exit, in the collapsed format flame graph tools read
+  --strict               warn about uninitialised reads, font and code writes, deep calls, I overflow and bad keys
+  --coverage FILE        on exit write which ROM bytes were executed, read or written, as a listing or as an image for a .png
+  --console              read cheat search commands from stdin, type help for the list
+  --cheat-dir DIR        where cheats are saved, one file per ROM CRC32 (default cheats, test only loads them when given)
+  --debug                open the debug window at startup, F8 toggles it
+  --gdb PORT             wait for a debugger to attach over the GDB remote protocol on 127.0.0.1:PORT
+  --beep-freq HZ         beeper frequency
+  --beep-wave NAME       square, triangle, sine or noise
+  --beep-volume V        beeper volume from 0.0 to 1.0
+  --beep-attack MS       beeper fade in time
+  --beep-release MS      beeper fade out time
+
+Options for term:
+  --braille              draw 2x4 pixels per character instead of 1x2, for terminals smaller than 64x17
+  --key-hold MS          how long a key counts as held after the terminal sends it, terminals don't tell when keys are let go (default 250)
+
+Options for run:
+  --compare OPTIONS      run another instance of the ROM in the same window with some options changed, e.g. --compare \"--quirks vip\"
+                         can be given more than once, allows {}",
+            PLATFORMS.join(", "), PALETTES.join(", "), PROFILES.join(", "), COMPARE_OPTIONS.join(" "))
+}
+
+pub fn parse(args: Vec<String>) -> Result<Command, String> {
+    let mut args = args.into_iter();
+    let first = match args.next() {
+        Some(a) => a,
+        None => return Ok(Command::Help)
+    };
+    match &first[..] {
+        "-h" | "--help" | "help" => Ok(Command::Help),
+        "disasm" | "info" => {
+            let rom = positional(&mut args, "ROM")?;
+            let mut options = RunOptions::new(rom);
+            while let Some(arg) = args.next() {
+                if !run_option(&mut options, &arg, &mut args)? {
+                    return Err(format!("unknown option {}", arg));
+                }
+            }
+            if first == "disasm" {
+                Ok(Command::Disasm(options))
+            } else {
+                Ok(Command::Info(options))
+            }
+        }
+        "asm" => {
+            let source = positional(&mut args, "SOURCE")?;
+            let mut output = None;
+            let mut base = 0x200;
+            while let Some(arg) = args.next() {
+                match &arg[..] {
+                    "-o" | "--output" => output = Some(value(&mut args, &arg)?),
+                    "--base" => base = address(&mut args, &arg)?,
+                    _ => return Err(format!("unknown option {}", arg))
+                }
+            }
+            let output = match output {
+                Some(o) => o,
+                None => default_output(&source)
+            };
+            Ok(Command::Asm { source, output, base })
+        }
+        "bench" => {
+            let rom = positional(&mut args, "ROM")?;
+            let mut options = RunOptions::new(rom);
+            let mut frames = 6000;
+            while let Some(arg) = args.next() {
+                match &arg[..] {
+                    "--frames" => frames = number(&mut args, &arg)?,
+                    _ => {
+                        if !run_option(&mut options, &arg, &mut args)? {
+                            return Err(format!("unknown option {}", arg));
+                        }
+                    }
+                }
+            }
+            Ok(Command::Bench { options, frames })
+        }
+        "cfg" => {
+            let rom = positional(&mut args, "ROM")?;
+            let mut options = RunOptions::new(rom);
+            let mut calls = false;
+            let mut output = None;
+            while let Some(arg) = args.next() {
+                match &arg[..] {
+                    "--calls" => calls = true,
+                    "-o" | "--output" => output = Some(value(&mut args, &arg)?),
+                    _ => {
+                        if !run_option(&mut options, &arg, &mut args)? {
+                            return Err(format!("unknown option {}", arg));
+                        }
+                    }
+                }
+            }
+            Ok(Command::Cfg { options, calls, output })
+        }
+        "run" => {
+            let rom = positional(&mut args, "ROM")?;
+            run_command(rom, args)
+        }
+        "term" => {
+            let rom = positional(&mut args, "ROM")?;
+            let mut options = RunOptions::new(rom);
+            let mut style = Style::HalfBlock;
+            let mut key_hold = 250;
+            while let Some(arg) = args.next() {
+                match &arg[..] {
+                    "--braille" => style = Style::Braille,
+                    "--key-hold" => key_hold = number(&mut args, &arg)?,
+                    _ => {
+                        if !run_option(&mut options, &arg, &mut args)? {
+                            return Err(format!("unknown option {}", arg));
+                        }
+                    }
+                }
+            }
+            Ok(Command::Term { options, style, key_hold })
+        }
+        "test" => {
+            let rom = positional(&mut args, "ROM")?;
+            let mut options = RunOptions::new(rom);
+            let mut frames = 600;
+            let mut screenshot = None;
+            while let Some(arg) = args.next() {
+                match &arg[..] {
+                    "--frames" => frames = number(&mut args, &arg)?,
+                    "--screenshot" => screenshot = Some(value(&mut args, &arg)?),
+                    _ => {
+                        if !run_option(&mut options, &arg, &mut args)? {
+                            return Err(format!("unknown option {}", arg));
+                        }
+                    }
+                }
+            }
+            Ok(Command::Test { options, frames, screenshot })
+        }
+        _ => {
+            //chipy8 ROM [options] is the same as chipy8 run ROM [options]
+            if first.starts_with('-') {
+                return Err(format!("expected a ROM file or command before {}", first));
+            }
+            run_command(first, args)
+        }
+    }
+}
+
+fn run_command<I: Iterator<Item = String>>(rom: String, mut args: I) -> Result<Command, String> {
+    let mut options = RunOptions::new(rom);
+    let mut compare = vec![];
+    while let Some(arg) = args.next() {
+        if arg == "--compare" {
+            compare.push(value(&mut args, &arg)?);
+        } else if !run_option(&mut options, &arg, &mut args)? {
+            return Err(format!("unknown option {}", arg));
+        }
+    }
+    //Only applied once all the options of the first instance are known
+    for label in compare {
+        let instance = compare_options(&options, &label)?;
+        options.compare.push(instance);
+    }
+    Ok(Command::Run(options))
+}
+
+fn compare_options(options: &RunOptions, label: &str) -> Result<CompareOptions, String> {
+    let mut changed = RunOptions::new(options.rom.clone());
+    changed.cpu = options.cpu;
+    changed.patch = options.patch.clone();
+    changed.instructions_per_frame = options.instructions_per_frame;
+    changed.palette = options.palette;
+    let mut args = label.split_whitespace().map(|a| a.to_string());
+    while let Some(arg) = args.next() {
+        if !COMPARE_OPTIONS.contains(&&arg[..]) {
+            return Err(format!("{} can't be changed by --compare, use one of {}", arg, COMPARE_OPTIONS.join(" ")));
+        }
+        run_option(&mut changed, &arg, &mut args)?;
+    }
+    Ok(CompareOptions {
+        label: label.trim().to_string(),
+        cpu: changed.cpu,
+        patch: changed.patch,
+        instructions_per_frame: changed.instructions_per_frame,
+        palette: changed.palette
+    })
+}
+
+//Returns false if the option isn't one shared by run and test
+fn run_option<I: Iterator<Item = String>>(options: &mut RunOptions, arg: &str, args: &mut I) -> Result<bool, String> {
+    match arg {
+        "--scale" => {
+            options.scale = number(args, arg)?;
+            if options.scale == 0 {
+                return Err("--scale must be at least 1".to_string());
+            }
+        }
+        "--palette" => {
+            let name = value(args, arg)?;
+            options.palette = match Palette::from_name(&name) {
+                Some(p) => p,
+                None => return Err(format!("unknown palette \"{}\", use one of {} or BACKGROUND,FOREGROUND as RRGGBB", name, PALETTES.join(", ")))
+            };
+        }
+        "--quirks" => {
+            let name = value(args, arg)?;
+            options.cpu.quirks = match Quirks::from_name(&name) {
+                Some(q) => q,
+                None => return Err(format!("unknown quirk profile \"{}\", use one of {}", name, PROFILES.join(", ")))
+            };
+        }
+        "--stack" => {
+            let text = value(args, arg)?;
+            options.cpu.quirks.stack_depth = match &text.to_lowercase()[..] {
+                "unbounded" => None,
+                _ => match text.parse() {
+                    Ok(0) | Err(_) => return Err(format!("invalid stack size \"{}\" for --stack, use a number of entries or unbounded", text)),
+                    Ok(n) => Some(n)
+                }
+            };
+        }
+        "--stack-overflow" => {
+            let text = value(args, arg)?;
+            options.cpu.quirks.stack_wraps = match &text.to_lowercase()[..] {
+                "error" => false,
+                "wrap" => true,
+                _ => return Err(format!("unknown stack overflow mode \"{}\", use error or wrap", text))
+            };
+        }
+        "--stack-in-ram" => options.cpu.stack_in_ram = true,
+        "--no-decode-cache" => options.cpu.decode_cache = false,
+        "--ipf" => {
+            options.instructions_per_frame = number(args, arg)?;
+            if options.instructions_per_frame == 0 {
+                return Err("--ipf must be at least 1".to_string());
+            }
+        }
+        "--mapping" => {
+            let name = value(args, arg)?;
+            options.mapping = match &name.to_lowercase()[..] {
+                "default" => Mappings::Default,
+                "alt" => Mappings::Alt,
+                "tetris" => Mappings::Tetris,
+                _ => return Err(format!("unknown key mapping \"{}\", use default, alt or tetris", name))
+            };
+        }
+        "--platform" => {
+            let name = value(args, arg)?;
+            options.cpu.platform = match Platform::from_name(&name) {
+                Some(p) => p,
+                None => return Err(format!("unknown platform \"{}\", use one of {}", name, PLATFORMS.join(", ")))
+            };
+        }
+        "--patch" => options.patch = Patch::File(value(args, arg)?),
+        "--no-patch" => options.patch = Patch::Off,
+        "--base" => options.cpu.base_address = address(args, arg)?,
+        "--seed" => options.cpu.seed = Some(number(args, arg)?),
+        "--paused" => options.paused = true,
+        "--watch" => options.watch = true,
+        "--watch-fresh" => {
+            options.watch = true;
+            options.watch_fresh = true;
+        }
+        "--load-state" => options.load_state = Some(value(args, arg)?),
+        "--record" => options.record = Some(value(args, arg)?),
+        "--wav" => options.wav = Some(value(args, arg)?),
+        "--debug" => options.debug = true,
+        "--console" => options.console = true,
+        "--cheat-dir" => options.cheat_dir = Some(value(args, arg)?),
+        "--gdb" => options.gdb = Some(number(args, arg)?),
+        "--trace" => options.trace = Some(value(args, arg)?),
+        "--profile" => options.profile = Some(value(args, arg)?),
+        "--profile-stacks" => options.profile_stacks = Some(value(args, arg)?),
+        "--coverage" => options.coverage = Some(value(args, arg)?),
+        "--strict" => options.strict = true,
+        "--trace-range" => {
+            let text = value(args, arg)?;
+            let mut parts = text.splitn(2, '-');
+            let start = parts.next().and_then(parse_address);
+            let end = parts.next().and_then(parse_address);
+            match (start, end) {
+                (Some(start), Some(end)) if start <= end => {
+                    options.trace_filter.start = start;
+                    options.trace_filter.end = end;
+                }
+                _ => return Err(format!("invalid address range \"{}\" for {}, use START-END", text, arg))
+            }
+        }
+        "--trace-op" => {
+            let text = value(args, arg)?;
+            options.trace_filter.mnemonics = text.split(',').map(|m| m.trim().to_uppercase()).filter(|m| !m.is_empty()).collect();
+        }
+        "--beep-freq" => {
+            let frequency = float(args, arg)?;
+            if frequency == 0.0 {
+                return Err(format!("{} must be more than 0", arg));
+            }
+            options.beeper.frequency = frequency;
+        }
+        "--beep-volume" => {
+            let volume = float(args, arg)?;
+            if !(0.0..=1.0).contains(&volume) {
+                return Err(format!("{} must be between 0.0 and 1.0", arg));
+            }
+            options.beeper.volume = volume;
+        }
+        "--beep-attack" => options.beeper.attack = float(args, arg)?,
+        "--beep-release" => options.beeper.release = float(args, arg)?,
+        "--beep-wave" => {
+            let name = value(args, arg)?;
+            options.beeper.waveform = match Waveform::from_name(&name) {
+                Some(w) => w,
+                None => return Err(format!("unknown waveform \"{}\", use square, triangle, sine or noise", name))
+            };
+        }
+        _ => return Ok(false)
+    }
+    Ok(true)
+}
+
+fn positional<I: Iterator<Item = String>>(args: &mut I, name: &str) -> Result<String, String> {
+    match args.next() {
+        Some(ref a) if a.starts_with('-') => Err(format!("expected {} before {}", name, a)),
+        Some(a) => Ok(a),
+        None => Err(format!("missing {}", name))
+    }
+}
+
+fn value<I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<String, String> {
+    match args.next() {
+        Some(v) => Ok(v),
+        None => Err(format!("missing value for {}", option))
+    }
+}
+
+fn number<T: ::std::str::FromStr, I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<T, String> {
+    let text = value(args, option)?;
+    match text.parse() {
+        Ok(n) => Ok(n),
+        Err(_) => Err(format!("invalid number \"{}\" for {}", text, option))
+    }
+}
+
+fn address<I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<u16, String> {
+    let text = value(args, option)?;
+    match parse_address(&text) {
+        Some(a) => Ok(a),
+        None => Err(format!("invalid address \"{}\" for {}", text, option))
+    }
+}
+
+//Addresses use the assembler's number formats, e.g. 0x600, #600 or 1536
+fn parse_address(text: &str) -> Option<u16> {
+    parse_number(text.trim())
+}
+
+fn float<I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<f32, String> {
+    let n: f32 = number(args, option)?;
+    //"NaN" and "inf" parse, but no option means anything by them
+    if !n.is_finite() {
+        return Err(format!("{} must be a finite number", option));
+    }
+    if n < 0.0 {
+        return Err(format!("{} can't be negative", option));
+    }
+    Ok(n)
+}
+
+fn default_output(source: &str) -> String {
+    let path = ::std::path::Path::new(source);
+    path.with_extension("ch8").to_string_lossy().into_owned()
+}
@@ -0,0 +1,141 @@
+//Paces emulated frames against the host clock and handles pause,
+//frame advance and speed changes.
+
+pub const FRAME_RATE: u32 = 60;
+
+static SPEEDS: [f64;6] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0];
+const NORMAL_SPEED: usize = 2;
+//Frames to catch up on at most after the host stalled, e.g. while dragging the window
+pub const MAX_CATCH_UP: u32 = 4;
+
+pub enum Due {
+    //Emulate this many frames now
+    Run(u32),
+    //Paused, nothing to emulate
+    Idle,
+    //Emulate as many frames as the host allows
+    Turbo,
+    //As fast as possible while the fast forward key is held, skipping
+    //most of the drawing and with the beeper muted
+    FastForward
+}
+
+pub struct Clock {
+    frequency: u64,
+    speed: usize,
+    turbo: bool,
+    fast_forward: bool,
+    paused: bool,
+    advance: bool,
+    next_frame: u64
+}
+
+impl Clock {
+    pub fn new(frequency: u64, now: u64) -> Clock {
+        Clock {
+            frequency,
+            speed: NORMAL_SPEED,
+            turbo: false,
+            fast_forward: false,
+            paused: false,
+            advance: false,
+            next_frame: now
+        }
+    }
+
+    //Works out how many frames are due at host time now, in performance counter ticks
+    pub fn frames_due(&mut self, now: u64) -> Due {
+        if self.fast_forward && !self.paused {
+            self.next_frame = now;
+            return Due::FastForward;
+        }
+        if self.turbo && !self.paused {
+            self.next_frame = now;
+            return Due::Turbo;
+        }
+        let speed = if self.paused { 1.0 } else { SPEEDS[self.speed] };
+        let period = ((self.frequency as f64) / (FRAME_RATE as f64 * speed)) as u64;
+        let mut frames = 0;
+        while now >= self.next_frame {
+            frames += 1;
+            self.next_frame += period.max(1);
+            if frames > MAX_CATCH_UP {
+                self.next_frame = now + period;
+                break;
+            }
+        }
+        if self.paused {
+            if self.advance {
+                self.advance = false;
+                return Due::Run(1);
+            }
+            return Due::Idle;
+        }
+        Due::Run(frames)
+    }
+
+    //Host time left at now until the next frame is due, zero when frames
+    //run as fast as possible
+    pub fn until_next_frame(&self, now: u64) -> u64 {
+        if (self.turbo || self.fast_forward) && !self.paused {
+            return 0;
+        }
+        self.next_frame.saturating_sub(now)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        self.advance = false;
+    }
+
+    //Runs a single frame while paused
+    pub fn advance_frame(&mut self) {
+        if self.paused {
+            self.advance = true;
+        }
+    }
+
+    pub fn faster(&mut self) {
+        if self.turbo {
+            return;
+        }
+        if self.speed + 1 < SPEEDS.len() {
+            self.speed += 1;
+        }
+    }
+
+    pub fn slower(&mut self) {
+        if self.turbo {
+            self.turbo = false;
+        } else if self.speed > 0 {
+            self.speed -= 1;
+        }
+    }
+
+    pub fn toggle_turbo(&mut self) {
+        self.turbo = !self.turbo;
+    }
+
+    pub fn set_fast_forward(&mut self, held: bool) {
+        self.fast_forward = held;
+    }
+
+    pub fn describe(&self) -> String {
+        let speed = if self.fast_forward {
+            "Fast forward".to_string()
+        } else if self.turbo {
+            "Turbo".to_string()
+        } else {
+            format!("{}x", SPEEDS[self.speed])
+        };
+        if self.paused {
+            format!("{} (Paused)", speed)
+        } else {
+            speed
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use capture;
+use capture::png::Crc32;
+use cli::RunOptions;
+use clock;
+use cpu;
+use cpu::config::{Config, Platform};
+use cpu::instruction::{Instruction, Opcode};
+use cpu::state::State;
+use frame;
+use rom;
+use screen;
+use sound::wav::WavCapture;
+
+//The subcommands that don't need a window
+
+pub fn disasm(options: &RunOptions) -> Result<(), String> {
+    let buffer = rom::load(Path::new(&options.rom), &options.cpu, &options.patch)?;
+    for line in cpu::disasm::listing(&buffer, options.cpu.base_address) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+pub fn cfg(options: &RunOptions, calls: bool, output: Option<&String>) -> Result<(), String> {
+    let buffer = rom::load(Path::new(&options.rom), &options.cpu, &options.patch)?;
+    let analysis = cpu::cfg::analyze(&buffer, &options.cpu);
+    let lines = if calls { analysis.call_graph_dot() } else { analysis.cfg_dot() };
+    let output = match output {
+        Some(o) => o,
+        None => {
+            for line in lines {
+                println!("{}", line);
+            }
+            return Ok(());
+        }
+    };
+    let mut text = lines.join("\n");
+    text.push('\n');
+    match File::create(output).and_then(|mut f| f.write_all(text.as_bytes())) {
+        Ok(_) => {}
+        Err(why) => return Err(format!("couldn't write \"{}\": {}", output, why))
+    }
+    let instructions: usize = analysis.blocks.values().map(|b| b.instructions.len()).sum();
+    println!("Wrote {} reachable instructions in {} blocks and {} functions to \"{}\"",
+             instructions, analysis.blocks.len(), analysis.functions.len(), output);
+    if !analysis.unresolved.is_empty() {
+        println!("{} computed jumps could not be resolved", analysis.unresolved.len());
+    }
+    Ok(())
+}
+
+pub fn asm(source_file_name: &str, output_file_name: &str, base: u16) -> Result<(), String> {
+    if Path::new(source_file_name) == Path::new(output_file_name) {
+        return Err("the output file would overwrite the source, use -o to pick another name".to_string());
+    }
+    let mut source = String::new();
+    match File::open(source_file_name).and_then(|mut f| f.read_to_string(&mut source)) {
+        Ok(_) => {}
+        Err(why) => return Err(format!("couldn't read \"{}\": {}", source_file_name, why))
+    }
+    let binary = match cpu::asm::assemble(&source, base) {
+        Ok(b) => b,
+        Err(why) => return Err(format!("{}:{}", source_file_name, why))
+    };
+    //Only warn, the program may target a platform with more memory
+    for &platform in &[Platform::Chip8, Platform::Schip, Platform::XoChip] {
+        let max = platform.max_rom_size(base);
+        if binary.len() <= max {
+            break;
+        }
+        println!("Warning: {} bytes is too large for {:?} (maximum {} bytes)", binary.len(), platform, max);
+    }
+    match File::create(output_file_name).and_then(|mut f| f.write_all(&binary)) {
+        Ok(_) => {
+            println!("Wrote {} bytes to \"{}\"", binary.len(), output_file_name);
+            Ok(())
+        }
+        Err(why) => Err(format!("couldn't write \"{}\": {}", output_file_name, why))
+    }
+}
+
+pub fn info(options: &RunOptions) -> Result<(), String> {
+    let buffer = rom::load(Path::new(&options.rom), &options.cpu, &options.patch)?;
+    let mut crc = Crc32::new();
+    crc.update(&buffer);
+    println!("File:      {}", options.rom);
+    if let Some(patch_path) = rom::find_patch(Path::new(&options.rom), &options.patch) {
+        println!("Patch:     {}", patch_path.display());
+    }
+    println!("Size:      {} bytes ({} bytes free for {:?} at {:#05X})", buffer.len(),
+             options.cpu.platform.max_rom_size(options.cpu.base_address) - buffer.len(),
+             options.cpu.platform, options.cpu.base_address);
+    println!("CRC32:     {:08X}", crc.finish());
+
+    //A linear scan can't tell code from data, so these counts are only a hint
+    //at which quirk profile the ROM may need
+    let mut invalid = 0;
+    let mut shifts = 0;
+    let mut load_store = 0;
+    let mut jump_offset = 0;
+    let mut logic = 0;
+    for word in buffer.chunks(2) {
+        if word.len() < 2 {
+            break;
+        }
+        let instr = Instruction::decode(((word[0] as u16) << 8) | word[1] as u16);
+        match instr.opcode {
+            Opcode::Invalid => invalid += 1,
+            Opcode::Shr | Opcode::Shl => shifts += 1,
+            Opcode::Stri | Opcode::Fetch => load_store += 1,
+            Opcode::B => jump_offset += 1,
+            Opcode::Or | Opcode::And | Opcode::Xor => logic += 1,
+            _ => {}
+        }
+    }
+    println!("Words:     {} ({} don't decode as instructions)", buffer.len() / 2, invalid);
+    println!("Quirk sensitive instructions:");
+    println!("  SHR/SHL           {}", shifts);
+    println!("  LD [I]/LD Vx,[I]  {}", load_store);
+    println!("  JP V0             {}", jump_offset);
+    println!("  OR/AND/XOR        {}", logic);
+    Ok(())
+}
+
+//Runs a ROM without a window or audio device for a number of frames, then
+//prints the screen and registers. Useful for test ROMs and for scripting.
+pub fn test(options: &RunOptions, frames: u32, screenshot: Option<&String>) -> Result<(), String> {
+    let path = Path::new(&options.rom);
+    let buffer = rom::load(path, &options.cpu, &options.patch)?;
+    let mut cpu = match cpu::cpu::Cpu::new(&buffer, &options.cpu) {
+        Ok(c) => c,
+        Err(why) => return Err(format!("couldn't load \"{}\": {}", options.rom, why))
+    };
+    cpu.screen_mut().palette = options.palette;
+    if let Some(ref state_file_name) = options.load_state {
+        cpu.load_state(&State::load(Path::new(state_file_name))?)?;
+    }
+
+    let scale = (options.scale, options.scale);
+    let mut recorder = match options.record {
+        Some(ref record_file_name) => {
+            match capture::recorder::Recorder::start(Path::new(record_file_name), cpu.screen(), scale, clock::FRAME_RATE, options.beeper) {
+                Ok(r) => Some(r),
+                Err(why) => return Err(format!("couldn't record to \"{}\": {}", record_file_name, why))
+            }
+        }
+        None => None
+    };
+    let mut wav_capture = match options.wav {
+        Some(ref wav_file_name) => {
+            match WavCapture::create(Path::new(wav_file_name), 44100, options.beeper) {
+                Ok(w) => Some(w),
+                Err(why) => return Err(format!("couldn't record sound to \"{}\": {}", wav_file_name, why))
+            }
+        }
+        None => None
+    };
+    //Cheats lying around in the working directory shouldn't change test results
+    let mut runner = frame::new_runner(options, &buffer, options.cheat_dir.as_deref())?;
+
+    let mut frame = 0;
+    while frame < frames {
+        runner.poll(&mut cpu)?;
+        if runner.is_killed() {
+            break;
+        }
+        if !runner.run(&mut cpu)? {
+            //Wait for the debugger to resume the cpu
+            thread::sleep(Duration::from_millis(1));
+            continue;
+        }
+        frame += 1;
+        if let Some(ref mut r) = recorder {
+            r.add_frame(cpu.screen(), cpu.get_sound_state()).map_err(|why| why.to_string())?;
+        }
+        if let Some(ref mut w) = wav_capture {
+            w.add_frame(cpu.get_sound_state(), clock::FRAME_RATE).map_err(|why| why.to_string())?;
+        }
+    }
+    runner.finish()?;
+    if let Some(r) = recorder {
+        r.finish().map_err(|why| why.to_string())?;
+    }
+    if let Some(w) = wav_capture {
+        w.finish().map_err(|why| why.to_string())?;
+    }
+    if let Some(screenshot_file_name) = screenshot {
+        capture::screenshot::write(cpu.screen(), Path::new(screenshot_file_name), scale).map_err(|why| why.to_string())?;
+    }
+
+    for y in 0..screen::HEIGHT {
+        let mut row = String::with_capacity(screen::WIDTH);
+        for x in 0..screen::WIDTH {
+            row.push(if cpu.screen().get_pixel(x, y) { '#' } else { '.' });
+        }
+        println!("{}", row);
+    }
+    println!("{:?}", cpu);
+    Ok(())
+}
+
+const BENCH_RUNS: u32 = 3;
+
+//Times headless runs of a ROM with and without the decode cache. Each is
+//run a few times and the fastest counts, to leave out start up noise.
+pub fn bench(options: &RunOptions, frames: u32) -> Result<(), String> {
+    let buffer = rom::load(Path::new(&options.rom), &options.cpu, &options.patch)?;
+    println!("Running \"{}\" for {} frames of {} instructions", options.rom, frames, options.instructions_per_frame);
+    let mut seconds = vec![];
+    for &decode_cache in &[false, true] {
+        let mut config = options.cpu;
+        config.decode_cache = decode_cache;
+        //The same random numbers for both, so they run the same code
+        config.seed = Some(config.seed.unwrap_or(1));
+        let mut best = None;
+        let mut instructions = 0;
+        for _ in 0..BENCH_RUNS {
+            let (count, elapsed) = bench_run(&buffer, &config, options.instructions_per_frame, frames)?;
+            instructions = count;
+            if best.is_none_or(|b| elapsed < b) {
+                best = Some(elapsed);
+            }
+        }
+        let elapsed = best.unwrap_or(0.0).max(1e-9);
+        println!("{:<26}{:>11} instructions in {:.3}s, {:.2} million per second",
+                 if decode_cache { "With the decode cache:" } else { "Without the decode cache:" },
+                 instructions, elapsed, instructions as f64 / elapsed / 1e6);
+        seconds.push(elapsed);
+    }
+    println!("Speed up: {:.2}x", seconds[0] / seconds[1]);
+    Ok(())
+}
+
+//Instructions executed and seconds taken, stopping early on a fault
+fn bench_run(buffer: &Vec<u8>, config: &Config, instructions_per_frame: u32, frames: u32) -> Result<(u64, f64), String> {
+    let mut cpu = match cpu::cpu::Cpu::new(buffer, config) {
+        Ok(c) => c,
+        Err(why) => return Err(format!("couldn't load the ROM: {}", why))
+    };
+    let mut instructions = 0;
+    let start = Instant::now();
+    'frames: for _ in 0..frames {
+        for _ in 0..instructions_per_frame {
+            cpu.execute_next_instruction();
+            if cpu.fault().is_some() {
+                break 'frames;
+            }
+            instructions += 1;
+        }
+        cpu.tick_timers();
+    }
+    let elapsed = start.elapsed();
+    Ok((instructions, elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9))
+}
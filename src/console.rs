@@ -0,0 +1,173 @@
+use std::io;
+use std::io::prelude::*;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+use cheat::{Cheat, Cheats, Compare, Location, Search};
+use cpu::asm::parse_number;
+use cpu::cpu::Cpu;
+
+//Commands typed on stdin while the emulator runs, for the cheat search.
+//Finding a lives counter goes like
+//
+//  new          start a search while the game runs
+//  same         nothing happened, drop what changed
+//  dec          after losing a life
+//  is 2         the counter shows 2
+//  freeze 0x2F1 3 lives
+//  save
+
+static HELP: &str = "Commands:
+  new                      start a cheat search from the current memory and registers
+  same | changed           keep the locations that stayed the same or changed since the last step
+  inc | dec                keep the locations that increased or decreased
+  is VALUE                 keep the locations holding VALUE
+  list                     show the remaining locations
+  freeze LOCATION VALUE [NAME]   keep a location at VALUE, e.g. freeze 0x2F1 3 lives or freeze V3 0
+  unfreeze NAME|LOCATION|all
+  cheats                   show the frozen locations
+  save                     save the cheats for this ROM
+  poke LOCATION VALUE      write a value once
+  help";
+
+//Candidates listed after each search step
+const LIST_MAX: usize = 20;
+
+pub struct Console {
+    lines: Receiver<String>,
+    search: Option<Search>
+}
+
+impl Console {
+    pub fn start() -> Console {
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            for line in stdin.lock().lines() {
+                match line {
+                    Ok(line) => if sender.send(line).is_err() { break },
+                    Err(_) => break
+                }
+            }
+        });
+        println!("Console ready, type help for the commands");
+        Console {
+            lines: receiver,
+            search: None
+        }
+    }
+
+    //Runs the commands typed since the last call
+    pub fn poll(&mut self, cpu: &mut Cpu, cheats: &mut Cheats) {
+        while let Ok(line) = self.lines.try_recv() {
+            self.execute(&line, cpu, cheats);
+        }
+    }
+
+    fn execute(&mut self, line: &str, cpu: &mut Cpu, cheats: &mut Cheats) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let compare = match words.first().map(|w| w.to_lowercase()) {
+            None => return,
+            Some(ref command) => match &command[..] {
+                "new" => {
+                    let search = Search::new(cpu);
+                    println!("Search started with {} locations", search.len());
+                    self.search = Some(search);
+                    return;
+                }
+                "same" | "eq" => Compare::Unchanged,
+                "changed" | "ne" => Compare::Changed,
+                "inc" => Compare::Increased,
+                "dec" => Compare::Decreased,
+                "is" => match words.get(1).and_then(|v| parse_number(v)) {
+                    Some(value) if value <= 0xFF => Compare::Equal(value as u8),
+                    _ => {
+                        println!("usage: is VALUE");
+                        return;
+                    }
+                },
+                "list" => {
+                    match self.search {
+                        Some(ref search) => print_candidates(search, cpu, usize::MAX),
+                        None => println!("No search running, start one with new")
+                    }
+                    return;
+                }
+                "freeze" => {
+                    let location = words.get(1).and_then(|l| Location::parse(l));
+                    let value = words.get(2).and_then(|v| parse_number(v));
+                    match (location, value) {
+                        (Some(location), Some(value)) if value <= 0xFF => {
+                            let name = words[3..].join(" ");
+                            println!("Freezing {} at {:02X}", location, value);
+                            cheats.freeze(Cheat { name, location, value: value as u8 });
+                        }
+                        _ => println!("usage: freeze LOCATION VALUE [NAME]")
+                    }
+                    return;
+                }
+                "unfreeze" => {
+                    match words.get(1) {
+                        Some(&"all") => {
+                            println!("Removed {} cheats", cheats.cheats.len());
+                            cheats.cheats.clear();
+                        }
+                        Some(_) => println!("Removed {} cheats", cheats.unfreeze(&words[1..].join(" "))),
+                        None => println!("usage: unfreeze NAME|LOCATION|all")
+                    }
+                    return;
+                }
+                "cheats" => {
+                    if cheats.cheats.is_empty() {
+                        println!("No cheats");
+                    }
+                    for line in cheats.describe() {
+                        println!("{}", line);
+                    }
+                    return;
+                }
+                "save" => {
+                    match cheats.save() {
+                        Ok(_) => println!("Saved {} cheats to \"{}\"", cheats.cheats.len(), cheats.path().display()),
+                        Err(why) => println!("Failed to save cheats: {}", why)
+                    }
+                    return;
+                }
+                "poke" => {
+                    let location = words.get(1).and_then(|l| Location::parse(l));
+                    let value = words.get(2).and_then(|v| parse_number(v));
+                    match (location, value) {
+                        (Some(location), Some(value)) if value <= 0xFF => location.write(cpu, value as u8),
+                        _ => println!("usage: poke LOCATION VALUE")
+                    }
+                    return;
+                }
+                "help" => {
+                    println!("{}", HELP);
+                    return;
+                }
+                _ => {
+                    println!("Unknown command \"{}\", type help for the commands", command);
+                    return;
+                }
+            }
+        };
+        match self.search {
+            Some(ref mut search) => {
+                search.filter(cpu, compare);
+                print_candidates(search, cpu, LIST_MAX);
+            }
+            None => println!("No search running, start one with new")
+        }
+    }
+}
+
+fn print_candidates(search: &Search, cpu: &Cpu, max: usize) {
+    println!("{} locations left", search.len());
+    for line in search.describe(cpu, max) {
+        println!("  {}", line);
+    }
+    if search.len() > max {
+        println!("  ...");
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use cpu::instruction::Instruction;
+use cpu::instruction::Opcode;
+use cpu::instruction::Operands;
+
+//Two pass assembler for the mnemonics produced by the disassembler.
+//
+//  ; comments run to the end of the line
+//  start:  LD V0, #0A        ; labels end with a colon
+//          DRW V0, V1, 5
+//          JP start
+//  sprite: DB #F0, #90, %11110000
+//          DW #1234
+//          ORG #300          ; pads with zeros up to the address
+//
+//Numbers can be decimal, hex with a #, $ or 0x prefix, or binary with % or 0b.
+
+#[derive(Debug)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Reg(u8),
+    I,
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    B,
+    Value(String)
+}
+
+struct Line {
+    number: usize,
+    mnemonic: String,
+    operands: Vec<Operand>
+}
+
+pub fn assemble(source: &str, base: u16) -> Result<Vec<u8>, AsmError> {
+    //First pass, find the label addresses
+    let mut labels = HashMap::new();
+    let mut lines = vec![];
+    let mut address = base as u32;
+    for (n, text) in source.lines().enumerate() {
+        let number = n + 1;
+        let mut text = match text.find(';') {
+            Some(comment) => &text[..comment],
+            None => text
+        }.trim();
+        if let Some(colon) = text.find(':') {
+            let label = text[..colon].trim();
+            if label.is_empty() || label.contains(char::is_whitespace) {
+                return Err(error(number, format!("invalid label \"{}\"", label)));
+            }
+            if labels.insert(label.to_lowercase(), address).is_some() {
+                return Err(error(number, format!("label \"{}\" defined twice", label)));
+            }
+            text = text[colon + 1..].trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+        let (mnemonic, rest) = match text.find(char::is_whitespace) {
+            Some(space) => (&text[..space], text[space..].trim()),
+            None => (text, "")
+        };
+        let mnemonic = mnemonic.to_uppercase();
+        let operands: Vec<Operand> = if rest.is_empty() {
+            vec![]
+        } else {
+            rest.split(',').map(|o| parse_operand(o.trim())).collect()
+        };
+        address = match &mnemonic[..] {
+            "DB" => address + operands.len() as u32,
+            "DW" => address + 2 * operands.len() as u32,
+            "ORG" => {
+                if operands.len() != 1 {
+                    return Err(error(number, "ORG takes one address".to_string()));
+                }
+                let target = match operands[0] {
+                    Operand::Value(ref v) => parse_number(v),
+                    _ => None
+                };
+                match target {
+                    Some(target) if target as u32 >= address => target as u32,
+                    _ => return Err(error(number, "ORG needs a number that is not before the current address".to_string()))
+                }
+            }
+            _ => address + 2
+        };
+        lines.push(Line { number, mnemonic, operands });
+    }
+
+    //Second pass, emit the bytes
+    let mut out = vec![];
+    for line in &lines {
+        match &line.mnemonic[..] {
+            "DB" => {
+                for operand in &line.operands {
+                    out.push(value(operand, &labels, 0xFF, line.number)? as u8);
+                }
+            }
+            "DW" => {
+                for operand in &line.operands {
+                    let word = value(operand, &labels, 0xFFFF, line.number)?;
+                    out.push((word >> 8) as u8);
+                    out.push(word as u8);
+                }
+            }
+            "ORG" => {
+                let target = value(&line.operands[0], &labels, 0xFFFF, line.number)? as usize;
+                while (base as usize) + out.len() < target {
+                    out.push(0);
+                }
+            }
+            _ => {
+                let word = encode(line, &labels)?;
+                out.push((word >> 8) as u8);
+                out.push(word as u8);
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn encode(line: &Line, labels: &HashMap<String, u32>) -> Result<u16, AsmError> {
+    use self::Operand::*;
+    let n = line.number;
+    let ops = &line.operands;
+    let none = Operands { reg_x: 0, reg_y: 0, address: 0, imm: 0 };
+    let (opcode, operands) = match (&line.mnemonic[..], &ops[..]) {
+        ("CLS", &[]) => (Opcode::Cls, none),
+        ("RET", &[]) => (Opcode::Ret, none),
+        ("JP", [a]) => (Opcode::Jp, Operands { address: value(a, labels, 0xFFF, n)?, ..none }),
+        ("JP", &[Reg(0), ref a]) => {
+            let address = value(a, labels, 0xFFF, n)?;
+            (Opcode::B, Operands { reg_x: (address >> 8) as u8, address, ..none })
+        }
+        ("CALL", [a]) => (Opcode::Call, Operands { address: value(a, labels, 0xFFF, n)?, ..none }),
+        ("SE", &[Reg(x), Reg(y)]) => (Opcode::Se, Operands { reg_x: x, reg_y: y, ..none }),
+        ("SE", &[Reg(x), ref b]) => (Opcode::Sei, Operands { reg_x: x, imm: value(b, labels, 0xFF, n)? as u8, ..none }),
+        ("SNE", &[Reg(x), Reg(y)]) => (Opcode::Sne, Operands { reg_x: x, reg_y: y, ..none }),
+        ("SNE", &[Reg(x), ref b]) => (Opcode::Snei, Operands { reg_x: x, imm: value(b, labels, 0xFF, n)? as u8, ..none }),
+        ("LD", &[Reg(x), Reg(y)]) => (Opcode::Ld, Operands { reg_x: x, reg_y: y, ..none }),
+        ("LD", &[Reg(x), Dt]) => (Opcode::Lddt, Operands { reg_x: x, ..none }),
+        ("LD", &[Reg(x), K]) => (Opcode::Ldkp, Operands { reg_x: x, ..none }),
+        ("LD", &[Reg(x), IndirectI]) => (Opcode::Fetch, Operands { reg_x: x, ..none }),
+        ("LD", &[Reg(x), ref b]) => (Opcode::Ldi, Operands { reg_x: x, imm: value(b, labels, 0xFF, n)? as u8, ..none }),
+        ("LD", &[I, ref a]) => (Opcode::Seti, Operands { address: value(a, labels, 0xFFF, n)?, ..none }),
+        ("LD", &[Dt, Reg(x)]) => (Opcode::Setdt, Operands { reg_x: x, ..none }),
+        ("LD", &[St, Reg(x)]) => (Opcode::Setst, Operands { reg_x: x, ..none }),
+        ("LD", &[F, Reg(x)]) => (Opcode::Setis, Operands { reg_x: x, ..none }),
+        ("LD", &[B, Reg(x)]) => (Opcode::Ibcd, Operands { reg_x: x, ..none }),
+        ("LD", &[IndirectI, Reg(x)]) => (Opcode::Stri, Operands { reg_x: x, ..none }),
+        ("ADD", &[Reg(x), Reg(y)]) => (Opcode::Add, Operands { reg_x: x, reg_y: y, ..none }),
+        ("ADD", &[Reg(x), ref b]) => (Opcode::Addi, Operands { reg_x: x, imm: value(b, labels, 0xFF, n)? as u8, ..none }),
+        ("ADD", &[I, Reg(x)]) => (Opcode::Addir, Operands { reg_x: x, ..none }),
+        ("OR", &[Reg(x), Reg(y)]) => (Opcode::Or, Operands { reg_x: x, reg_y: y, ..none }),
+        ("AND", &[Reg(x), Reg(y)]) => (Opcode::And, Operands { reg_x: x, reg_y: y, ..none }),
+        ("XOR", &[Reg(x), Reg(y)]) => (Opcode::Xor, Operands { reg_x: x, reg_y: y, ..none }),
+        ("SUB", &[Reg(x), Reg(y)]) => (Opcode::Sub, Operands { reg_x: x, reg_y: y, ..none }),
+        ("SUBN", &[Reg(x), Reg(y)]) => (Opcode::Subn, Operands { reg_x: x, reg_y: y, ..none }),
+        ("SHR", &[Reg(x)]) => (Opcode::Shr, Operands { reg_x: x, reg_y: x, ..none }),
+        ("SHR", &[Reg(x), Reg(y)]) => (Opcode::Shr, Operands { reg_x: x, reg_y: y, ..none }),
+        ("SHL", &[Reg(x)]) => (Opcode::Shl, Operands { reg_x: x, reg_y: x, ..none }),
+        ("SHL", &[Reg(x), Reg(y)]) => (Opcode::Shl, Operands { reg_x: x, reg_y: y, ..none }),
+        ("RND", &[Reg(x), ref b]) => (Opcode::Rnd, Operands { reg_x: x, imm: value(b, labels, 0xFF, n)? as u8, ..none }),
+        ("DRW", &[Reg(x), Reg(y), ref h]) => (Opcode::Drw, Operands { reg_x: x, reg_y: y, imm: value(h, labels, 0xF, n)? as u8, ..none }),
+        ("SKP", &[Reg(x)]) => (Opcode::Skp, Operands { reg_x: x, ..none }),
+        ("SKNP", &[Reg(x)]) => (Opcode::Sknp, Operands { reg_x: x, ..none }),
+        _ => return Err(error(n, format!("unknown instruction or operands: {} {:?}", line.mnemonic, ops)))
+    };
+    Ok(Instruction::find(opcode).encode(&operands))
+}
+
+fn parse_operand(text: &str) -> Operand {
+    let upper = text.to_uppercase();
+    match &upper[..] {
+        "I" => return Operand::I,
+        "[I]" => return Operand::IndirectI,
+        "DT" => return Operand::Dt,
+        "ST" => return Operand::St,
+        "K" => return Operand::K,
+        "F" => return Operand::F,
+        "B" => return Operand::B,
+        _ => {}
+    }
+    if upper.len() == 2 && upper.starts_with('V') {
+        if let Ok(reg) = u8::from_str_radix(&upper[1..], 16) {
+            return Operand::Reg(reg);
+        }
+    }
+    Operand::Value(text.to_string())
+}
+
+pub fn parse_number(text: &str) -> Option<u16> {
+    let lower = text.to_lowercase();
+    let (digits, radix) = if let Some(digits) = lower.strip_prefix('#').or_else(|| lower.strip_prefix('$')) {
+        (digits, 16)
+    } else if let Some(digits) = lower.strip_prefix("0x") {
+        (digits, 16)
+    } else if let Some(digits) = lower.strip_prefix('%') {
+        (digits, 2)
+    } else if let Some(digits) = lower.strip_prefix("0b") {
+        (digits, 2)
+    } else {
+        (&lower[..], 10)
+    };
+    u16::from_str_radix(digits, radix).ok()
+}
+
+fn value(operand: &Operand, labels: &HashMap<String, u32>, max: u16, line: usize) -> Result<u16, AsmError> {
+    let text = match *operand {
+        Operand::Value(ref text) => text,
+        _ => return Err(error(line, format!("expected a number or label, found {:?}", operand)))
+    };
+    let number = match parse_number(text) {
+        Some(number) => number as u32,
+        None => match labels.get(&text.to_lowercase()) {
+            Some(&address) => address,
+            None => return Err(error(line, format!("unknown label or bad number \"{}\"", text)))
+        }
+    };
+    if number > max as u32 {
+        return Err(error(line, format!("{} does not fit in {} bits", text, 16 - max.leading_zeros())));
+    }
+    Ok(number as u16)
+}
+
+fn error(line: usize, message: String) -> AsmError {
+    AsmError { line, message }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use cpu::disasm;
+    use super::assemble;
+
+    fn error(source: &str) -> String {
+        assemble(source, 0x200).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn bundled_roms_round_trip() {
+        let roms = Path::new(env!("CARGO_MANIFEST_DIR")).join("roms");
+        let mut count = 0;
+        for entry in fs::read_dir(roms).unwrap() {
+            let path = entry.unwrap().path();
+            let rom = fs::read(&path).unwrap();
+            //The listing without the address and raw bytes columns
+            let source: Vec<String> = disasm::listing(&rom, 0x200).iter().map(|line| line[12..].to_string()).collect();
+            let assembled = assemble(&source.join("\n"), 0x200).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+            assert!(assembled == rom, "{} doesn't round trip", path.display());
+            count += 1;
+        }
+        assert_eq!(count, 23);
+    }
+
+    #[test]
+    fn labels_and_jumps() {
+        let rom = assemble("start: LD V0, #0A\nJP V0, table\nJP start\ntable: DB 1, %10, 0x3", 0x200).unwrap();
+        assert_eq!(rom, vec![0x60, 0x0A, 0xB2, 0x06, 0x12, 0x00, 0x01, 0x02, 0x03]);
+        assert_eq!(assemble("JP V0, #345", 0x200).unwrap(), vec![0xB3, 0x45]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("a: CLS\nA: CLS"), "line 2: label \"A\" defined twice");
+        assert_eq!(error("ORG #300\nORG #2FE"), "line 2: ORG needs a number that is not before the current address");
+        assert_eq!(error("LD V0, #100"), "line 1: #100 does not fit in 8 bits");
+        assert_eq!(error("JP V0, #1000"), "line 1: #1000 does not fit in 12 bits");
+        assert_eq!(error("DRW V0, V1, 16"), "line 1: 16 does not fit in 4 bits");
+        assert_eq!(error("JP nowhere"), "line 1: unknown label or bad number \"nowhere\"");
+    }
+}
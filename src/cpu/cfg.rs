@@ -0,0 +1,385 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use cpu::config::Config;
+use cpu::disasm;
+use cpu::instruction::{Instruction, Opcode};
+
+//Static control flow analysis. Code is followed from the load address through
+//jumps, calls, returns and skips without running it, then split into basic
+//blocks and into the functions CALL reaches. The graphs are written in
+//Graphviz DOT, e.g. chipy8 cfg GAME | dot -Tsvg -o game.svg
+//
+//BNNN jumps to an address computed at run time, so its targets are guessed:
+//a constant loaded into the offset register right before the jump gives one
+//target, otherwise a table of JP instructions at NNN is followed. Failing
+//both only NNN itself is followed and the jump is reported as unresolved.
+
+//Most entries a jump table indexed by a byte register can have
+const MAX_TABLE: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+    //Falling through to the next instruction
+    Next,
+    Jump,
+    //Past the skipped instruction
+    Skip,
+    //A guessed BNNN target
+    Computed
+}
+
+pub struct Block {
+    pub start: u16,
+    //(address, instruction) pairs
+    pub instructions: Vec<(u16, u16)>,
+    pub successors: Vec<(u16, Edge)>,
+    pub calls: Vec<u16>
+}
+
+pub struct Function {
+    pub entry: u16,
+    //Starts of the blocks reachable from the entry without following calls
+    pub blocks: Vec<u16>,
+    pub calls: Vec<u16>
+}
+
+pub struct Analysis {
+    pub base: u16,
+    pub blocks: BTreeMap<u16, Block>,
+    //The program entry first, then the CALL targets by address
+    pub functions: Vec<Function>,
+    //BNNN jumps only followed to NNN
+    pub unresolved: Vec<u16>,
+    //Jumps, calls and fall throughs to addresses outside the ROM, as (from, to)
+    pub outside: Vec<(u16, u16)>
+}
+
+//What is known about one reachable instruction
+struct Step {
+    word: u16,
+    successors: Vec<(u16, Edge)>,
+    call: Option<u16>,
+    //Control doesn't simply continue with the next instruction
+    ends_block: bool
+}
+
+pub fn analyze(rom: &[u8], config: &Config) -> Analysis {
+    let base = config.base_address;
+    let mut steps: BTreeMap<u16, Step> = BTreeMap::new();
+    let mut leaders = BTreeSet::new();
+    let mut entries = BTreeSet::new();
+    let mut unresolved = vec![];
+    let mut outside = vec![];
+    let mut pending = vec![base];
+    leaders.insert(base);
+    entries.insert(base);
+
+    while let Some(address) = pending.pop() {
+        if steps.contains_key(&address) {
+            continue;
+        }
+        let word = match fetch(rom, base, address) {
+            Some(w) => w,
+            None => continue
+        };
+        let instr = Instruction::decode(word);
+        let operands = instr.get_operands(word);
+        let next = address.wrapping_add(2);
+        let mut successors = vec![];
+        let mut call = None;
+        match instr.opcode {
+            Opcode::Ret | Opcode::Invalid => {}
+            Opcode::Jp => successors.push((operands.address, Edge::Jump)),
+            Opcode::Call => {
+                call = Some(operands.address);
+                successors.push((next, Edge::Next));
+            }
+            Opcode::Sei | Opcode::Snei | Opcode::Se | Opcode::Sne | Opcode::Skp | Opcode::Sknp => {
+                successors.push((next, Edge::Next));
+                successors.push((next.wrapping_add(2), Edge::Skip));
+            }
+            Opcode::B => {
+                let (targets, guessed) = computed_targets(rom, config, address, word);
+                if guessed {
+                    unresolved.push(address);
+                }
+                successors.extend(targets.into_iter().map(|t| (t, Edge::Computed)));
+            }
+            _ => successors.push((next, Edge::Next))
+        }
+        let ends_block = successors != vec![(next, Edge::Next)];
+
+        if let Some(target) = call {
+            if fetch(rom, base, target).is_some() {
+                entries.insert(target);
+                leaders.insert(target);
+                pending.push(target);
+            } else {
+                outside.push((address, target));
+            }
+        }
+        for &(target, _) in &successors {
+            if fetch(rom, base, target).is_none() {
+                outside.push((address, target));
+                continue;
+            }
+            if ends_block {
+                leaders.insert(target);
+            }
+            pending.push(target);
+        }
+        steps.insert(address, Step { word, successors, call, ends_block });
+    }
+
+    let mut blocks = BTreeMap::new();
+    for &start in &leaders {
+        if !steps.contains_key(&start) {
+            continue;
+        }
+        let mut block = Block { start, instructions: vec![], successors: vec![], calls: vec![] };
+        let mut address = start;
+        loop {
+            let step = &steps[&address];
+            block.instructions.push((address, step.word));
+            if let Some(target) = step.call {
+                block.calls.push(target);
+            }
+            let next = address.wrapping_add(2);
+            if step.ends_block || leaders.contains(&next) || !steps.contains_key(&next) {
+                block.successors = step.successors.iter().cloned()
+                    .filter(|&(target, _)| steps.contains_key(&target))
+                    .collect();
+                break;
+            }
+            address = next;
+        }
+        blocks.insert(start, block);
+    }
+
+    let functions = entries.iter().map(|&entry| function(&blocks, entry)).collect();
+    outside.sort();
+    outside.dedup();
+    Analysis {
+        base,
+        blocks,
+        functions,
+        unresolved,
+        outside
+    }
+}
+
+fn fetch(rom: &[u8], base: u16, address: u16) -> Option<u16> {
+    if address < base {
+        return None;
+    }
+    let offset = (address - base) as usize;
+    if offset + 1 >= rom.len() {
+        return None;
+    }
+    Some((rom[offset] as u16) << 8 | rom[offset + 1] as u16)
+}
+
+//The targets of the BNNN at address, and whether they are only a guess
+fn computed_targets(rom: &[u8], config: &Config, address: u16, word: u16) -> (Vec<u16>, bool) {
+    let operands = Instruction::decode(word).get_operands(word);
+    let table = operands.address;
+    let register = if config.quirks.jump_uses_vx { operands.reg_x } else { 0 };
+    //LD Vx, NN right before the jump
+    if let Some(previous) = fetch(rom, config.base_address, address.wrapping_sub(2)) {
+        let instr = Instruction::decode(previous);
+        let loaded = instr.get_operands(previous);
+        if instr.opcode == Opcode::Ldi && loaded.reg_x == register {
+            return (vec![(table + loaded.imm as u16) & 0x0FFF], false);
+        }
+    }
+    //A table of jumps for the register to pick from
+    let mut targets = vec![];
+    let mut entry = table;
+    while targets.len() < MAX_TABLE {
+        match fetch(rom, config.base_address, entry) {
+            Some(w) if Instruction::decode(w).opcode == Opcode::Jp => targets.push(entry),
+            _ => break
+        }
+        entry += 2;
+    }
+    if targets.is_empty() {
+        (vec![table], true)
+    } else {
+        (targets, false)
+    }
+}
+
+fn function(blocks: &BTreeMap<u16, Block>, entry: u16) -> Function {
+    let mut reached = BTreeSet::new();
+    let mut calls = BTreeSet::new();
+    let mut pending = vec![entry];
+    while let Some(start) = pending.pop() {
+        if !reached.insert(start) {
+            continue;
+        }
+        if let Some(block) = blocks.get(&start) {
+            calls.extend(block.calls.iter().cloned());
+            pending.extend(block.successors.iter().map(|&(target, _)| target));
+        }
+    }
+    Function {
+        entry,
+        blocks: reached.into_iter().collect(),
+        calls: calls.into_iter().collect()
+    }
+}
+
+impl Analysis {
+    pub fn name(&self, entry: u16) -> String {
+        if entry == self.base {
+            "main".to_string()
+        } else {
+            format!("sub_{:03X}", entry)
+        }
+    }
+
+    //Every block with its instructions, grouped into a cluster per function.
+    //A block shared by several functions is drawn in the first one.
+    pub fn cfg_dot(&self) -> Vec<String> {
+        let mut lines = vec!["digraph cfg {".to_string()];
+        lines.push("    node [shape=box, fontname=\"monospace\"];".to_string());
+        lines.extend(self.warnings());
+        let mut drawn = BTreeSet::new();
+        for function in &self.functions {
+            lines.push(format!("    subgraph cluster_{:04X} {{", function.entry));
+            lines.push(format!("        label=\"{}\";", self.name(function.entry)));
+            for start in &function.blocks {
+                if !drawn.insert(*start) {
+                    continue;
+                }
+                let block = &self.blocks[start];
+                let text: String = block.instructions.iter()
+                    .map(|&(address, word)| format!("{:04X}  {:04X}  {}\\l", address, word, disasm::disassemble(word)))
+                    .collect();
+                lines.push(format!("        b{:04X} [label=\"{}\"];", block.start, text));
+            }
+            lines.push("    }".to_string());
+        }
+        for block in self.blocks.values() {
+            for &(target, edge) in &block.successors {
+                let attributes = match edge {
+                    Edge::Next | Edge::Jump => "",
+                    Edge::Skip => " [label=\"skip\"]",
+                    Edge::Computed => " [style=dashed]"
+                };
+                lines.push(format!("    b{:04X} -> b{:04X}{};", block.start, target, attributes));
+            }
+            for &target in &block.calls {
+                if self.blocks.contains_key(&target) {
+                    lines.push(format!("    b{:04X} -> b{:04X} [style=dotted, label=\"call\"];", block.start, target));
+                }
+            }
+        }
+        lines.push("}".to_string());
+        lines
+    }
+
+    //One node per function with an edge for each function it calls
+    pub fn call_graph_dot(&self) -> Vec<String> {
+        let mut lines = vec!["digraph calls {".to_string()];
+        lines.push("    node [shape=box, fontname=\"monospace\"];".to_string());
+        lines.extend(self.warnings());
+        for function in &self.functions {
+            let instructions: usize = function.blocks.iter()
+                .filter_map(|start| self.blocks.get(start))
+                .map(|block| block.instructions.len())
+                .sum();
+            lines.push(format!("    f{:04X} [label=\"{}\\n{} instructions\"];", function.entry, self.name(function.entry), instructions));
+        }
+        for function in &self.functions {
+            for &target in &function.calls {
+                if self.blocks.contains_key(&target) {
+                    lines.push(format!("    f{:04X} -> f{:04X};", function.entry, target));
+                }
+            }
+        }
+        lines.push("}".to_string());
+        lines
+    }
+
+    fn warnings(&self) -> Vec<String> {
+        let mut lines = vec![];
+        for address in &self.unresolved {
+            lines.push(format!("    // {:04X}: computed jump, only the base address is followed", address));
+        }
+        for &(from, to) in &self.outside {
+            lines.push(format!("    // {:04X}: leaves the ROM for {:04X}", from, to));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cpu::asm::assemble;
+    use cpu::config::{Config, Quirks};
+    use super::{analyze, Analysis, Edge};
+
+    fn analyze_source(source: &str, config: &Config) -> Analysis {
+        analyze(&assemble(source, config.base_address).unwrap(), config)
+    }
+
+    fn starts(analysis: &Analysis) -> Vec<u16> {
+        analysis.blocks.keys().cloned().collect()
+    }
+
+    fn successors(analysis: &Analysis, start: u16) -> Vec<(u16, Edge)> {
+        analysis.blocks[&start].successors.clone()
+    }
+
+    #[test]
+    fn constant_before_computed_jump() {
+        let source = "LD V0, 4\nJP V0, #208\nCLS\nRET\nCLS\nRET\nRET";
+        let analysis = analyze_source(source, &Config::new());
+        assert_eq!(starts(&analysis), vec![0x200, 0x20C]);
+        assert_eq!(successors(&analysis, 0x200), vec![(0x20C, Edge::Computed)]);
+        assert!(analysis.unresolved.is_empty());
+
+        //SCHIP adds VX, so loading V0 says nothing about the target
+        let mut config = Config::new();
+        config.quirks = Quirks::from_name("schip").unwrap();
+        let analysis = analyze_source("LD V2, 4\nJP V0, #208\nCLS\nRET\nCLS\nRET\nRET", &config);
+        assert_eq!(successors(&analysis, 0x200), vec![(0x20C, Edge::Computed)]);
+        let analysis = analyze_source(source, &config);
+        assert_eq!(successors(&analysis, 0x200), vec![(0x208, Edge::Computed)]);
+        assert_eq!(analysis.unresolved, vec![0x202]);
+    }
+
+    #[test]
+    fn jump_table() {
+        let analysis = analyze_source("RND V0, 1\nJP V0, table\ntable: JP one\nJP two\none: CLS\ntwo: RET", &Config::new());
+        assert_eq!(starts(&analysis), vec![0x200, 0x204, 0x206, 0x208, 0x20A]);
+        assert_eq!(successors(&analysis, 0x200), vec![(0x204, Edge::Computed), (0x206, Edge::Computed)]);
+        assert_eq!(successors(&analysis, 0x204), vec![(0x208, Edge::Jump)]);
+        assert_eq!(successors(&analysis, 0x208), vec![(0x20A, Edge::Next)]);
+        assert!(analysis.unresolved.is_empty());
+    }
+
+    #[test]
+    fn unresolved_computed_jump() {
+        let analysis = analyze_source("RND V0, 3\nJP V0, #206\nRET\nCLS\nRET", &Config::new());
+        assert_eq!(starts(&analysis), vec![0x200, 0x206]);
+        assert_eq!(successors(&analysis, 0x200), vec![(0x206, Edge::Computed)]);
+        assert_eq!(analysis.unresolved, vec![0x202]);
+    }
+
+    #[test]
+    fn skips_calls_and_leaving_the_rom() {
+        let analysis = analyze_source("SE V0, 1\nJP #300\nCALL sub\nRET\nsub: RET", &Config::new());
+        assert_eq!(starts(&analysis), vec![0x200, 0x202, 0x204, 0x208]);
+        assert_eq!(successors(&analysis, 0x200), vec![(0x202, Edge::Next), (0x204, Edge::Skip)]);
+        assert!(successors(&analysis, 0x202).is_empty());
+        assert_eq!(analysis.outside, vec![(0x202, 0x300)]);
+        assert_eq!(analysis.blocks[&0x204].instructions, vec![(0x204, 0x2208), (0x206, 0x00EE)]);
+        assert_eq!(analysis.blocks[&0x204].calls, vec![0x208]);
+        assert_eq!(analysis.functions.len(), 2);
+        assert_eq!(analysis.functions[0].blocks, vec![0x200, 0x202, 0x204]);
+        assert_eq!(analysis.functions[0].calls, vec![0x208]);
+        assert_eq!(analysis.name(analysis.functions[1].entry), "sub_208");
+    }
+}
@@ -0,0 +1,123 @@
+//Behaviour that differs between CHIP-8 interpreters.
+//ROMs written for one interpreter often misbehave on another, so the
+//differences can be picked per ROM with a quirk profile.
+
+#[derive(Debug, Clone, Copy)]
+pub struct Quirks {
+    //8XY6/8XYE shift Vy and store the result in Vx, instead of shifting Vx
+    pub shift_uses_vy: bool,
+    //FX55/FX65 leave I pointing after the last register stored or loaded
+    pub load_store_increments_i: bool,
+    //BNNN jumps to XNN + VX instead of NNN + V0
+    pub jump_uses_vx: bool,
+    //8XY1/8XY2/8XY3 reset VF to 0
+    pub logic_resets_vf: bool,
+    //Return addresses the call stack holds, None for no limit
+    pub stack_depth: Option<usize>,
+    //CALL on a full stack overwrites the oldest return address instead of
+    //stopping with a stack overflow
+    pub stack_wraps: bool
+}
+
+pub static PROFILES: [&str;3] = ["default", "vip", "schip"];
+
+impl Quirks {
+    //The behaviour chipy8 has always had
+    pub fn new() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            stack_depth: None,
+            stack_wraps: false
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match &name.to_lowercase()[..] {
+            "default" => Some(Quirks::new()),
+            //The original COSMAC VIP interpreter
+            "vip" => Some(Quirks {
+                shift_uses_vy: true,
+                load_store_increments_i: true,
+                jump_uses_vx: false,
+                logic_resets_vf: true,
+                //12 entries at 0xEA0, below the interpreter's own variables
+                stack_depth: Some(12),
+                stack_wraps: true
+            }),
+            //SUPER-CHIP 1.1 on the HP48
+            "schip" => Some(Quirks {
+                shift_uses_vy: false,
+                load_store_increments_i: false,
+                jump_uses_vx: true,
+                logic_resets_vf: false,
+                stack_depth: Some(16),
+                stack_wraps: false
+            }),
+            _ => None
+        }
+    }
+}
+
+//The machine the ROM was written for, which sets how much RAM there is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Platform {
+    Chip8,
+    Schip,
+    XoChip
+}
+
+pub static PLATFORMS: [&str;3] = ["chip8", "schip", "xochip"];
+
+impl Platform {
+    pub fn from_name(name: &str) -> Option<Platform> {
+        match &name.to_lowercase().replace("-", "")[..] {
+            "chip8" => Some(Platform::Chip8),
+            "schip" | "superchip" => Some(Platform::Schip),
+            "xochip" => Some(Platform::XoChip),
+            _ => None
+        }
+    }
+
+    pub fn ram_size(&self) -> usize {
+        match *self {
+            Platform::Chip8 | Platform::Schip => 0x1000,
+            Platform::XoChip => 0x10000
+        }
+    }
+
+    //Largest ROM that fits between the load address and the end of RAM
+    pub fn max_rom_size(&self, base_address: u16) -> usize {
+        self.ram_size().saturating_sub(base_address as usize)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub quirks: Quirks,
+    pub platform: Platform,
+    //Where the ROM is loaded and execution starts, 0x600 for ETI-660 programs
+    pub base_address: u16,
+    //Seed for the CXNN random number generator, random if not set
+    pub seed: Option<u64>,
+    //Keep the return addresses in RAM at 0xEA0 like the COSMAC VIP, for
+    //ROMs that look at or change them
+    pub stack_in_ram: bool,
+    //Keep decoded instructions by address instead of decoding every time
+    pub decode_cache: bool
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            quirks: Quirks::new(),
+            platform: Platform::Chip8,
+            base_address: 0x200,
+            seed: None,
+            stack_in_ram: false,
+            decode_cache: true
+        }
+    }
+}
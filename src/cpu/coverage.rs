@@ -0,0 +1,166 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use capture::png;
+use cpu::cpu::Cpu;
+use cpu::disasm;
+use cpu::instruction::{Instruction, Opcode};
+
+//Records how each byte of the ROM was used: fetched as part of an
+//instruction, read as data through I by DRW, LD Vx, [I], or written through
+//I by LD B, Vx and LD [I], Vx.
+//
+//The map is written on exit, as a listing when the file name ends in
+//anything but .png:
+//
+//  0200  A21E  X..  LD I, #21E
+//  021A  80    .R.  DB #80
+//  0230  00    ...  DB #00
+//
+//where instructions are listed where one was executed and every other byte
+//on its own. A .png gets a coloured cell per byte instead, 64 bytes a row.
+
+const EXECUTED: u8 = 1;
+const READ: u8 = 2;
+const WRITTEN: u8 = 4;
+//An instruction was executed starting at this byte
+const START: u8 = 8;
+
+//Bytes per row and pixels per byte in the image
+const ROW: usize = 64;
+const CELL: usize = 8;
+
+//Indexed by the EXECUTED, READ and WRITTEN bits, then the gap between cells
+static COLOURS: [(u8, u8, u8);9] = [
+    (0x30, 0x30, 0x30), //never used
+    (0x40, 0xC8, 0x40), //code
+    (0x40, 0x80, 0xFF), //data
+    (0x00, 0xDC, 0xDC), //code also read as data
+    (0xE6, 0x3C, 0x3C), //written
+    (0xFF, 0x00, 0xFF), //code written to, self modifying
+    (0xF0, 0xA0, 0x28), //data written to
+    (0xFF, 0xFF, 0xFF), //all of them
+    (0x00, 0x00, 0x00)
+];
+const GAP: u8 = 8;
+
+pub struct Coverage {
+    path: PathBuf,
+    rom: Vec<u8>,
+    base: u16,
+    flags: Vec<u8>
+}
+
+impl Coverage {
+    //Fails early when the map can't be written at the end
+    pub fn create(path: &Path, rom: &[u8], base: u16) -> io::Result<Coverage> {
+        File::create(path)?;
+        Ok(Coverage {
+            path: path.to_path_buf(),
+            rom: rom.to_vec(),
+            base,
+            flags: vec![0; rom.len()]
+        })
+    }
+
+    //Starts over for a reloaded ROM
+    pub fn reset(&mut self, rom: &[u8]) {
+        self.rom = rom.to_vec();
+        self.flags = vec![0; rom.len()];
+    }
+
+    //Records the instruction the cpu is about to execute
+    pub fn record(&mut self, cpu: &Cpu) {
+        let registers = cpu.registers();
+        let word = cpu.next_instruction();
+        self.mark(registers.pc, 1, START | EXECUTED);
+        self.mark(registers.pc.wrapping_add(1), 1, EXECUTED);
+        let instr = Instruction::decode(word);
+        let operands = instr.get_operands(word);
+        let registers_used = operands.reg_x as u16 + 1;
+        match instr.opcode {
+            Opcode::Drw => self.mark(registers.i, operands.imm as u16, READ),
+            Opcode::Fetch => self.mark(registers.i, registers_used, READ),
+            Opcode::Stri => self.mark(registers.i, registers_used, WRITTEN),
+            Opcode::Ibcd => self.mark(registers.i, 3, WRITTEN),
+            _ => {}
+        }
+    }
+
+    fn mark(&mut self, address: u16, length: u16, flags: u8) {
+        for n in 0..length {
+            let address = address.wrapping_add(n);
+            if address < self.base {
+                continue;
+            }
+            if let Some(byte) = self.flags.get_mut((address - self.base) as usize) {
+                *byte |= flags;
+            }
+        }
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(&self.path)?);
+        let is_png = self.path.extension().is_some_and(|e| e.eq_ignore_ascii_case("png"));
+        if is_png {
+            self.write_image(&mut out)
+        } else {
+            for line in self.listing() {
+                writeln!(out, "{}", line)?;
+            }
+            out.flush()
+        }
+    }
+
+    fn listing(&self) -> Vec<String> {
+        let count = |flag: u8| self.flags.iter().filter(|&&f| f & flag != 0).count();
+        let unused = self.flags.iter().filter(|&&f| f == 0).count();
+        let mut lines = vec![format!("{} bytes: {} executed, {} read, {} written, {} never used",
+                                     self.rom.len(), count(EXECUTED), count(READ), count(WRITTEN), unused)];
+        let mut offset = 0;
+        while offset < self.rom.len() {
+            let address = self.base as usize + offset;
+            let flags = self.flags[offset];
+            if flags & START != 0 && offset + 1 < self.rom.len() {
+                let word = (self.rom[offset] as u16) << 8 | self.rom[offset + 1] as u16;
+                let used = flags | self.flags[offset + 1];
+                lines.push(format!("{:04X}  {:04X}  {}  {}", address, word, marks(used), disasm::disassemble(word)));
+                offset += 2;
+            } else {
+                let byte = self.rom[offset];
+                lines.push(format!("{:04X}  {:02X}    {}  DB #{:02X}", address, byte, marks(flags), byte));
+                offset += 1;
+            }
+        }
+        lines
+    }
+
+    fn write_image<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let rows = self.flags.len().div_ceil(ROW);
+        let width = ROW * CELL;
+        let height = rows.max(1) * CELL;
+        let mut pixels = vec![GAP; width * height];
+        for (offset, &flags) in self.flags.iter().enumerate() {
+            let (column, row) = (offset % ROW, offset / ROW);
+            //One pixel smaller than a cell, leaving a gap to the next one
+            for y in 0..CELL - 1 {
+                for x in 0..CELL - 1 {
+                    pixels[(row * CELL + y) * width + column * CELL + x] = flags & (EXECUTED | READ | WRITTEN);
+                }
+            }
+        }
+        png::write_palette(out, width as u32, height as u32, &COLOURS, &pixels)
+    }
+}
+
+//"XRW" with a dot for each kind of use the byte never had
+fn marks(flags: u8) -> String {
+    let mut text = String::new();
+    text.push(if flags & EXECUTED != 0 { 'X' } else { '.' });
+    text.push(if flags & READ != 0 { 'R' } else { '.' });
+    text.push(if flags & WRITTEN != 0 { 'W' } else { '.' });
+    text
+}
@@ -7,11 +7,87 @@ use super::super::keypad::Keypad;
 use cpu::instruction::Instruction;
 use cpu::instruction::Opcode;
 use cpu::instruction::Operands;
-use rand::Rng;
+use cpu::config::Config;
+use cpu::config::Platform;
+use cpu::config::Quirks;
+use cpu::state::State;
+use rand::{Rng, SeedableRng};
+
+//The font sprites live below this address, so programs can't be loaded there
+pub const FONT_END: u16 = 0x50;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Empty,
+    TooLarge { size: usize, max: usize, platform: Platform },
+    BadBaseAddress { base_address: u16, platform: Platform },
+    //The stack kept in RAM would overwrite the end of the ROM
+    StackOverlapsRom { end: usize }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Empty => write!(f, "the ROM is empty"),
+            LoadError::TooLarge { size, max, platform } =>
+                write!(f, "the ROM is {} bytes but at most {} bytes fit in {:?} RAM", size, max, platform),
+            LoadError::BadBaseAddress { base_address, platform } =>
+                write!(f, "can't load a ROM at {:#05X} on {:?}, it must be between {:#05X} and the end of RAM at {:#05X}",
+                       base_address, platform, FONT_END, platform.ram_size()),
+            LoadError::StackOverlapsRom { end } =>
+                write!(f, "the ROM runs up to {:#05X}, past the start of the stack in RAM at {:#05X}", end, STACK_ADDRESS)
+        }
+    }
+}
+
+//Where the COSMAC VIP keeps its call stack
+const STACK_ADDRESS: usize = 0xEA0;
+
+//Stops the cpu, the faulting instruction is left at the PC
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    StackOverflow { pc: u16, depth: usize },
+    StackUnderflow { pc: u16 },
+    //The return address in RAM was changed to one past the end of RAM
+    BadReturn { pc: u16, address: u16 },
+    //A word that isn't an instruction, e.g. data or the font jumped into
+    InvalidInstruction { pc: u16, word: u16 }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fault::StackOverflow { pc, depth } =>
+                write!(f, "stack overflow at {:04X}, CALL with all {} stack entries in use", pc, depth),
+            Fault::StackUnderflow { pc } =>
+                write!(f, "stack underflow at {:04X}, RET with an empty stack", pc),
+            Fault::BadReturn { pc, address } =>
+                write!(f, "bad return at {:04X}, RET to {:04X} past the end of RAM", pc, address),
+            Fault::InvalidInstruction { pc, word } =>
+                write!(f, "invalid instruction {:04X} at {:04X}", word, pc)
+        }
+    }
+}
+
+//The registers that change from one instruction to the next, cheap to copy
+//before and after each step for tracing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Registers {
+    pub pc: u16,
+    pub i: u16,
+    pub gpr: [u8;16],
+    pub sp: usize,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub waiting_for_key: bool
+}
+
+//Runs one decoded instruction
+type Handler = fn(&mut Cpu, Operands);
 
 pub struct Cpu {
     pc: u16,
-    ram: [u8;0x1000],
+    ram: Vec<u8>,
     screen: Screen,
     pub keypad: Keypad,
     gpr: [u8;16],
@@ -19,15 +95,45 @@ pub struct Cpu {
     stack: Vec<u16>,
     delay_timer: u8,
     sound_timer: u8,
-    rng: rand::ThreadRng,
-    waiting_for_key: bool
+    rng: rand::XorShiftRng,
+    waiting_for_key: bool,
+    quirks: Quirks,
+    stack_in_ram: bool,
+    fault: Option<Fault>,
+    //Instructions decoded by address into the handler that runs them and
+    //their operands, None where nothing is cached. Entries are dropped when
+    //the RAM under them is written.
+    decoded: Vec<Option<(Handler, Operands)>>,
+    decode_cache: bool,
+    //RAM written since the last take_writes as (address, length), only kept
+    //once something asks for them
+    writes: Option<Vec<(usize, usize)>>
 }
 
 impl Cpu {
-    pub fn new (rom: &Vec<u8>) -> Cpu {
+    pub fn new (rom: &Vec<u8>, config: &Config) -> Result<Cpu, LoadError> {
+        let platform = config.platform;
+        let base = config.base_address;
+        if base < FONT_END || base as usize >= platform.ram_size() || !base.is_multiple_of(2) {
+            return Err(LoadError::BadBaseAddress { base_address: base, platform });
+        }
+        if rom.is_empty() {
+            return Err(LoadError::Empty);
+        }
+        if rom.len() > platform.max_rom_size(base) {
+            return Err(LoadError::TooLarge { size: rom.len(), max: platform.max_rom_size(base), platform });
+        }
+        if config.stack_in_ram && base as usize + rom.len() > STACK_ADDRESS {
+            return Err(LoadError::StackOverlapsRom { end: base as usize + rom.len() - 1 });
+        }
+        let rng = match config.seed {
+            //XorShift must not be seeded with all zeros
+            Some(seed) => rand::XorShiftRng::from_seed([seed as u32, (seed >> 32) as u32, 0x9E37_79B9, 0x7F4A_7C15]),
+            None => rand::weak_rng()
+        };
         let mut ret = Cpu {
-            pc: 0x200,
-            ram: [0;0x1000],
+            pc: base,
+            ram: vec![0; platform.ram_size()],
             screen: Screen::new(),
             keypad: Keypad::new(),
             gpr: [0;16],
@@ -35,18 +141,24 @@ impl Cpu {
             stack: vec![],
             delay_timer: 0,
             sound_timer: 0,
-            rng: rand::thread_rng(),
-            waiting_for_key: false
+            rng,
+            waiting_for_key: false,
+            quirks: config.quirks,
+            stack_in_ram: config.stack_in_ram,
+            fault: None,
+            decoded: vec![None; platform.ram_size()],
+            decode_cache: config.decode_cache,
+            writes: None
         };
         for x in 0..rom.len() {
-            //load ROM file to RAM at 0x0200
-            ret.ram[0x200+x] = rom[x];
+            //load ROM file to RAM at the base address, 0x0200 unless configured otherwise
+            ret.ram[base as usize+x] = rom[x];
         }
         Cpu::load_sprites(&mut ret.ram);
-        return ret;
+        Ok(ret)
     }
     
-    fn load_sprites(ram: &mut [u8;0x1000]) {
+    fn load_sprites(ram: &mut [u8]) {
         let sprites = [
             [0xF0,0x90,0x90,0x90,0xF0], //0
             [0x20,0x60,0x20,0x20,0x70], //1
@@ -73,257 +185,617 @@ impl Cpu {
     }
 
     pub fn execute_next_instruction(&mut self) {
-        let instruction_bytes = ((self.ram[self.pc as usize] as u16) << 8 as u16) + (self.ram[(self.pc+1) as usize]) as u16;
-        let instr = Instruction::decode(instruction_bytes);
-
-        self.pc+=2;
-        let Operands {reg_x, reg_y, address, imm} = instr.get_operands(instruction_bytes);
-
-        match instr.opcode {
-            Opcode::Add => {
-                //The values of Vx and Vy are added together.
-                //If the result is greater than 8 bits (i.e., > 255,) VF is set to 1, otherwise 0.
-                //Only the lowest 8 bits of the result are kept, and stored in Vx.
-                let sum = self.gpr[reg_x as usize] as u16 + self.gpr[reg_y as usize] as u16;
-                if sum > 0xFF {
-                    self.gpr[0xF] = 1;
-                } else {
-                    self.gpr[0xF] = 0;
-                }
-                self.gpr[reg_x as usize] = (sum & 0x00FF) as u8
-            }
-            Opcode::Addir => {
-                //The values of I and Vx are added, and the results are stored in I.
-                self.i = self.i.wrapping_add(self.gpr[reg_x as usize] as u16);
-                if(self.i as u32) + self.gpr[reg_x as usize] as u32 > 0xffff {
-                    panic!("I overflow");
-                }
-            }
-            Opcode::Addi => {
-                //Add imm
-                if self.gpr[reg_x as usize] as u16 + imm as u16 > 255 {
-                    self.gpr[reg_x as usize] = self.gpr[reg_x as usize].wrapping_add(imm);
-                } else {
-                    self.gpr[reg_x as usize] = self.gpr[reg_x as usize].wrapping_add(imm);
-                }
-            }
-            Opcode::And => {
-                //And
-                self.gpr[reg_x as usize]=self.gpr[reg_x as usize] & self.gpr[reg_y as usize];
-            }
-            Opcode::Call => {
-                //Call
-                self.stack.push(self.pc);
-                self.pc=address;
-            }
-            Opcode::Cls => {
-                //Clear screen
-                self.screen.clear();
-            }
-            Opcode::Drw => {
-                //Draw sprite
-                let mut sprite = vec![];
-                for offset in 0..imm {
-                    let byte = self.ram[(self.i+(offset as u16)) as usize];
-                    sprite.push((byte & 0b1000_0000) != 0);
-                    sprite.push((byte & 0b0100_0000) != 0);
-                    sprite.push((byte & 0b0010_0000) != 0);
-                    sprite.push((byte & 0b0001_0000) != 0);
-                    sprite.push((byte & 0b0000_1000) != 0);
-                    sprite.push((byte & 0b0000_0100) != 0);
-                    sprite.push((byte & 0b0000_0010) != 0);
-                    sprite.push((byte & 0b0000_0001) != 0);
-                }
-                let pos_x = self.gpr[reg_x as usize];
-                let pos_y = self.gpr[reg_y as usize];
-                let vf = self.screen.draw_sprire(pos_x, pos_y, sprite);
-                if vf {
-                    self.gpr[0xF]=1;
-                } else {
-                    self.gpr[0xF]=0;
-                }
-            }
-            Opcode::Fetch => {
-                //Read registers V0 through Vx from memory starting at location I.
-                for x in 0..reg_x+1 {
-                    self.gpr[x as usize] = self.ram[(self.i + (x as u16)) as usize];
-                }
-            }
-            Opcode::Ibcd => {
-                // Store Vx as a binary coded decimal at location I
-                let x = self.gpr[reg_x as usize];
-                let h = x/100;
-                let t = (x%100)/10;
-                let o = x%10;
-                self.ram[self.i as usize] = h;
-                self.ram[(self.i+1) as usize] = t;
-                self.ram[(self.i+2) as usize] = o;
-            }
-            Opcode::Jp => {
-                //Jump
-                self.pc=address;
+        if self.fault.is_some() {
+            return;
+        }
+        let pc = self.pc;
+        let (handler, operands) = self.decode(pc);
+        self.pc = self.pc.wrapping_add(2);
+        handler(self, operands);
+    }
 
+    fn decode(&mut self, address: u16) -> (Handler, Operands) {
+        let index = self.wrap(address as usize);
+        if let Some(decoded) = self.decoded[index] {
+            return decoded;
+        }
+        let word = self.next_instruction_at(address);
+        let instr = Instruction::decode(word);
+        let decoded = (Cpu::handler(instr.opcode), instr.get_operands(word));
+        if self.decode_cache {
+            self.decoded[index] = Some(decoded);
+        }
+        decoded
+    }
+
+    //Addresses past the end of RAM wrap around to the start, the way they do
+    //on the 64K of XO-CHIP, so I and the pc can point anywhere
+    fn wrap(&self, address: usize) -> usize {
+        address % self.ram.len()
+    }
+
+    //Every write to RAM comes through here, from instructions, the stack,
+    //debuggers and cheats alike
+    fn wrote(&mut self, start: usize, length: usize) {
+        self.invalidate(start, length);
+        if let Some(ref mut writes) = self.writes {
+            writes.push((start, length));
+        }
+    }
+
+    //Drops the cached instructions overlapping a write, including the one
+    //starting a byte before it. Counted from a RAM size up so the byte
+    //before address 0 wraps to the end like the write does.
+    fn invalidate(&mut self, start: usize, length: usize) {
+        let size = self.decoded.len();
+        for address in start + size - 1..start + size + length.min(size) {
+            let index = self.wrap(address);
+            self.decoded[index] = None;
+        }
+    }
+
+    //With the stack in RAM each entry is a big endian address from 0xEA0 up,
+    //as far as RAM goes
+    fn store_stack_entry(&mut self, index: usize) {
+        let address = STACK_ADDRESS + index * 2;
+        if self.stack_in_ram && address + 1 < self.ram.len() {
+            self.ram[address] = (self.stack[index] >> 8) as u8;
+            self.ram[address + 1] = self.stack[index] as u8;
+            self.wrote(address, 2);
+        }
+    }
+
+    fn load_stack_entry(&self, index: usize) -> Option<u16> {
+        let address = STACK_ADDRESS + index * 2;
+        if self.stack_in_ram && address + 1 < self.ram.len() {
+            Some((self.ram[address] as u16) << 8 | self.ram[address + 1] as u16)
+        } else {
+            None
+        }
+    }
+
+    //Set once the cpu stopped on an error, no more instructions run
+    pub fn fault(&self) -> Option<&Fault> {
+        self.fault.as_ref()
+    }
+
+    pub fn tick_timers(&mut self){
+        if self.delay_timer > 0 {
+            self.delay_timer-=1;
+        }
+        if self.sound_timer > 0 {
+            self.sound_timer-=1;
+        }
+    }
+
+    pub fn get_sound_state(&self) -> bool {
+        if self.sound_timer > 0 {
+            return true;
+        } else {
+            return false;
+        }
+    }
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            i: self.i,
+            gpr: self.gpr,
+            sp: self.stack.len(),
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+            waiting_for_key: self.waiting_for_key
+        }
+    }
+
+    //For debuggers, the stack is grown with zeros or cut to match sp. False,
+    //changing nothing, if the pc is past the end of RAM or sp deeper than the
+    //quirk profile lets the stack go
+    pub fn set_registers(&mut self, registers: &Registers) -> bool {
+        if registers.pc as usize + 1 >= self.ram.len() {
+            return false;
+        }
+        if self.quirks.stack_depth.is_some_and(|depth| registers.sp > depth) {
+            return false;
+        }
+        self.pc = registers.pc;
+        self.i = registers.i;
+        self.gpr = registers.gpr;
+        self.stack.resize(registers.sp, 0);
+        self.delay_timer = registers.delay_timer;
+        self.sound_timer = registers.sound_timer;
+        self.waiting_for_key = registers.waiting_for_key;
+        true
+    }
+
+    //Return addresses, the most recent call last
+    pub fn stack(&self) -> &[u16] {
+        &self.stack
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    //For debuggers and cheats, false if the bytes don't all fit in RAM
+    pub fn write_ram(&mut self, address: usize, bytes: &[u8]) -> bool {
+        if address + bytes.len() > self.ram.len() {
+            return false;
+        }
+        self.ram[address..address + bytes.len()].copy_from_slice(bytes);
+        self.wrote(address, bytes.len());
+        true
+    }
+
+    //The RAM written since the last call, which may run past the end of RAM
+    //and wrap. Writes are only kept from the first call on.
+    pub fn take_writes(&mut self) -> Vec<(usize, usize)> {
+        self.writes.replace(vec![]).unwrap_or_default()
+    }
+
+    //The instruction that will execute next
+    pub fn next_instruction(&self) -> u16 {
+        self.next_instruction_at(self.pc)
+    }
+
+    fn next_instruction_at(&self, address: u16) -> u16 {
+        let high = self.ram[self.wrap(address as usize)];
+        let low = self.ram[self.wrap(address as usize + 1)];
+        ((high as u16) << 8) | low as u16
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    pub fn screen_mut(&mut self) -> &mut Screen {
+        &mut self.screen
+    }
+
+    pub fn save_state(&self) -> State {
+        let mut vram = vec![];
+        for y in 0..::screen::HEIGHT {
+            for x in 0..::screen::WIDTH {
+                vram.push(self.screen.get_pixel(x, y));
             }
-            Opcode::Ld => {
-                //Stores the value of register Vy in register Vx.
-                self.gpr[reg_x as usize]=self.gpr[reg_y as usize];
-            }
-            Opcode::Lddt => {
-                //Set Vx = delay timer value.
-                self.gpr[reg_x as usize] = self.delay_timer;
-            }
-            Opcode::Ldi => {
-                //Load x with imm
-                self.gpr[reg_x as usize] = imm;
-            }
-            Opcode::Ldkp => {
-                //Wait for a key press, store the value of the key in Vx.
-                if self.waiting_for_key {
-                    if self.keypad.new_key {
-                        self.waiting_for_key=false;
-                        self.gpr[reg_x as usize] = self.keypad.get_last_key();
-                    } else {
-                        self.pc-=2;
-                    }
-                } else {
-                    self.waiting_for_key=true;
-                    self.keypad.set_wait();
-                    self.pc-=2;
-                }
-            }
-            Opcode::Or => {
-                //Or
-                self.gpr[reg_x as usize]=self.gpr[reg_x as usize] | self.gpr[reg_y as usize];
-            }
-            Opcode::Ret => {
-                //Return
-                match self.stack.pop() {
-                    Some(x) => {
-                        self.pc = x;
-                    }
-                    None => {
-                        panic!("Ret called with empty stack");
-                    }
-                }
-            }
-            Opcode::Rnd => {
-                //Random
-                self.gpr[reg_x as usize] = (self.rng.gen::<f64>()*(255) as f64) as u8 & imm;
-            }
-            Opcode::Se => {
-                //Skip next instruction if Vx == Vy.
-                if self.gpr[reg_x as usize] == self.gpr[reg_y as usize] {
-                    self.pc+=2;
-                }
-            }
-            Opcode::Sei => {
-                //Skip next instruction if Vx == kk.
-                if self.gpr[reg_x as usize] == imm {
-                    self.pc+=2;
-                }
-            }
-            Opcode::Setdt => {
-                //Set delay timer = Vx.
-                self.delay_timer = self.gpr[reg_x as usize];
-            }
-            Opcode::Seti => {
-                //load i with address
-                self.i=address;
-            }
-            Opcode::Setis => {
-                //Set I = location of sprite for digit Vx.
-                self.i = (self.gpr[reg_x as usize] as u16)*0x5;
-            }
-            Opcode::Setst => {
-                //Sets the sound timer to VX.
-                self.sound_timer = self.gpr[reg_x as usize];
-            }
-            Opcode::Shl => {
-                //Left shift.
-                self.gpr[0xf] = (self.gpr[reg_x as usize] & (0x80) != 0) as u8;
-                self.gpr[reg_x as usize] = self.gpr[reg_x as usize].wrapping_shl(1);
-            }
-            Opcode::Shr => {
-                //Right Shift
-                self.gpr[0xf] = (self.gpr[reg_x as usize] &1 ) as u8;
-                self.gpr[reg_x as usize] = self.gpr[reg_x as usize].wrapping_shr(1);
-            }
-            Opcode::Skp => {
-                //Skip next instruction if key with the value of Vx is pressed.
-                if self.keypad.keys[self.gpr[reg_x as usize] as usize] == true {
-                    self.pc+=2;
-                }
-            }
-            Opcode::Sknp => {
-                //Skip next instruction if key with the value of Vx is not pressed.
-                if self.keypad.keys[self.gpr[reg_x as usize] as usize] == false {
-                    self.pc+=2;
-                }
-            }
-            Opcode::Sne => {
-                //Skip next instruction if Vx != Vy.
-                if self.gpr[reg_x as usize] != self.gpr[reg_y as usize] {
-                    self.pc+=2;
-                }
+        }
+        State {
+            pc: self.pc,
+            i: self.i,
+            gpr: self.gpr,
+            stack: self.stack.clone(),
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+            waiting_for_key: self.waiting_for_key,
+            ram: self.ram.to_vec(),
+            vram
+        }
+    }
+
+    pub fn load_state(&mut self, state: &State) -> Result<(), String> {
+        if state.ram.len() != self.ram.len() {
+            return Err(format!("save state has {} bytes of RAM, expected {}", state.ram.len(), self.ram.len()));
+        }
+        //Checked before anything changes, so a bad state leaves the cpu as it was
+        if state.pc as usize + 1 >= self.ram.len() {
+            return Err(format!("save state has the pc at {:#06X}, past the end of RAM", state.pc));
+        }
+        if state.i as usize >= self.ram.len() {
+            return Err(format!("save state has I at {:#06X}, past the end of RAM", state.i));
+        }
+        if let Some(depth) = self.quirks.stack_depth {
+            if state.stack.len() > depth {
+                return Err(format!("save state has {} stack entries, at most {} fit", state.stack.len(), depth));
             }
-            Opcode::Snei => {
-                //Skip next instruction if Vx != kk.
-                if self.gpr[reg_x as usize] != imm {
-                    self.pc+=2;
-                }
+        }
+        if let Some(&address) = state.stack.iter().find(|&&a| a as usize + 1 >= self.ram.len()) {
+            return Err(format!("save state returns to {:#06X}, past the end of RAM", address));
+        }
+        self.pc = state.pc;
+        self.i = state.i;
+        self.gpr = state.gpr;
+        self.stack = state.stack.clone();
+        self.delay_timer = state.delay_timer;
+        self.sound_timer = state.sound_timer;
+        self.waiting_for_key = state.waiting_for_key;
+        self.fault = None;
+        self.ram.copy_from_slice(&state.ram);
+        let size = self.ram.len();
+        self.wrote(0, size);
+        for y in 0..::screen::HEIGHT {
+            for x in 0..::screen::WIDTH {
+                self.screen.set_pixel(x, y, state.vram[y * ::screen::WIDTH + x]);
             }
-            Opcode::Stri => {
-                //Stores V0 to VX in memory starting at address I
-                for x in 0..reg_x+1 {
-                    self.ram[(self.i + (x as u16)) as usize]=self.gpr[x as usize];
+        }
+        Ok(())
+    }
+}
+
+//One handler per opcode, called with the pc already past the instruction
+impl Cpu {
+    fn handler(opcode: Opcode) -> Handler {
+        match opcode {
+            Opcode::Add => Cpu::add,
+            Opcode::Addir => Cpu::addir,
+            Opcode::Addi => Cpu::addi,
+            Opcode::And => Cpu::and,
+            Opcode::B => Cpu::b,
+            Opcode::Call => Cpu::call,
+            Opcode::Cls => Cpu::cls,
+            Opcode::Drw => Cpu::drw,
+            Opcode::Fetch => Cpu::fetch,
+            Opcode::Ibcd => Cpu::ibcd,
+            Opcode::Jp => Cpu::jp,
+            Opcode::Ld => Cpu::ld,
+            Opcode::Lddt => Cpu::lddt,
+            Opcode::Ldi => Cpu::ldi,
+            Opcode::Ldkp => Cpu::ldkp,
+            Opcode::Or => Cpu::or,
+            Opcode::Ret => Cpu::ret,
+            Opcode::Rnd => Cpu::rnd,
+            Opcode::Se => Cpu::se,
+            Opcode::Sei => Cpu::sei,
+            Opcode::Setdt => Cpu::setdt,
+            Opcode::Seti => Cpu::seti,
+            Opcode::Setis => Cpu::setis,
+            Opcode::Setst => Cpu::setst,
+            Opcode::Shl => Cpu::shl,
+            Opcode::Shr => Cpu::shr,
+            Opcode::Skp => Cpu::skp,
+            Opcode::Sknp => Cpu::sknp,
+            Opcode::Sne => Cpu::sne,
+            Opcode::Snei => Cpu::snei,
+            Opcode::Stri => Cpu::stri,
+            Opcode::Sub => Cpu::sub,
+            Opcode::Subn => Cpu::subn,
+            Opcode::Xor => Cpu::xor,
+            Opcode::Invalid => Cpu::invalid
+        }
+    }
+
+    fn add(&mut self, operands: Operands) {
+        //The values of Vx and Vy are added together.
+        //If the result is greater than 8 bits (i.e., > 255,) VF is set to 1, otherwise 0.
+        //Only the lowest 8 bits of the result are kept, and stored in Vx.
+        let Operands { reg_x, reg_y, .. } = operands;
+        let sum = self.gpr[reg_x as usize] as u16 + self.gpr[reg_y as usize] as u16;
+        if sum > 0xFF {
+            self.gpr[0xF] = 1;
+        } else {
+            self.gpr[0xF] = 0;
+        }
+        self.gpr[reg_x as usize] = (sum & 0x00FF) as u8
+    }
+
+    fn addir(&mut self, operands: Operands) {
+        //The values of I and Vx are added, and the results are stored in I.
+        //Past 0xFFFF I wraps to the start of RAM like the addresses it points at.
+        let Operands { reg_x, .. } = operands;
+        self.i = self.i.wrapping_add(self.gpr[reg_x as usize] as u16);
+    }
+
+    fn addi(&mut self, operands: Operands) {
+        //Add imm
+        let Operands { reg_x, imm, .. } = operands;
+        if self.gpr[reg_x as usize] as u16 + imm as u16 > 255 {
+            self.gpr[reg_x as usize] = self.gpr[reg_x as usize].wrapping_add(imm);
+        } else {
+            self.gpr[reg_x as usize] = self.gpr[reg_x as usize].wrapping_add(imm);
+        }
+    }
+
+    fn and(&mut self, operands: Operands) {
+        //And
+        let Operands { reg_x, reg_y, .. } = operands;
+        self.gpr[reg_x as usize]=self.gpr[reg_x as usize] & self.gpr[reg_y as usize];
+        if self.quirks.logic_resets_vf {
+            self.gpr[0xF] = 0;
+        }
+    }
+
+    fn b(&mut self, operands: Operands) {
+        //Jump to address plus V0, or plus VX with the SCHIP quirk
+        let Operands { reg_x, address, .. } = operands;
+        let offset = if self.quirks.jump_uses_vx {
+            self.gpr[reg_x as usize]
+        } else {
+            self.gpr[0]
+        };
+        self.pc = (address + offset as u16) & 0x0FFF;
+    }
+
+    fn call(&mut self, operands: Operands) {
+        //Call
+        let Operands { address, .. } = operands;
+        if self.quirks.stack_depth.is_some_and(|depth| self.stack.len() >= depth) {
+            if self.quirks.stack_wraps {
+                self.stack.remove(0);
+                for n in 0..self.stack.len() {
+                    self.store_stack_entry(n);
                 }
+            } else {
+                self.pc = self.pc.wrapping_sub(2);
+                self.fault = Some(Fault::StackOverflow { pc: self.pc, depth: self.stack.len() });
+                return;
             }
-            Opcode::Sub => {
-                //Set Vx = Vx - Vy, set VF = NOT borrow.
-                if self.gpr[reg_y as usize] > self.gpr[reg_x as usize] {
-                    self.gpr[0xf] = 0;
-                } else {
-                    self.gpr[0xf] = 1;
-                }
-                self.gpr[reg_x as usize] = self.gpr[reg_x as usize].wrapping_sub(self.gpr[reg_y as usize]);
+        }
+        self.stack.push(self.pc);
+        let top = self.stack.len() - 1;
+        self.store_stack_entry(top);
+        self.pc=address;
+    }
+
+    fn cls(&mut self, _: Operands) {
+        //Clear screen
+        self.screen.clear();
+    }
+
+    fn drw(&mut self, operands: Operands) {
+        //Draw sprite
+        let Operands { reg_x, reg_y, imm, .. } = operands;
+        let mut sprite = vec![];
+        for offset in 0..imm {
+            let byte = self.ram[self.wrap(self.i as usize + offset as usize)];
+            sprite.push((byte & 0b1000_0000) != 0);
+            sprite.push((byte & 0b0100_0000) != 0);
+            sprite.push((byte & 0b0010_0000) != 0);
+            sprite.push((byte & 0b0001_0000) != 0);
+            sprite.push((byte & 0b0000_1000) != 0);
+            sprite.push((byte & 0b0000_0100) != 0);
+            sprite.push((byte & 0b0000_0010) != 0);
+            sprite.push((byte & 0b0000_0001) != 0);
+        }
+        let pos_x = self.gpr[reg_x as usize];
+        let pos_y = self.gpr[reg_y as usize];
+        let vf = self.screen.draw_sprire(pos_x, pos_y, sprite);
+        if vf {
+            self.gpr[0xF]=1;
+        } else {
+            self.gpr[0xF]=0;
+        }
+    }
+
+    fn fetch(&mut self, operands: Operands) {
+        //Read registers V0 through Vx from memory starting at location I.
+        let Operands { reg_x, .. } = operands;
+        for x in 0..reg_x+1 {
+            self.gpr[x as usize] = self.ram[self.wrap(self.i as usize + x as usize)];
+        }
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(reg_x as u16 + 1);
+        }
+    }
+
+    fn ibcd(&mut self, operands: Operands) {
+        // Store Vx as a binary coded decimal at location I
+        let Operands { reg_x, .. } = operands;
+        let x = self.gpr[reg_x as usize];
+        let h = x/100;
+        let t = (x%100)/10;
+        let o = x%10;
+        let i = self.i as usize;
+        for (n, &digit) in [h, t, o].iter().enumerate() {
+            let address = self.wrap(i + n);
+            self.ram[address] = digit;
+        }
+        self.wrote(i, 3);
+    }
+
+    fn jp(&mut self, operands: Operands) {
+        //Jump
+        let Operands { address, .. } = operands;
+        self.pc=address;
+    }
+
+    fn ld(&mut self, operands: Operands) {
+        //Stores the value of register Vy in register Vx.
+        let Operands { reg_x, reg_y, .. } = operands;
+        self.gpr[reg_x as usize]=self.gpr[reg_y as usize];
+    }
+
+    fn lddt(&mut self, operands: Operands) {
+        //Set Vx = delay timer value.
+        let Operands { reg_x, .. } = operands;
+        self.gpr[reg_x as usize] = self.delay_timer;
+    }
+
+    fn ldi(&mut self, operands: Operands) {
+        //Load x with imm
+        let Operands { reg_x, imm, .. } = operands;
+        self.gpr[reg_x as usize] = imm;
+    }
+
+    fn ldkp(&mut self, operands: Operands) {
+        //Wait for a key press, store the value of the key in Vx.
+        let Operands { reg_x, .. } = operands;
+        if self.waiting_for_key {
+            if self.keypad.new_key {
+                self.waiting_for_key=false;
+                self.gpr[reg_x as usize] = self.keypad.get_last_key();
+            } else {
+                self.pc = self.pc.wrapping_sub(2);
             }
-            Opcode::Subn => {
-                //Set Vx = Vy - Vx, set VF = NOT borrow.
-                if self.gpr[reg_y as usize] > self.gpr[reg_x as usize] {
-                    self.gpr[0xf] = 0;
+        } else {
+            self.waiting_for_key=true;
+            self.keypad.set_wait();
+            self.pc = self.pc.wrapping_sub(2);
+        }
+    }
+
+    fn or(&mut self, operands: Operands) {
+        //Or
+        let Operands { reg_x, reg_y, .. } = operands;
+        self.gpr[reg_x as usize]=self.gpr[reg_x as usize] | self.gpr[reg_y as usize];
+        if self.quirks.logic_resets_vf {
+            self.gpr[0xF] = 0;
+        }
+    }
+
+    fn ret(&mut self, _: Operands) {
+        //Return
+        match self.stack.pop() {
+            Some(x) => {
+                //The ROM may have changed the copy in RAM to anything
+                let top = self.stack.len();
+                let address = self.load_stack_entry(top).unwrap_or(x);
+                if address as usize + 1 >= self.ram.len() {
+                    self.stack.push(x);
+                    self.pc = self.pc.wrapping_sub(2);
+                    self.fault = Some(Fault::BadReturn { pc: self.pc, address });
                 } else {
-                    self.gpr[0xf] = 1;
+                    self.pc = address;
                 }
-                self.gpr[reg_x as usize] = self.gpr[reg_y as usize].wrapping_sub(self.gpr[reg_x as usize]);
             }
-            Opcode::Xor => {
-                //Xor
-                self.gpr[reg_x as usize]=self.gpr[reg_x as usize] ^ self.gpr[reg_y as usize];
-            }
-            _ => {
-                panic!("Unhandled opcode {:?} {:#04X}", instr, instruction_bytes)
+            None => {
+                self.pc = self.pc.wrapping_sub(2);
+                self.fault = Some(Fault::StackUnderflow { pc: self.pc });
             }
         }
     }
 
-    pub fn tick_timers(&mut self){
-        if self.delay_timer > 0 {
-            self.delay_timer-=1;
+    fn rnd(&mut self, operands: Operands) {
+        //Random
+        let Operands { reg_x, imm, .. } = operands;
+        self.gpr[reg_x as usize] = (self.rng.gen::<f64>()*(255) as f64) as u8 & imm;
+    }
+
+    fn se(&mut self, operands: Operands) {
+        //Skip next instruction if Vx == Vy.
+        let Operands { reg_x, reg_y, .. } = operands;
+        if self.gpr[reg_x as usize] == self.gpr[reg_y as usize] {
+            self.pc = self.pc.wrapping_add(2);
         }
-        if self.sound_timer > 0 {
-            self.sound_timer-=1;
+    }
+
+    fn sei(&mut self, operands: Operands) {
+        //Skip next instruction if Vx == kk.
+        let Operands { reg_x, imm, .. } = operands;
+        if self.gpr[reg_x as usize] == imm {
+            self.pc = self.pc.wrapping_add(2);
         }
     }
 
-    pub fn get_sound_state(&self) -> bool {
-        if self.sound_timer > 0 {
-            return true;
+    fn setdt(&mut self, operands: Operands) {
+        //Set delay timer = Vx.
+        let Operands { reg_x, .. } = operands;
+        self.delay_timer = self.gpr[reg_x as usize];
+    }
+
+    fn seti(&mut self, operands: Operands) {
+        //load i with address
+        let Operands { address, .. } = operands;
+        self.i=address;
+    }
+
+    fn setis(&mut self, operands: Operands) {
+        //Set I = location of sprite for digit Vx.
+        let Operands { reg_x, .. } = operands;
+        self.i = (self.gpr[reg_x as usize] as u16)*0x5;
+    }
+
+    fn setst(&mut self, operands: Operands) {
+        //Sets the sound timer to VX.
+        let Operands { reg_x, .. } = operands;
+        self.sound_timer = self.gpr[reg_x as usize];
+    }
+
+    fn shl(&mut self, operands: Operands) {
+        //Left shift.
+        let Operands { reg_x, reg_y, .. } = operands;
+        let source = if self.quirks.shift_uses_vy { reg_y } else { reg_x };
+        self.gpr[0xf] = (self.gpr[source as usize] & (0x80) != 0) as u8;
+        self.gpr[reg_x as usize] = self.gpr[source as usize].wrapping_shl(1);
+    }
+
+    fn shr(&mut self, operands: Operands) {
+        //Right Shift
+        let Operands { reg_x, reg_y, .. } = operands;
+        let source = if self.quirks.shift_uses_vy { reg_y } else { reg_x };
+        self.gpr[0xf] = self.gpr[source as usize] & 1;
+        self.gpr[reg_x as usize] = self.gpr[source as usize].wrapping_shr(1);
+    }
+
+    fn skp(&mut self, operands: Operands) {
+        //Skip next instruction if key with the value of Vx is pressed.
+        //Only the low 4 bits pick the key, as on the COSMAC VIP
+        let Operands { reg_x, .. } = operands;
+        if self.keypad.keys[(self.gpr[reg_x as usize] & 0xF) as usize] {
+            self.pc = self.pc.wrapping_add(2);
+        }
+    }
+
+    fn sknp(&mut self, operands: Operands) {
+        //Skip next instruction if key with the value of Vx is not pressed.
+        let Operands { reg_x, .. } = operands;
+        if !self.keypad.keys[(self.gpr[reg_x as usize] & 0xF) as usize] {
+            self.pc = self.pc.wrapping_add(2);
+        }
+    }
+
+    fn sne(&mut self, operands: Operands) {
+        //Skip next instruction if Vx != Vy.
+        let Operands { reg_x, reg_y, .. } = operands;
+        if self.gpr[reg_x as usize] != self.gpr[reg_y as usize] {
+            self.pc = self.pc.wrapping_add(2);
+        }
+    }
+
+    fn snei(&mut self, operands: Operands) {
+        //Skip next instruction if Vx != kk.
+        let Operands { reg_x, imm, .. } = operands;
+        if self.gpr[reg_x as usize] != imm {
+            self.pc = self.pc.wrapping_add(2);
+        }
+    }
+
+    fn stri(&mut self, operands: Operands) {
+        //Stores V0 to VX in memory starting at address I
+        let Operands { reg_x, .. } = operands;
+        for x in 0..reg_x+1 {
+            let address = self.wrap(self.i as usize + x as usize);
+            self.ram[address] = self.gpr[x as usize];
+        }
+        let i = self.i as usize;
+        self.wrote(i, reg_x as usize + 1);
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(reg_x as u16 + 1);
+        }
+    }
+
+    fn sub(&mut self, operands: Operands) {
+        //Set Vx = Vx - Vy, set VF = NOT borrow.
+        let Operands { reg_x, reg_y, .. } = operands;
+        if self.gpr[reg_y as usize] > self.gpr[reg_x as usize] {
+            self.gpr[0xf] = 0;
         } else {
-            return false;
+            self.gpr[0xf] = 1;
         }
+        self.gpr[reg_x as usize] = self.gpr[reg_x as usize].wrapping_sub(self.gpr[reg_y as usize]);
     }
-    pub fn draw(&mut self, renderer: &mut sdl2::render::Renderer) {
-        self.screen.draw(renderer);
+
+    fn subn(&mut self, operands: Operands) {
+        //Set Vx = Vy - Vx, set VF = NOT borrow.
+        let Operands { reg_x, reg_y, .. } = operands;
+        if self.gpr[reg_y as usize] > self.gpr[reg_x as usize] {
+            self.gpr[0xf] = 0;
+        } else {
+            self.gpr[0xf] = 1;
+        }
+        self.gpr[reg_x as usize] = self.gpr[reg_y as usize].wrapping_sub(self.gpr[reg_x as usize]);
+    }
+
+    fn xor(&mut self, operands: Operands) {
+        //Xor
+        let Operands { reg_x, reg_y, .. } = operands;
+        self.gpr[reg_x as usize]=self.gpr[reg_x as usize] ^ self.gpr[reg_y as usize];
+        if self.quirks.logic_resets_vf {
+            self.gpr[0xF] = 0;
+        }
+    }
+
+    fn invalid(&mut self, _: Operands) {
+        let pc = self.pc.wrapping_sub(2);
+        self.pc = pc;
+        self.fault = Some(Fault::InvalidInstruction { pc, word: self.next_instruction_at(pc) });
     }
 }
 
@@ -337,3 +809,83 @@ impl fmt::Debug for Cpu {
         write!(f, "")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use cpu::config::{Config, Platform};
+    use super::{Cpu, Fault};
+
+    fn run(rom: &[u8], config: &Config, instructions: usize) -> Cpu {
+        let mut cpu = Cpu::new(&rom.to_vec(), config).unwrap();
+        for _ in 0..instructions {
+            cpu.execute_next_instruction();
+        }
+        cpu
+    }
+
+    #[test]
+    fn invalid_instructions_fault() {
+        for &(rom, pc, word) in &[([0x00, 0x00], 0x200, 0x0000), ([0x0A, 0xBC], 0x200, 0x0ABC), ([0xB0, 0x00], 0x000, 0xF090)] {
+            let cpu = run(&rom, &Config::new(), 3);
+            assert_eq!(cpu.fault(), Some(&Fault::InvalidInstruction { pc, word }));
+            assert_eq!(cpu.registers().pc, pc);
+        }
+    }
+    #[test]
+    fn add_i_wraps_at_the_top_of_64k() {
+        let mut config = Config::new();
+        config.platform = Platform::XoChip;
+        for &(i, v0, expected) in &[(0xFF00, 0x80, 0xFF80), (0xFFC0, 0x80, 0x0040)] {
+            //ADD I, V0
+            let mut cpu = run(&[0xF0, 0x1E], &config, 0);
+            let mut registers = cpu.registers();
+            registers.i = i;
+            registers.gpr[0] = v0;
+            assert!(cpu.set_registers(&registers));
+            cpu.execute_next_instruction();
+            assert_eq!(cpu.registers().i, expected);
+            assert_eq!(cpu.fault(), None);
+        }
+    }
+    //Runs a subroutine at 0x210 that sets V2 to 1, then rewrites its first
+    //instruction with the given store and calls it again
+    fn rewrite_subroutine(store: [u8;2]) -> Cpu {
+        let rom = [
+            0x60, 0x72,     //LD V0, #72
+            0x61, 0x05,     //LD V1, #05
+            0x22, 0x10,     //CALL #210
+            0xA2, 0x10,     //LD I, #210
+            store[0], store[1],
+            0x22, 0x10,     //CALL #210
+            0x12, 0x0C,     //JP #20C
+            0x00, 0x00,
+            0x62, 0x01,     //LD V2, #01
+            0x00, 0xEE];    //RET
+        run(&rom, &Config::new(), 20)
+    }
+
+    #[test]
+    fn stores_invalidate_cached_instructions() {
+        //LD [I], V1 turns LD V2, #01 into ADD V2, #05
+        let cpu = rewrite_subroutine([0xF1, 0x55]);
+        assert_eq!(cpu.fault(), None);
+        assert_eq!(cpu.registers().gpr[2], 6);
+        //LD B, V0 writes 1 1 4 for #72, which is no instruction
+        let cpu = rewrite_subroutine([0xF0, 0x33]);
+        assert_eq!(cpu.fault(), Some(&Fault::InvalidInstruction { pc: 0x210, word: 0x0101 }));
+    }
+
+    #[test]
+    fn ram_writes_invalidate_cached_instructions() {
+        //LD V2, #01 then JP back to it
+        let mut cpu = run(&[0x62, 0x01, 0x12, 0x00], &Config::new(), 2);
+        assert_eq!(cpu.registers().gpr[2], 1);
+        assert!(cpu.write_ram(0x200, &[0x72, 0x05]));
+        for _ in 0..2 {
+            cpu.execute_next_instruction();
+        }
+        assert_eq!(cpu.registers().gpr[2], 6);
+        assert!(!cpu.write_ram(0xFFF, &[0, 0]));
+    }
+}
+
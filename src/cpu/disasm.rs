@@ -0,0 +1,68 @@
+use cpu::instruction::Instruction;
+use cpu::instruction::Opcode;
+use cpu::instruction::Operands;
+
+//Formats instructions using the common Cowgod mnemonics, which is also the
+//syntax accepted by the assembler.
+
+pub fn disassemble(instruction_bytes: u16) -> String {
+    let instr = Instruction::decode(instruction_bytes);
+    let Operands {reg_x, reg_y, address, imm} = instr.get_operands(instruction_bytes);
+    let x = format!("V{:X}", reg_x);
+    let y = format!("V{:X}", reg_y);
+    match instr.opcode {
+        Opcode::Invalid => format!("DW #{:04X}", instruction_bytes),
+        Opcode::Cls => "CLS".to_string(),
+        Opcode::Ret => "RET".to_string(),
+        Opcode::Jp => format!("JP #{:03X}", address),
+        Opcode::Call => format!("CALL #{:03X}", address),
+        Opcode::Sei => format!("SE {}, #{:02X}", x, imm),
+        Opcode::Snei => format!("SNE {}, #{:02X}", x, imm),
+        Opcode::Se => format!("SE {}, {}", x, y),
+        Opcode::Ldi => format!("LD {}, #{:02X}", x, imm),
+        Opcode::Addi => format!("ADD {}, #{:02X}", x, imm),
+        Opcode::Ld => format!("LD {}, {}", x, y),
+        Opcode::Or => format!("OR {}, {}", x, y),
+        Opcode::And => format!("AND {}, {}", x, y),
+        Opcode::Xor => format!("XOR {}, {}", x, y),
+        Opcode::Add => format!("ADD {}, {}", x, y),
+        Opcode::Sub => format!("SUB {}, {}", x, y),
+        Opcode::Shr => format!("SHR {}, {}", x, y),
+        Opcode::Subn => format!("SUBN {}, {}", x, y),
+        Opcode::Shl => format!("SHL {}, {}", x, y),
+        Opcode::Sne => format!("SNE {}, {}", x, y),
+        Opcode::Seti => format!("LD I, #{:03X}", address),
+        Opcode::B => format!("JP V0, #{:03X}", address),
+        Opcode::Rnd => format!("RND {}, #{:02X}", x, imm),
+        Opcode::Drw => format!("DRW {}, {}, {}", x, y, imm),
+        Opcode::Skp => format!("SKP {}", x),
+        Opcode::Sknp => format!("SKNP {}", x),
+        Opcode::Lddt => format!("LD {}, DT", x),
+        Opcode::Ldkp => format!("LD {}, K", x),
+        Opcode::Setdt => format!("LD DT, {}", x),
+        Opcode::Setst => format!("LD ST, {}", x),
+        Opcode::Addir => format!("ADD I, {}", x),
+        Opcode::Setis => format!("LD F, {}", x),
+        Opcode::Ibcd => format!("LD B, {}", x),
+        Opcode::Stri => format!("LD [I], {}", x),
+        Opcode::Fetch => format!("LD {}, [I]", x)
+    }
+}
+
+//One line per instruction: address, raw bytes and mnemonic.
+//A trailing odd byte is listed as data.
+pub fn listing(rom: &[u8], base: u16) -> Vec<String> {
+    let mut lines = vec![];
+    let mut offset = 0;
+    while offset < rom.len() {
+        let address = base as usize + offset;
+        if offset + 1 < rom.len() {
+            let bytes = ((rom[offset] as u16) << 8) | rom[offset + 1] as u16;
+            lines.push(format!("{:04X}  {:04X}  {}", address, bytes, disassemble(bytes)));
+        } else {
+            lines.push(format!("{:04X}  {:02X}    DB #{:02X}", address, rom[offset], rom[offset]));
+        }
+        offset += 2;
+    }
+    lines
+}
@@ -58,6 +58,13 @@ static OPERAND_REG_X_Y_IMM: OperandEncoding = OperandEncoding {
     imm_mask: 0x000F
 };
 
+static OPERAND_REG_X_ADDRESS: OperandEncoding = OperandEncoding {
+    reg_x_mask: 0x0F00, reg_x_right_shift:8,
+    reg_y_mask: 0x0000, reg_y_right_shift:0,
+    address_mask: 0x0FFF,
+    imm_mask: 0x0000
+};
+
 static OPS: [Instruction;35] = [
     Instruction{mask: 0xFFFF, code: 0x0000, opcode: Opcode::Invalid, operand_encoding: &OPERAND_NONE},
     Instruction{mask: 0xFFFF, code: 0x00E0, opcode: Opcode::Cls,     operand_encoding: &OPERAND_NONE},
@@ -80,7 +87,7 @@ static OPS: [Instruction;35] = [
     Instruction{mask: 0xF00F, code: 0x800E, opcode: Opcode::Shl,     operand_encoding: &OPERAND_REG_XY},
     Instruction{mask: 0xF00F, code: 0x9000, opcode: Opcode::Sne,     operand_encoding: &OPERAND_REG_XY},
     Instruction{mask: 0xF000, code: 0xA000, opcode: Opcode::Seti,    operand_encoding: &OPERAND_ADDRESS},
-    Instruction{mask: 0xF000, code: 0xB000, opcode: Opcode::B,       operand_encoding: &OPERAND_NONE},
+    Instruction{mask: 0xF000, code: 0xB000, opcode: Opcode::B,       operand_encoding: &OPERAND_REG_X_ADDRESS},
     Instruction{mask: 0xF000, code: 0xC000, opcode: Opcode::Rnd,     operand_encoding: &OPERAND_REG_X_IMM},
     Instruction{mask: 0xF000, code: 0xD000, opcode: Opcode::Drw,     operand_encoding: &OPERAND_REG_X_Y_IMM},
     Instruction{mask: 0xF0FF, code: 0xE09E, opcode: Opcode::Skp,     operand_encoding: &OPERAND_REG_X},
@@ -95,7 +102,7 @@ static OPS: [Instruction;35] = [
     Instruction{mask: 0xF0FF, code: 0xF055, opcode: Opcode::Stri,    operand_encoding: &OPERAND_REG_X},
     Instruction{mask: 0xF0FF, code: 0xF065, opcode: Opcode::Fetch,   operand_encoding: &OPERAND_REG_X}];
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Opcode {
     Invalid, Cls, Ret, Jp, Call, Sei, Snei, Se, Ldi, Addi,
     Ld, Or, And, Xor, Add, Sub, Shr, Subn, Shl, Sne,
@@ -113,6 +120,10 @@ impl Instruction {
         return &OPS[0]; //Unknown opcode
     }
 
+    pub fn find(opcode: Opcode) -> &'static Instruction {
+        OPS.iter().find(|op| op.opcode == opcode).unwrap_or(&OPS[0])
+    }
+
     fn test(test_code: &Instruction, instruction_bytes: u16) -> bool {
         instruction_bytes & test_code.mask == test_code.code
     }
@@ -125,8 +136,19 @@ impl Instruction {
             imm: (self.operand_encoding.imm_mask & instruction_bytes) as u8
         }
     }
+
+    //Inverse of get_operands, operands that don't fit their field are truncated
+    pub fn encode(&self, operands: &Operands) -> u16 {
+        let enc = self.operand_encoding;
+        self.code
+            | (((operands.reg_x as u16) << enc.reg_x_right_shift) & enc.reg_x_mask)
+            | (((operands.reg_y as u16) << enc.reg_y_right_shift) & enc.reg_y_mask)
+            | (operands.address & enc.address_mask)
+            | (operands.imm as u16 & enc.imm_mask)
+    }
 }
 
+#[derive(Debug, Clone, Copy)]
 pub struct Operands {
     pub reg_x: u8,
     pub reg_y: u8,
@@ -1,2 +1,11 @@
+pub mod asm;
+pub mod cfg;
+pub mod config;
+pub mod coverage;
 pub mod cpu;
+pub mod disasm;
 pub mod instruction;
+pub mod profile;
+pub mod sanity;
+pub mod state;
+pub mod trace;
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::Path;
+
+use cpu::cpu::Cpu;
+use cpu::disasm;
+use cpu::instruction::{Instruction, Opcode};
+
+//Counts executed instructions per address, per opcode and per subroutine.
+//Every instruction counts as one cycle, the budget a program has is the
+//instructions per frame setting.
+//
+//Subroutines are tracked alongside the cpu stack: a new stack entry starts
+//the subroutine at the PC the CALL jumped to, so a report of the call chains
+//can be written in the collapsed format flamegraph.pl and speedscope read,
+//one "main;sub_3D0;sub_750 1234" line per chain.
+//
+//A CALL on a full stack that wraps, like on the COSMAC VIP, drops the oldest
+//return address, so the chains lose their outermost subroutine too and the
+//report says how often that happened.
+
+//Rows in the hot spot table
+const HOT_SPOTS: usize = 30;
+
+pub struct Profiler {
+    report: Option<Box<dyn Write + Send>>,
+    stacks: Option<Box<dyn Write + Send>>,
+    base: u16,
+    instructions: u64,
+    frames: u64,
+    //Execution count and the instruction last seen at each address
+    addresses: HashMap<u16, (u64, u16)>,
+    //Execution count and an instruction of each kind, as an example
+    opcodes: HashMap<Opcode, (u64, u16)>,
+    //Entry points of the subroutines being run, the program's first
+    calls: Vec<u16>,
+    chains: HashMap<Vec<u16>, u64>,
+    //Whether the last instruction counted was a CALL
+    called: bool,
+    //CALLs that wrapped the stack instead of growing it
+    wraps: u64
+}
+
+impl Profiler {
+    //Writes the report and the call chains at the end of the run to the
+    //files given, "-" for stdout
+    pub fn create(report: Option<&Path>, stacks: Option<&Path>, base: u16) -> io::Result<Profiler> {
+        Ok(Profiler {
+            report: match report {
+                Some(path) => Some(open(path)?),
+                None => None
+            },
+            stacks: match stacks {
+                Some(path) => Some(open(path)?),
+                None => None
+            },
+            base,
+            instructions: 0,
+            frames: 0,
+            addresses: HashMap::new(),
+            opcodes: HashMap::new(),
+            calls: vec![base],
+            chains: HashMap::new(),
+            called: false,
+            wraps: 0
+        })
+    }
+
+    //Counts the instruction the cpu is about to execute
+    pub fn count(&mut self, cpu: &Cpu) {
+        let pc = cpu.registers().pc;
+        let depth = cpu.stack().len() + 1;
+        if self.called && depth == self.calls.len() && depth > 1 && cpu.fault().is_none() {
+            self.calls.remove(1);
+            self.wraps += 1;
+        }
+        self.calls.truncate(depth);
+        while self.calls.len() < depth {
+            self.calls.push(pc);
+        }
+
+        let word = cpu.next_instruction();
+        let opcode = Instruction::decode(word).opcode;
+        self.called = opcode == Opcode::Call;
+        self.instructions += 1;
+        let entry = self.addresses.entry(pc).or_insert((0, word));
+        entry.0 += 1;
+        entry.1 = word;
+        self.opcodes.entry(opcode).or_insert((0, word)).0 += 1;
+        if let Some(count) = self.chains.get_mut(&self.calls[..]) {
+            *count += 1;
+            return;
+        }
+        self.chains.insert(self.calls.clone(), 1);
+    }
+
+    pub fn end_frame(&mut self) {
+        self.frames += 1;
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(mut out) = self.report.take() {
+            self.write_report(&mut out)?;
+            out.flush()?;
+        }
+        if let Some(mut out) = self.stacks.take() {
+            let mut chains: Vec<(String, u64)> = self.chains.iter()
+                .map(|(calls, &count)| (calls.iter().map(|&c| self.name(c)).collect::<Vec<_>>().join(";"), count))
+                .collect();
+            chains.sort();
+            for (chain, count) in chains {
+                writeln!(out, "{} {}", chain, count)?;
+            }
+            out.flush()?;
+            if self.wraps > 0 {
+                println!("Warning: the call stack wrapped {} times, the call chains leave out the calls it dropped", self.wraps);
+            }
+        }
+        Ok(())
+    }
+
+    fn name(&self, entry: u16) -> String {
+        if entry == self.base {
+            "main".to_string()
+        } else {
+            format!("sub_{:03X}", entry)
+        }
+    }
+
+    fn write_report(&self, out: &mut Box<dyn Write + Send>) -> io::Result<()> {
+        let total = self.instructions.max(1) as f64;
+        let frames = self.frames.max(1) as f64;
+        writeln!(out, "{} instructions in {} frames, {:.1} per frame", self.instructions, self.frames, self.instructions as f64 / frames)?;
+        if self.wraps > 0 {
+            writeln!(out, "The call stack wrapped {} times, the totals leave out the calls it dropped", self.wraps)?;
+        }
+
+        //Self counts the instructions of the subroutine itself, total also
+        //those of the subroutines it calls
+        let mut subroutines: HashMap<u16, (u64, u64)> = HashMap::new();
+        for (calls, &count) in &self.chains {
+            subroutines.entry(calls[calls.len() - 1]).or_insert((0, 0)).0 += count;
+            let mut seen = vec![];
+            for &entry in calls {
+                //Recursion only counts once
+                if !seen.contains(&entry) {
+                    seen.push(entry);
+                    subroutines.entry(entry).or_insert((0, 0)).1 += count;
+                }
+            }
+        }
+        let mut subroutines: Vec<(u16, (u64, u64))> = subroutines.into_iter().collect();
+        subroutines.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then(a.0.cmp(&b.0)));
+        writeln!(out, "\n{:<12} {:>11} {:>7} {:>11} {:>12} {:>7} {:>11}", "Subroutine", "self", "%", "per frame", "total", "%", "per frame")?;
+        for &(entry, (own, all)) in &subroutines {
+            writeln!(out, "{:<12} {:>11} {:>6.1}% {:>11.1} {:>12} {:>6.1}% {:>11.1}",
+                     self.name(entry),
+                     own, own as f64 * 100.0 / total, own as f64 / frames,
+                     all, all as f64 * 100.0 / total, all as f64 / frames)?;
+        }
+
+        let mut addresses: Vec<(u16, (u64, u16))> = self.addresses.iter().map(|(&a, &c)| (a, c)).collect();
+        addresses.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then(a.0.cmp(&b.0)));
+        writeln!(out, "\n{:<30} {:>11} {:>7}", "Hot spots", "count", "%")?;
+        for &(address, (count, word)) in addresses.iter().take(HOT_SPOTS) {
+            writeln!(out, "{:04X}  {:04X}  {:<18} {:>11} {:>6.1}%",
+                     address, word, disasm::disassemble(word), count, count as f64 * 100.0 / total)?;
+        }
+
+        let mut opcodes: Vec<(u64, u16)> = self.opcodes.values().cloned().collect();
+        opcodes.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        writeln!(out, "\n{:<8} {:>10} {:>7}   e.g.", "Opcode", "count", "%")?;
+        for &(count, word) in &opcodes {
+            writeln!(out, "{:<8} {:>10} {:>6.1}%   {}",
+                     format!("{:?}", Instruction::decode(word).opcode), count, count as f64 * 100.0 / total, disasm::disassemble(word))?;
+        }
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> io::Result<Box<dyn Write + Send>> {
+    if path == Path::new("-") {
+        Ok(Box::new(io::stdout()))
+    } else {
+        Ok(Box::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cpu::config::{Config, Quirks};
+    use cpu::cpu::Cpu;
+    use super::Profiler;
+
+    #[test]
+    fn wrapped_calls_start_a_new_chain() {
+        let rom = [
+            0x60, 0x00,     //LD V0, #00
+            0x22, 0x06,     //CALL #206
+            0x00, 0x00,
+            0x70, 0x01,     //ADD V0, #01
+            0x30, 0x0D,     //SE V0, #0D
+            0x22, 0x06,     //CALL #206
+            0x22, 0x10,     //CALL #210
+            0x00, 0x00,
+            0x12, 0x10      //JP #210
+        ];
+        let mut config = Config::new();
+        config.quirks = Quirks::from_name("vip").unwrap();
+        let mut cpu = Cpu::new(&rom.to_vec(), &config).unwrap();
+        let mut profiler = Profiler::create(None, None, 0x200).unwrap();
+        for _ in 0..50 {
+            profiler.count(&cpu);
+            cpu.execute_next_instruction();
+        }
+        assert_eq!(cpu.fault(), None);
+        assert_eq!(profiler.wraps, 2);
+        //CALL #206 twelve times, the last wrapping, then the wrapping CALL #210
+        let mut chain = vec![0x200];
+        chain.extend(vec![0x206; 11]);
+        chain.push(0x210);
+        assert_eq!(profiler.calls, chain);
+        assert!(profiler.chains.keys().all(|c| c.len() <= 13));
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::HashSet;
+
+use cpu::cpu::{Cpu, FONT_END};
+use cpu::disasm;
+use cpu::instruction::{Instruction, Opcode};
+
+//Strict mode, warnings about what a ROM does that is undefined or likely a
+//bug on other interpreters:
+//
+//  Strict: 0234  F465  LD V4, [I]        reads uninitialised RAM at 0F00-0F04
+//
+//The cpu carries on after a warning, reads and writes past the end of RAM
+//wrap around to the start. Each kind of warning is only given once per
+//instruction address.
+
+//Call depth of the COSMAC VIP and of SUPER-CHIP
+const VIP_STACK: usize = 12;
+const SCHIP_STACK: usize = 16;
+//I only has 12 bits on the original interpreters
+const I_MAX: u32 = 0xFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Uninitialised,
+    FontWrite,
+    SelfModifying,
+    VipStack,
+    SchipStack,
+    IOverflow,
+    PastRam,
+    BadKey
+}
+
+pub struct Checker {
+    //Bytes loaded or written since the start
+    initialised: Vec<bool>,
+    //Bytes fetched as part of an instruction
+    executed: Vec<bool>,
+    warned: HashSet<(u16, Kind)>
+}
+
+impl Checker {
+    //The font and the ROM are the only RAM with defined contents at the start
+    pub fn new(ram_size: usize, base: u16, rom_size: usize) -> Checker {
+        let mut initialised = vec![false; ram_size];
+        for byte in initialised.iter_mut().take(FONT_END as usize) {
+            *byte = true;
+        }
+        for byte in initialised.iter_mut().skip(base as usize).take(rom_size) {
+            *byte = true;
+        }
+        Checker {
+            initialised,
+            executed: vec![false; ram_size],
+            warned: HashSet::new()
+        }
+    }
+
+    //Checks the instruction the cpu is about to execute
+    pub fn check(&mut self, cpu: &Cpu) {
+        let registers = cpu.registers();
+        let pc = registers.pc;
+        let word = cpu.next_instruction();
+        let instr = Instruction::decode(word);
+        let operands = instr.get_operands(word);
+        let x = operands.reg_x as usize;
+        let vx = registers.gpr[x];
+        let i = registers.i as usize;
+
+        if !self.is_initialised(pc as usize, 2) {
+            self.warn(pc, word, Kind::Uninitialised, format!("executes uninitialised RAM at {:04X}", pc));
+        }
+        for address in pc as usize..pc as usize + 2 {
+            let index = address % self.executed.len();
+            self.executed[index] = true;
+        }
+
+        match instr.opcode {
+            Opcode::Drw => self.read(pc, word, i, operands.imm as usize, "sprite"),
+            Opcode::Fetch => self.read(pc, word, i, x + 1, "load"),
+            Opcode::Stri => self.write(pc, word, i, x + 1),
+            Opcode::Ibcd => self.write(pc, word, i, 3),
+            Opcode::Addir if i as u32 + vx as u32 > I_MAX => {
+                self.warn(pc, word, Kind::IOverflow, format!("takes I past {:03X} to {:04X} (I={:04X} V{:X}={:02X})",
+                                                              I_MAX, i + vx as usize, i, x, vx));
+            }
+            Opcode::Call => {
+                let depth = registers.sp + 1;
+                if depth > SCHIP_STACK {
+                    self.warn(pc, word, Kind::SchipStack, format!("nests {} calls deep, more than the {} of SUPER-CHIP", depth, SCHIP_STACK));
+                } else if depth > VIP_STACK {
+                    self.warn(pc, word, Kind::VipStack, format!("nests {} calls deep, more than the {} of the COSMAC VIP", depth, VIP_STACK));
+                }
+            }
+            Opcode::Skp | Opcode::Sknp if vx > 0xF => {
+                self.warn(pc, word, Kind::BadKey, format!("tests V{:X}={:02X}, which isn't a key, only the low 4 bits are used", x, vx));
+            }
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, pc: u16, word: u16, start: usize, length: usize, what: &str) {
+        if start + length > self.initialised.len() {
+            self.warn(pc, word, Kind::PastRam, format!("{} data at I={:04X} runs {} bytes past the end of RAM at {:04X}, wrapping to the start",
+                                                        what, start, start + length - self.initialised.len(), self.initialised.len()));
+        }
+        if !self.is_initialised(start, length) {
+            self.warn(pc, word, Kind::Uninitialised, format!("reads uninitialised RAM at {}", range(start, length)));
+        }
+    }
+
+    //Warns about a write the instruction is about to make, the bytes are
+    //marked as initialised once it's made, see wrote
+    fn write(&mut self, pc: u16, word: u16, start: usize, length: usize) {
+        if start + length > self.initialised.len() {
+            self.warn(pc, word, Kind::PastRam, format!("writes at I={:04X} run {} bytes past the end of RAM at {:04X}, wrapping to the start",
+                                                        start, start + length - self.initialised.len(), self.initialised.len()));
+        }
+        if start < FONT_END as usize {
+            self.warn(pc, word, Kind::FontWrite, format!("writes into the font at {}", range(start, length)));
+        }
+        let size = self.executed.len();
+        if (start..start + length).any(|a| self.executed[a % size]) {
+            self.warn(pc, word, Kind::SelfModifying, format!("writes into code that has run at {}", range(start, length)));
+        }
+    }
+
+    //Marks what the cpu wrote since the last check, whether by an
+    //instruction, the stack in RAM, a debugger or a cheat
+    pub fn wrote(&mut self, writes: &[(usize, usize)]) {
+        let size = self.initialised.len();
+        for &(start, length) in writes {
+            for address in start..start + length.min(size) {
+                self.initialised[address % size] = true;
+            }
+        }
+    }
+
+    fn is_initialised(&self, start: usize, length: usize) -> bool {
+        (start..start + length).all(|a| self.initialised[a % self.initialised.len()])
+    }
+
+    fn warn(&mut self, pc: u16, word: u16, kind: Kind, message: String) {
+        if self.warned.insert((pc, kind)) {
+            println!("Strict: {:04X}  {:04X}  {:<18}{}", pc, word, disasm::disassemble(word), message);
+        }
+    }
+}
+
+fn range(start: usize, length: usize) -> String {
+    if length <= 1 {
+        format!("{:04X}", start)
+    } else {
+        format!("{:04X}-{:04X}", start, start + length - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use cheat::{Cheat, Cheats, Location};
+    use cpu::asm::assemble;
+    use cpu::config::Config;
+    use cpu::cpu::Cpu;
+    use frame::FrameRunner;
+    use super::{Checker, Kind};
+
+    //Warnings about uninitialised RAM after a few frames, with setup
+    //changing the cpu and runner before they start
+    fn uninitialised_reads<F>(source: &str, config: &Config, setup: F) -> usize
+        where F: FnOnce(&mut Cpu, &mut FrameRunner) {
+        let rom = assemble(source, config.base_address).unwrap();
+        let mut cpu = Cpu::new(&rom, config).unwrap();
+        let mut runner = FrameRunner::new(10);
+        runner.checker = Some(Checker::new(config.platform.ram_size(), config.base_address, rom.len()));
+        runner.poll(&mut cpu).unwrap();
+        setup(&mut cpu, &mut runner);
+        for _ in 0..3 {
+            runner.poll(&mut cpu).unwrap();
+            runner.run(&mut cpu).unwrap();
+        }
+        runner.checker.unwrap().warned.iter().filter(|&&(_, kind)| kind == Kind::Uninitialised).count()
+    }
+
+    static READ_300: &str = "LD I, #300\nLD V0, [I]\nloop: JP loop";
+
+    #[test]
+    fn ram_writes_initialise() {
+        assert_eq!(uninitialised_reads(READ_300, &Config::new(), |_, _| {}), 1);
+        //The debug view, the console and gdb all write with write_ram
+        assert_eq!(uninitialised_reads(READ_300, &Config::new(), |cpu, _| {
+            cpu.write_ram(0x300, &[1]);
+        }), 0);
+        assert_eq!(uninitialised_reads(READ_300, &Config::new(), |_, runner| {
+            let mut cheats = Cheats::new(&PathBuf::new(), &[]);
+            cheats.freeze(Cheat { name: String::new(), location: Location::Ram(0x300), value: 1 });
+            runner.cheats = Some(cheats);
+        }), 0);
+    }
+
+    #[test]
+    fn stack_in_ram_initialises() {
+        let mut config = Config::new();
+        config.stack_in_ram = true;
+        let source = "CALL sub\nloop: JP loop\nsub: LD I, #EA0\nLD V1, [I]\nRET";
+        assert_eq!(uninitialised_reads(source, &config, |_, _| {}), 0);
+    }
+
+    #[test]
+    fn wrapped_writes_initialise() {
+        //LD [I] with I at 12FC writes 02FC on 4K of RAM
+        let source = "LD I, #F00\nLD VE, #FF\nADD I, VE\nADD I, VE\nADD I, VE\nADD I, VE\nLD [I], V0\n\
+                      LD I, #2FC\nLD V0, [I]\nloop: JP loop";
+        assert_eq!(uninitialised_reads(source, &Config::new(), |_, _| {}), 0);
+    }
+}
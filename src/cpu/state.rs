@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use screen;
+
+//Save states, a snapshot of everything the running ROM can observe.
+//The random number generator is not included, so CXNN results after loading
+//a state depend on the seed the emulator was started with.
+
+const MAGIC: &[u8;4] = b"CH8S";
+const VERSION: u8 = 1;
+
+pub struct State {
+    pub pc: u16,
+    pub i: u16,
+    pub gpr: [u8;16],
+    pub stack: Vec<u16>,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub waiting_for_key: bool,
+    pub ram: Vec<u8>,
+    pub vram: Vec<bool>
+}
+
+impl State {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        push_u16(&mut out, self.pc);
+        push_u16(&mut out, self.i);
+        out.extend_from_slice(&self.gpr);
+        out.push(self.stack.len() as u8);
+        for &address in &self.stack {
+            push_u16(&mut out, address);
+        }
+        out.push(self.delay_timer);
+        out.push(self.sound_timer);
+        out.push(self.waiting_for_key as u8);
+        push_u32(&mut out, self.ram.len() as u32);
+        out.extend_from_slice(&self.ram);
+        for pixels in self.vram.chunks(8) {
+            let mut byte = 0u8;
+            for (bit, &lit) in pixels.iter().enumerate() {
+                if lit {
+                    byte |= 0x80 >> bit;
+                }
+            }
+            out.push(byte);
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<State, String> {
+        let mut reader = Reader { data, pos: 0 };
+        if reader.take(4)? != &MAGIC[..] {
+            return Err("not a chipy8 save state".to_string());
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(format!("unsupported save state version {}", version));
+        }
+        let pc = reader.u16()?;
+        let i = reader.u16()?;
+        let mut gpr = [0;16];
+        gpr.copy_from_slice(reader.take(16)?);
+        let depth = reader.u8()?;
+        let mut stack = vec![];
+        for _ in 0..depth {
+            stack.push(reader.u16()?);
+        }
+        let delay_timer = reader.u8()?;
+        let sound_timer = reader.u8()?;
+        let waiting_for_key = reader.u8()? != 0;
+        let ram_size = reader.u32()? as usize;
+        let ram = reader.take(ram_size)?.to_vec();
+        let mut vram = vec![];
+        for &byte in reader.take(screen::WIDTH * screen::HEIGHT / 8)? {
+            for bit in 0..8 {
+                vram.push(byte & (0x80 >> bit) != 0);
+            }
+        }
+        Ok(State {
+            pc,
+            i,
+            gpr,
+            stack,
+            delay_timer,
+            sound_timer,
+            waiting_for_key,
+            ram,
+            vram
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        file.write_all(&self.encode())
+    }
+
+    pub fn load(path: &Path) -> Result<State, String> {
+        let mut data = vec![];
+        match File::open(path).and_then(|mut f| f.read_to_end(&mut data)) {
+            Ok(_) => State::decode(&data),
+            Err(why) => Err(format!("couldn't read {}: {}", path.display(), why))
+        }
+    }
+}
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+    out.push((value >> 8) as u8);
+    out.push(value as u8);
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    push_u16(out, (value >> 16) as u16);
+    push_u16(out, value as u16);
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.pos + len > self.data.len() {
+            return Err("save state is truncated".to_string());
+        }
+        let ret = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(ret)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(((bytes[0] as u16) << 8) | bytes[1] as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let high = self.u16()? as u32;
+        let low = self.u16()? as u32;
+        Ok((high << 16) | low)
+    }
+}
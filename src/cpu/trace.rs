@@ -0,0 +1,119 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::Path;
+
+use cpu::cpu::{Cpu, Registers};
+use cpu::disasm;
+
+//Logs every executed instruction with the registers it changed, one line each:
+//
+//  0200  6A02  LD VA, #02        VA=02
+//  0202  A2EA  LD I, #2EA        I=02EA
+//  0204  2216  CALL #216         PC=0216 SP=1
+//
+//There are no timestamps or counters, so two traces only differ where the
+//runs do and can be compared with diff.
+
+#[derive(Debug, Clone)]
+pub struct TraceFilter {
+    //Inclusive range of instruction addresses to log
+    pub start: u16,
+    pub end: u16,
+    //Mnemonics to log, e.g. DRW or CALL, everything if empty
+    pub mnemonics: Vec<String>
+}
+
+impl TraceFilter {
+    pub fn new() -> TraceFilter {
+        TraceFilter {
+            start: 0,
+            end: 0xFFFF,
+            mnemonics: vec![]
+        }
+    }
+
+    fn matches(&self, pc: u16, mnemonic: &str) -> bool {
+        if pc < self.start || pc > self.end {
+            return false;
+        }
+        if self.mnemonics.is_empty() {
+            return true;
+        }
+        let name = mnemonic.split_whitespace().next().unwrap_or("");
+        self.mnemonics.iter().any(|m| m == name)
+    }
+}
+
+pub struct Tracer {
+    out: BufWriter<Box<dyn Write + Send>>,
+    filter: TraceFilter
+}
+
+impl Tracer {
+    //Writes to the file at path, or to stdout for "-"
+    pub fn create(path: &Path, filter: TraceFilter) -> io::Result<Tracer> {
+        let out: Box<dyn Write + Send> = if path == Path::new("-") {
+            Box::new(io::stdout())
+        } else {
+            Box::new(File::create(path)?)
+        };
+        Ok(Tracer { out: BufWriter::new(out), filter })
+    }
+
+    //Executes one instruction on the cpu and logs it
+    pub fn step(&mut self, cpu: &mut Cpu) -> io::Result<()> {
+        let before = cpu.registers();
+        let word = cpu.next_instruction();
+        cpu.execute_next_instruction();
+        let after = cpu.registers();
+        //A program waiting for a key re-executes LD Vx, K until one is
+        //pressed, only the first attempt and the one that succeeds are logged
+        if before.waiting_for_key && after.waiting_for_key {
+            return Ok(());
+        }
+        let mnemonic = disasm::disassemble(word);
+        if !self.filter.matches(before.pc, &mnemonic) {
+            return Ok(());
+        }
+        let changes = changes(&before, &after);
+        if changes.is_empty() {
+            writeln!(self.out, "{:04X}  {:04X}  {}", before.pc, word, mnemonic)
+        } else {
+            writeln!(self.out, "{:04X}  {:04X}  {:<18}{}", before.pc, word, mnemonic, changes)
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn changes(before: &Registers, after: &Registers) -> String {
+    let mut out = vec![];
+    if after.pc != before.pc.wrapping_add(2) {
+        out.push(format!("PC={:04X}", after.pc));
+    }
+    for x in 0..16 {
+        if after.gpr[x] != before.gpr[x] {
+            out.push(format!("V{:X}={:02X}", x, after.gpr[x]));
+        }
+    }
+    if after.i != before.i {
+        out.push(format!("I={:04X}", after.i));
+    }
+    if after.sp != before.sp {
+        out.push(format!("SP={}", after.sp));
+    }
+    if after.delay_timer != before.delay_timer {
+        out.push(format!("DT={:02X}", after.delay_timer));
+    }
+    if after.sound_timer != before.sound_timer {
+        out.push(format!("ST={:02X}", after.sound_timer));
+    }
+    if after.waiting_for_key != before.waiting_for_key {
+        out.push(if after.waiting_for_key { "WAIT".to_string() } else { "KEY".to_string() });
+    }
+    out.join(" ")
+}
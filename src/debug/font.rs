@@ -0,0 +1,82 @@
+//A 5x7 bitmap font for the debug window, covering ASCII 0x20 to 0x5F.
+//Each glyph is five columns, with the top row in the lowest bit.
+//Lowercase letters are drawn as uppercase.
+
+pub const GLYPH_WIDTH: u32 = 5;
+pub const GLYPH_HEIGHT: u32 = 7;
+
+static GLYPHS: [[u8;5];64] = [
+    [0x00,0x00,0x00,0x00,0x00], //space
+    [0x00,0x00,0x5F,0x00,0x00], //exclamation mark
+    [0x00,0x07,0x00,0x07,0x00], //"
+    [0x14,0x7F,0x14,0x7F,0x14], //#
+    [0x24,0x2A,0x7F,0x2A,0x12], //$
+    [0x23,0x13,0x08,0x64,0x62], //%
+    [0x36,0x49,0x56,0x20,0x50], //&
+    [0x00,0x08,0x07,0x03,0x00], //'
+    [0x00,0x1C,0x22,0x41,0x00], //(
+    [0x00,0x41,0x22,0x1C,0x00], //)
+    [0x2A,0x1C,0x7F,0x1C,0x2A], //*
+    [0x08,0x08,0x3E,0x08,0x08], //+
+    [0x00,0x50,0x30,0x00,0x00], //,
+    [0x08,0x08,0x08,0x08,0x08], //-
+    [0x00,0x60,0x60,0x00,0x00], //.
+    [0x20,0x10,0x08,0x04,0x02], //slash
+    [0x3E,0x51,0x49,0x45,0x3E], //0
+    [0x00,0x42,0x7F,0x40,0x00], //1
+    [0x42,0x61,0x51,0x49,0x46], //2
+    [0x21,0x41,0x45,0x4B,0x31], //3
+    [0x18,0x14,0x12,0x7F,0x10], //4
+    [0x27,0x45,0x45,0x45,0x39], //5
+    [0x3C,0x4A,0x49,0x49,0x31], //6
+    [0x41,0x21,0x11,0x09,0x07], //7
+    [0x36,0x49,0x49,0x49,0x36], //8
+    [0x46,0x49,0x49,0x29,0x1E], //9
+    [0x00,0x00,0x14,0x00,0x00], //:
+    [0x00,0x40,0x34,0x00,0x00], //;
+    [0x00,0x08,0x14,0x22,0x41], //<
+    [0x14,0x14,0x14,0x14,0x14], //=
+    [0x00,0x41,0x22,0x14,0x08], //>
+    [0x02,0x01,0x59,0x09,0x06], //?
+    [0x3E,0x41,0x5D,0x59,0x4E], //@
+    [0x7C,0x12,0x11,0x12,0x7C], //A
+    [0x7F,0x49,0x49,0x49,0x36], //B
+    [0x3E,0x41,0x41,0x41,0x22], //C
+    [0x7F,0x41,0x41,0x41,0x3E], //D
+    [0x7F,0x49,0x49,0x49,0x41], //E
+    [0x7F,0x09,0x09,0x09,0x01], //F
+    [0x3E,0x41,0x41,0x51,0x73], //G
+    [0x7F,0x08,0x08,0x08,0x7F], //H
+    [0x00,0x41,0x7F,0x41,0x00], //I
+    [0x20,0x40,0x41,0x3F,0x01], //J
+    [0x7F,0x08,0x14,0x22,0x41], //K
+    [0x7F,0x40,0x40,0x40,0x40], //L
+    [0x7F,0x02,0x1C,0x02,0x7F], //M
+    [0x7F,0x04,0x08,0x10,0x7F], //N
+    [0x3E,0x41,0x41,0x41,0x3E], //O
+    [0x7F,0x09,0x09,0x09,0x06], //P
+    [0x3E,0x41,0x51,0x21,0x5E], //Q
+    [0x7F,0x09,0x19,0x29,0x46], //R
+    [0x26,0x49,0x49,0x49,0x32], //S
+    [0x03,0x01,0x7F,0x01,0x03], //T
+    [0x3F,0x40,0x40,0x40,0x3F], //U
+    [0x1F,0x20,0x40,0x20,0x1F], //V
+    [0x3F,0x40,0x38,0x40,0x3F], //W
+    [0x63,0x14,0x08,0x14,0x63], //X
+    [0x03,0x04,0x78,0x04,0x03], //Y
+    [0x61,0x59,0x49,0x4D,0x43], //Z
+    [0x00,0x7F,0x41,0x41,0x00], //[
+    [0x02,0x04,0x08,0x10,0x20], //backslash
+    [0x00,0x41,0x41,0x7F,0x00], //]
+    [0x04,0x02,0x01,0x02,0x04], //^
+    [0x40,0x40,0x40,0x40,0x40]];//_
+
+//Columns of the glyph for c, unknown characters are shown as ?
+pub fn glyph(c: char) -> &'static [u8;5] {
+    let c = c.to_ascii_uppercase() as u32;
+    if (0x20..0x60).contains(&c) {
+        &GLYPHS[(c - 0x20) as usize]
+    } else {
+        &GLYPHS[('?' as u32 - 0x20) as usize]
+    }
+}
@@ -0,0 +1,448 @@
+extern crate sdl2;
+
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+
+use cpu::cpu::Cpu;
+use cpu::disasm;
+use debug::font;
+
+//A second window showing the cpu state while the game runs. Tab switches
+//between the pages:
+//
+//  CPU      registers, keypad, call stack and the disassembly around the PC
+//  MEMORY   hex view of the RAM, following the PC or I or scrolled by hand
+//  SPRITES  the RAM drawn as 8 pixel wide sprites, to find graphics
+//
+//While the emulator is paused the V registers and RAM can be edited by
+//selecting them with the arrow keys and typing hex digits.
+
+const COLUMNS: u32 = 64;
+const ROWS: u32 = 30;
+const PIXEL_SCALE: u32 = 2;
+const CELL_WIDTH: u32 = (font::GLYPH_WIDTH + 1) * PIXEL_SCALE;
+const CELL_HEIGHT: u32 = (font::GLYPH_HEIGHT + 2) * PIXEL_SCALE;
+//Column where the disassembly starts
+const DISASM_COLUMN: u32 = 34;
+//Instructions listed before the current one
+const DISASM_BEFORE: u16 = 10;
+const STACK_LINES: usize = 12;
+//The hex view shows this many rows of 16 bytes below the header
+const MEMORY_ROWS: usize = 27;
+//Rows kept above the followed address
+const FOLLOW_MARGIN: usize = 8;
+//Sprite view layout, each column is a strip of bytes one below the other
+const SPRITE_SCALE: u32 = 3;
+const SPRITE_COLUMN_BYTES: usize = 128;
+const SPRITE_COLUMNS: usize = 24;
+const SPRITE_TOP: u32 = 3 * CELL_HEIGHT;
+
+const BACKGROUND: (u8, u8, u8) = (0x10, 0x10, 0x18);
+const TEXT: (u8, u8, u8) = (0xC8, 0xC8, 0xC8);
+const LABEL: (u8, u8, u8) = (0x78, 0x8C, 0xB4);
+const HIGHLIGHT: (u8, u8, u8) = (0xFF, 0xDC, 0x50);
+const CHANGED: (u8, u8, u8) = (0xFF, 0x60, 0x60);
+const CURSOR: (u8, u8, u8) = (0x50, 0xC8, 0x78);
+
+static KEYPAD_LAYOUT: [[u8;4];4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF]];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Page {
+    Cpu,
+    Memory,
+    Sprites
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Follow {
+    Cursor,
+    Pc,
+    I
+}
+
+pub struct DebugView {
+    renderer: sdl2::render::Renderer<'static>,
+    //Text drawn this frame, grouped by color so each color is one fill call
+    rects: Vec<((u8, u8, u8), Vec<sdl2::rect::Rect>)>,
+    page: Page,
+    //RAM at the start of the last emulated frame, to highlight changes
+    previous_ram: Vec<u8>,
+    //Selected byte on the memory and sprite pages and selected V register on the cpu page
+    cursor: usize,
+    register: usize,
+    //First nibble typed when editing, the value is written with the second
+    nibble: Option<u8>,
+    follow: Follow,
+    //First address shown by the hex view and the sprite view
+    top: usize,
+    sprite_top: usize
+}
+
+impl DebugView {
+    pub fn open(video_subsystem: &sdl2::VideoSubsystem) -> Result<DebugView, String> {
+        let window = match video_subsystem.window("Chipy8 - Debug", COLUMNS * CELL_WIDTH, ROWS * CELL_HEIGHT).build() {
+            Ok(w) => w,
+            Err(why) => return Err(format!("couldn't open the debug window: {}", why))
+        };
+        //No vsync, the main window already paces the loop
+        let renderer = match window.renderer().build() {
+            Ok(r) => r,
+            Err(why) => return Err(format!("couldn't open the debug window: {}", why))
+        };
+        Ok(DebugView {
+            renderer,
+            rects: vec![],
+            page: Page::Cpu,
+            previous_ram: vec![],
+            cursor: 0x200,
+            register: 0,
+            nibble: None,
+            follow: Follow::Pc,
+            top: 0x200,
+            sprite_top: 0x200
+        })
+    }
+
+    //Events carrying this id belong to the debug window
+    pub fn window_id(&self) -> u32 {
+        self.renderer.window().unwrap().id()
+    }
+
+    //RAM as it was at the start of the last emulated frame, so the memory
+    //page can show what changed
+    pub fn set_frame_start(&mut self, ram: &[u8]) {
+        self.previous_ram.clear();
+        self.previous_ram.extend_from_slice(ram);
+    }
+
+    //Handles keys pressed while the debug window has focus, true if the key
+    //was used and shouldn't reach the emulator. Editing is only allowed when
+    //editable, i.e. while the emulator is paused.
+    pub fn handle_event(&mut self, event: &Event, cpu: &mut Cpu, editable: bool) -> bool {
+        let keycode = match *event {
+            Event::KeyDown { window_id, keycode: Some(keycode), .. } if window_id == self.window_id() => keycode,
+            _ => return false
+        };
+        let ram_size = cpu.ram().len();
+        match keycode {
+            Keycode::Tab => {
+                self.page = match self.page {
+                    Page::Cpu => Page::Memory,
+                    Page::Memory => Page::Sprites,
+                    Page::Sprites => Page::Cpu
+                };
+                self.nibble = None;
+                return true;
+            }
+            Keycode::Up | Keycode::Down | Keycode::Left | Keycode::Right | Keycode::PageUp | Keycode::PageDown => {
+                self.nibble = None;
+                self.move_cursor(keycode, ram_size);
+                return true;
+            }
+            Keycode::Home if self.page == Page::Memory => {
+                self.follow = match self.follow {
+                    Follow::Cursor => Follow::Pc,
+                    Follow::Pc => Follow::I,
+                    Follow::I => Follow::Cursor
+                };
+                return true;
+            }
+            _ => {}
+        }
+        let digit = match hex_digit(keycode) {
+            Some(d) if editable && self.page != Page::Sprites => d,
+            _ => return false
+        };
+        let first = match self.nibble.take() {
+            Some(first) => first,
+            None => {
+                self.nibble = Some(digit);
+                return true;
+            }
+        };
+        let value = (first << 4) | digit;
+        match self.page {
+            Page::Cpu => {
+                let mut registers = cpu.registers();
+                registers.gpr[self.register] = value;
+                cpu.set_registers(&registers);
+            }
+            _ => {
+                cpu.write_ram(self.cursor, &[value]);
+                //Don't show the edit as a change made by the program
+                if self.cursor < self.previous_ram.len() {
+                    self.previous_ram[self.cursor] = value;
+                }
+                self.cursor = (self.cursor + 1).min(ram_size - 1);
+            }
+        }
+        true
+    }
+
+    fn move_cursor(&mut self, keycode: Keycode, ram_size: usize) {
+        match self.page {
+            Page::Cpu => {
+                self.register = match keycode {
+                    Keycode::Up => (self.register + 12) % 16,
+                    Keycode::Down => (self.register + 4) % 16,
+                    Keycode::Left => (self.register + 15) % 16,
+                    Keycode::Right => (self.register + 1) % 16,
+                    _ => self.register
+                };
+            }
+            Page::Memory => {
+                let step: isize = match keycode {
+                    Keycode::Up => -16,
+                    Keycode::Down => 16,
+                    Keycode::Left => -1,
+                    Keycode::Right => 1,
+                    Keycode::PageUp => -16 * MEMORY_ROWS as isize,
+                    _ => 16 * MEMORY_ROWS as isize
+                };
+                self.cursor = offset(self.cursor, step, ram_size);
+                self.follow = Follow::Cursor;
+            }
+            Page::Sprites => {
+                let step: isize = match keycode {
+                    Keycode::Up => -1,
+                    Keycode::Down => 1,
+                    Keycode::Left => -(SPRITE_COLUMN_BYTES as isize),
+                    Keycode::Right => SPRITE_COLUMN_BYTES as isize,
+                    Keycode::PageUp => -((SPRITE_COLUMN_BYTES * SPRITE_COLUMNS) as isize),
+                    _ => (SPRITE_COLUMN_BYTES * SPRITE_COLUMNS) as isize
+                };
+                self.sprite_top = offset(self.sprite_top, step, ram_size);
+            }
+        }
+    }
+
+    pub fn draw(&mut self, cpu: &Cpu, editable: bool) {
+        match self.page {
+            Page::Cpu => self.draw_cpu(cpu, editable),
+            Page::Memory => self.draw_memory(cpu, editable),
+            Page::Sprites => self.draw_sprites(cpu)
+        }
+        self.present();
+    }
+
+    fn draw_cpu(&mut self, cpu: &Cpu, editable: bool) {
+        let registers = cpu.registers();
+        self.text(0, 0, "PC", LABEL);
+        self.text(3, 0, &format!("{:04X}", registers.pc), TEXT);
+        self.text(9, 0, "I", LABEL);
+        self.text(11, 0, &format!("{:04X}", registers.i), TEXT);
+        self.text(17, 0, "DT", LABEL);
+        self.text(20, 0, &format!("{:02X}", registers.delay_timer), TEXT);
+        self.text(24, 0, "ST", LABEL);
+        self.text(27, 0, &format!("{:02X}", registers.sound_timer), TEXT);
+        if registers.waiting_for_key {
+            self.text(0, 1, "WAITING FOR KEY", HIGHLIGHT);
+        }
+
+        for (x, &value) in registers.gpr.iter().enumerate() {
+            let column = (x as u32 % 4) * 7;
+            let row = 3 + x as u32 / 4;
+            let selected = editable && x == self.register;
+            self.text(column, row, &format!("V{:X}", x), if selected { CURSOR } else { LABEL });
+            let value = match self.nibble {
+                Some(n) if selected => format!("{:X}_", n),
+                _ => format!("{:02X}", value)
+            };
+            self.text(column + 3, row, &value, if selected { CURSOR } else { TEXT });
+        }
+
+        self.text(0, 8, "KEYPAD", LABEL);
+        for (row, keys) in KEYPAD_LAYOUT.iter().enumerate() {
+            for (column, &key) in keys.iter().enumerate() {
+                let color = if cpu.keypad.keys[key as usize] { HIGHLIGHT } else { TEXT };
+                self.text(column as u32 * 2, 9 + row as u32, &format!("{:X}", key), color);
+            }
+        }
+
+        let stack = cpu.stack();
+        self.text(0, 14, &format!("STACK ({})", stack.len()), LABEL);
+        for (n, address) in stack.iter().rev().take(STACK_LINES).enumerate() {
+            self.text(0, 15 + n as u32, &format!("{:2} {:04X}", stack.len() - n - 1, address), TEXT);
+        }
+        if stack.len() > STACK_LINES {
+            self.text(0, 15 + STACK_LINES as u32, "...", TEXT);
+        }
+
+        self.text(DISASM_COLUMN, 0, "DISASSEMBLY", LABEL);
+        let ram = cpu.ram();
+        let mut address = registers.pc.saturating_sub(DISASM_BEFORE * 2);
+        for row in 1..ROWS {
+            if address as usize + 1 >= ram.len() {
+                break;
+            }
+            let word = ((ram[address as usize] as u16) << 8) | ram[address as usize + 1] as u16;
+            let current = address == registers.pc;
+            let line = format!("{} {:04X}  {:04X}  {}", if current { ">" } else { " " }, address, word, disasm::disassemble(word));
+            self.text(DISASM_COLUMN, row, &line, if current { HIGHLIGHT } else { TEXT });
+            address += 2;
+        }
+        self.text(0, ROWS - 1, "TAB: PAGE", LABEL);
+    }
+
+    fn draw_memory(&mut self, cpu: &Cpu, editable: bool) {
+        let registers = cpu.registers();
+        let ram = cpu.ram();
+        let (follow, target) = match self.follow {
+            Follow::Cursor => ("CURSOR", self.cursor),
+            Follow::Pc => ("PC", registers.pc as usize),
+            Follow::I => ("I", registers.i as usize)
+        };
+        //Scroll only when the followed address leaves the view
+        let target = target.min(ram.len() - 1) & !0xF;
+        if target < self.top || target >= self.top + MEMORY_ROWS * 16 {
+            self.top = target.saturating_sub(FOLLOW_MARGIN * 16);
+        }
+        self.top = self.top.min(ram.len().saturating_sub(MEMORY_ROWS * 16));
+
+        self.text(0, 0, "MEMORY", LABEL);
+        self.text(8, 0, &format!("FOLLOW {} (HOME)", follow), TEXT);
+        self.text(27, 0, &format!("CURSOR {:04X}", self.cursor), if editable { CURSOR } else { TEXT });
+        self.text(41, 0, &format!("PC {:04X}  I {:04X}", registers.pc, registers.i), TEXT);
+        self.text(0, 1, "ADDR", LABEL);
+        for column in 0..16 {
+            self.text(6 + column * 3, 1, &format!("{:02X}", column), LABEL);
+        }
+        for row in 0..MEMORY_ROWS {
+            let address = self.top + row * 16;
+            if address >= ram.len() {
+                break;
+            }
+            self.text(0, 2 + row as u32, &format!("{:04X}", address), LABEL);
+            for column in 0..16 {
+                let a = address + column;
+                let pc = registers.pc as usize;
+                let color = if a == self.cursor {
+                    CURSOR
+                } else if a < self.previous_ram.len() && self.previous_ram[a] != ram[a] {
+                    CHANGED
+                } else if a == pc || a == pc + 1 || a == registers.i as usize {
+                    HIGHLIGHT
+                } else {
+                    TEXT
+                };
+                let value = match self.nibble {
+                    Some(n) if a == self.cursor => format!("{:X}_", n),
+                    _ => format!("{:02X}", ram[a])
+                };
+                self.text(6 + column as u32 * 3, 2 + row as u32, &value, color);
+            }
+        }
+        self.text(0, ROWS - 1, "TAB: PAGE  ARROWS/PGUP/PGDN: MOVE  0-F: EDIT WHILE PAUSED", LABEL);
+    }
+
+    fn draw_sprites(&mut self, cpu: &Cpu) {
+        let ram = cpu.ram();
+        let i = cpu.registers().i as usize;
+        self.text(0, 0, "SPRITES", LABEL);
+        self.text(9, 0, &format!("FROM {:04X}  I {:04X}", self.sprite_top, i), TEXT);
+        let column_width = 8 * SPRITE_SCALE + 2 * PIXEL_SCALE;
+        let mut lit = vec![];
+        let mut marked = vec![];
+        for column in 0..SPRITE_COLUMNS {
+            let start = self.sprite_top + column * SPRITE_COLUMN_BYTES;
+            if start >= ram.len() {
+                break;
+            }
+            let left = column as u32 * column_width;
+            if column % 4 == 0 {
+                self.text(left / CELL_WIDTH, 1, &format!("{:04X}", start), LABEL);
+            }
+            for row in 0..SPRITE_COLUMN_BYTES {
+                let address = start + row;
+                if address >= ram.len() {
+                    break;
+                }
+                let top = SPRITE_TOP + row as u32 * SPRITE_SCALE;
+                //I points at the sprite DRW would draw next
+                let pixels = if address == i || address == self.cursor { &mut marked } else { &mut lit };
+                for bit in 0..8 {
+                    if ram[address] & (0x80 >> bit) != 0 {
+                        pixels.push(sdl2::rect::Rect::new((left + bit * SPRITE_SCALE) as i32, top as i32, SPRITE_SCALE, SPRITE_SCALE).unwrap().unwrap());
+                    }
+                }
+            }
+        }
+        self.push_rects(TEXT, lit);
+        self.push_rects(HIGHLIGHT, marked);
+        self.text(0, ROWS - 1, "TAB: PAGE  UP/DOWN: BYTE  LEFT/RIGHT: COLUMN  PGUP/PGDN: PAGE", LABEL);
+    }
+
+    fn push_rects(&mut self, color: (u8, u8, u8), mut rects: Vec<sdl2::rect::Rect>) {
+        match self.rects.iter_mut().find(|&&mut (c, _)| c == color) {
+            Some(&mut (_, ref mut existing)) => existing.append(&mut rects),
+            None => self.rects.push((color, rects))
+        }
+    }
+
+    fn text(&mut self, column: u32, row: u32, text: &str, color: (u8, u8, u8)) {
+        let mut rects = vec![];
+        for (n, c) in text.chars().enumerate() {
+            let left = ((column + n as u32) * CELL_WIDTH) as i32;
+            let top = (row * CELL_HEIGHT + PIXEL_SCALE) as i32;
+            for (x, bits) in font::glyph(c).iter().enumerate() {
+                for y in 0..font::GLYPH_HEIGHT {
+                    if bits & (1 << y) != 0 {
+                        let rect = sdl2::rect::Rect::new(left + (x as u32 * PIXEL_SCALE) as i32, top + (y * PIXEL_SCALE) as i32,
+                                                         PIXEL_SCALE, PIXEL_SCALE).unwrap().unwrap();
+                        rects.push(rect);
+                    }
+                }
+            }
+        }
+        self.push_rects(color, rects);
+    }
+
+    fn present(&mut self) {
+        let (r, g, b) = BACKGROUND;
+        self.renderer.set_draw_color(sdl2::pixels::Color::RGB(r, g, b));
+        self.renderer.clear();
+        for &mut ((r, g, b), ref mut rects) in self.rects.iter_mut() {
+            self.renderer.set_draw_color(sdl2::pixels::Color::RGB(r, g, b));
+            self.renderer.fill_rects(rects);
+            rects.clear();
+        }
+        self.renderer.present();
+    }
+}
+
+fn offset(address: usize, step: isize, size: usize) -> usize {
+    let moved = address as isize + step;
+    if moved < 0 {
+        0
+    } else if moved as usize >= size {
+        size - 1
+    } else {
+        moved as usize
+    }
+}
+
+fn hex_digit(keycode: Keycode) -> Option<u8> {
+    match keycode {
+        Keycode::Num0 | Keycode::Kp0 => Some(0x0),
+        Keycode::Num1 | Keycode::Kp1 => Some(0x1),
+        Keycode::Num2 | Keycode::Kp2 => Some(0x2),
+        Keycode::Num3 | Keycode::Kp3 => Some(0x3),
+        Keycode::Num4 | Keycode::Kp4 => Some(0x4),
+        Keycode::Num5 | Keycode::Kp5 => Some(0x5),
+        Keycode::Num6 | Keycode::Kp6 => Some(0x6),
+        Keycode::Num7 | Keycode::Kp7 => Some(0x7),
+        Keycode::Num8 | Keycode::Kp8 => Some(0x8),
+        Keycode::Num9 | Keycode::Kp9 => Some(0x9),
+        Keycode::A => Some(0xA),
+        Keycode::B => Some(0xB),
+        Keycode::C => Some(0xC),
+        Keycode::D => Some(0xD),
+        Keycode::E => Some(0xE),
+        Keycode::F => Some(0xF),
+        _ => None
+    }
+}
@@ -0,0 +1,411 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use capture::recorder::Recorder;
+use clock;
+use clock::{Clock, Due};
+use cpu::config::Config;
+use cpu::cpu::Cpu;
+use frame::FrameRunner;
+use screen::{Palette, Screen};
+use sound::wav::WavCapture;
+
+//Runs the cpu on a thread of its own, the SDL thread only handles the
+//window, input and sound. After each batch of frames the emulation thread
+//hands over a copy of the screen and the beeper state of every frame, key
+//presses come back through a queue it drains before the next batch.
+//Anything else that needs the cpu, like the hotkeys, the debug window and
+//the console, locks the machine for a moment between batches.
+//
+//Instances added to compare settings run in lockstep with the first one,
+//which is the only one with sound, recordings and the debugging tools.
+//
+//Between batches the emulation thread sleeps until the clock has the next
+//frame due, only turbo and fast forward keep it busy.
+
+//Batches the render thread may fall behind before emulation waits for it
+const FRAME_QUEUE: usize = 4;
+//The clock runs on nanoseconds since the emulation thread started
+const TICKS_PER_SECOND: u64 = 1_000_000_000;
+
+pub struct Machine {
+    pub cpu: Cpu,
+    pub runner: FrameRunner,
+    pub clock: Clock,
+    pub recorder: Option<Recorder>,
+    pub wav_capture: Option<WavCapture>,
+    //RAM at the start of the last frame, only kept while the debug window wants it
+    pub frame_start_ram: Option<Vec<u8>>,
+    pub compared: Vec<Instance>,
+    //Instance the keypad reaches, 0 for the first and 1 on for the compared
+    //ones, or all of them when None
+    pub focus: Option<usize>,
+    //Frames emulated since the start
+    frames: u64,
+    //Host time the beeper states handed over so far cover
+    sound_time: u64,
+    //The beeper sounded in frames emulated since the last frame of real time
+    //was handed over, at high speed many of them share one
+    sound_pending: bool,
+    //Beeper state of the last frame emulated
+    sound_held: bool,
+    //The screens differed at the end of the last frame
+    diverged: bool
+}
+
+//Another copy of the ROM, run with different settings to compare against
+pub struct Instance {
+    pub label: String,
+    pub cpu: Cpu,
+    runner: FrameRunner,
+    rom: Vec<u8>,
+    config: Config,
+    palette: Palette,
+    //Set once it failed, the others keep running
+    halted: bool,
+    //Frame its screen first differed from the first instance's, only reported then
+    diverged_at: Option<u64>
+}
+
+//What one batch of frames produced
+pub struct Frame {
+    //The first instance's screen then the compared ones', each only when it changed
+    pub screens: Vec<Option<Screen>>,
+    //Some compared screen differs from the first
+    pub diverged: bool,
+    //Beeper state for each frame of real time that passed, whatever the
+    //speed, silent while paused and fast forwarding
+    pub sound: Vec<bool>
+}
+
+impl Machine {
+    pub fn new(cpu: Cpu, runner: FrameRunner) -> Machine {
+        Machine {
+            cpu,
+            runner,
+            clock: Clock::new(TICKS_PER_SECOND, 0),
+            recorder: None,
+            wav_capture: None,
+            frame_start_ram: None,
+            compared: vec![],
+            focus: None,
+            frames: 0,
+            sound_time: 0,
+            sound_pending: false,
+            sound_held: false,
+            diverged: false
+        }
+    }
+
+    pub fn diverged(&self) -> bool {
+        self.diverged
+    }
+
+    //Sends a keypad key press or release to the instance in focus
+    fn key(&mut self, key: u8, pressed: bool) {
+        let focus = self.focus;
+        let first = ::std::iter::once(&mut self.cpu);
+        let targets = first.chain(self.compared.iter_mut().map(|i| &mut i.cpu))
+            .enumerate()
+            .filter(|&(index, _)| focus.is_none_or(|f| f == index));
+        for (_, cpu) in targets {
+            if pressed {
+                cpu.keypad.key_down(key);
+            } else {
+                cpu.keypad.key_up(key);
+            }
+        }
+    }
+
+    //Starts every instance over from its ROM, the first from cpu
+    pub fn reset(&mut self, cpu: Cpu) {
+        self.cpu = cpu;
+        self.frames = 0;
+        for instance in &mut self.compared {
+            instance.reset();
+        }
+    }
+
+    fn compare_screens(&mut self) {
+        let mut diverged = false;
+        for instance in &mut self.compared {
+            if instance.cpu.screen().same_pixels(self.cpu.screen()) {
+                continue;
+            }
+            diverged = true;
+            if instance.diverged_at.is_none() {
+                println!("Frame {}: \"{}\" draws a different screen than the first instance", self.frames, instance.label);
+                instance.diverged_at = Some(self.frames);
+            }
+        }
+        self.diverged = diverged;
+    }
+
+    //Emulates the frames due at host time now. Turbo and fast forward stop
+    //after a frame of real time, counted from start, to hand over the screen.
+    fn run_due(&mut self, now: u64, start: Instant) -> Result<Frame, String> {
+        let mut beeps = vec![];
+        let due = self.clock.frames_due(now);
+        let fast_forward = matches!(due, Due::FastForward);
+        let silent = self.runner.is_stopped() || matches!(due, Due::Idle);
+        let frames = match due {
+            _ if self.runner.is_stopped() => 0,
+            Due::Run(n) => n,
+            Due::Idle => 0,
+            Due::Turbo | Due::FastForward => u32::MAX
+        };
+
+        let period = frame_period();
+        for _ in 0..frames {
+            if let Some(ref mut ram) = self.frame_start_ram {
+                ram.clear();
+                ram.extend_from_slice(self.cpu.ram());
+            }
+            //False when stopped by the debugger
+            if !self.runner.run(&mut self.cpu)? {
+                break;
+            }
+            for instance in &mut self.compared {
+                instance.run_frame();
+            }
+            self.frames += 1;
+            if !self.compared.is_empty() {
+                self.compare_screens();
+            }
+            let beeping = self.cpu.get_sound_state();
+            beeps.push(beeping && !fast_forward);
+            self.record_frame(beeping);
+            if start.elapsed() > period {
+                break;
+            }
+        }
+        let mut screens = vec![self.cpu.screen_mut().take_update()];
+        screens.extend(self.compared.iter_mut().map(|i| i.cpu.screen_mut().take_update()));
+        Ok(Frame {
+            screens,
+            diverged: self.diverged,
+            sound: self.real_time_sound(&beeps, now, silent)
+        })
+    }
+
+    //The sound device plays a beeper state per frame of real time, so the
+    //emulated frames' states are stretched or squeezed to the frames of real
+    //time that passed up to now. Squeezed frames beep if any of them did, so
+    //short beeps survive high speeds, and between the frames of slow speeds
+    //the beeper keeps its last state unless silenced.
+    fn real_time_sound(&mut self, beeps: &[bool], now: u64, silent: bool) -> Vec<bool> {
+        if silent {
+            self.sound_held = false;
+        }
+        let period = TICKS_PER_SECOND / clock::FRAME_RATE as u64;
+        let mut real = now.saturating_sub(self.sound_time) / period;
+        if real > clock::MAX_CATCH_UP as u64 {
+            //The host stalled, don't make up for it with a long silence
+            real = clock::MAX_CATCH_UP as u64;
+            self.sound_time = now - real * period;
+        }
+        self.sound_time += real * period;
+        let mut sound = Vec::with_capacity(real as usize);
+        if real == 0 {
+            self.sound_pending |= beeps.iter().any(|&b| b);
+        } else {
+            for n in 0..real {
+                let first = (n * beeps.len() as u64 / real) as usize;
+                let last = (((n + 1) * beeps.len() as u64 / real) as usize).max(first + 1);
+                let beeping = match beeps.get(first..last) {
+                    Some(frames) => frames.iter().any(|&b| b),
+                    None => self.sound_held
+                };
+                sound.push(beeping || (n == 0 && self.sound_pending));
+            }
+            self.sound_pending = false;
+        }
+        if let Some(&beeping) = beeps.last() {
+            self.sound_held = beeping;
+        }
+        sound
+    }
+
+    fn record_frame(&mut self, beeping: bool) {
+        let failed = match self.recorder {
+            Some(ref mut r) => match r.add_frame(self.cpu.screen(), beeping) {
+                Ok(_) => false,
+                Err(why) => {
+                    println!("Failed to record frame to \"{}\": {}", r.path().display(), why);
+                    true
+                }
+            },
+            None => false
+        };
+        if failed {
+            self.recorder = None;
+        }
+        let failed = match self.wav_capture {
+            Some(ref mut w) => match w.add_frame(beeping, clock::FRAME_RATE) {
+                Ok(_) => false,
+                Err(why) => {
+                    println!("Failed to record sound: {}", why);
+                    true
+                }
+            },
+            None => false
+        };
+        if failed {
+            self.wav_capture = None;
+        }
+    }
+}
+
+impl Instance {
+    pub fn new(label: &str, rom: Vec<u8>, config: Config, palette: Palette, instructions_per_frame: u32) -> Result<Instance, String> {
+        let mut cpu = match Cpu::new(&rom, &config) {
+            Ok(c) => c,
+            Err(why) => return Err(format!("couldn't load the ROM for {}: {}", label, why))
+        };
+        cpu.screen_mut().palette = palette;
+        Ok(Instance {
+            label: label.to_string(),
+            cpu,
+            runner: FrameRunner::new(instructions_per_frame),
+            rom,
+            config,
+            palette,
+            halted: false,
+            diverged_at: None
+        })
+    }
+
+    fn reset(&mut self) {
+        self.cpu = Cpu::new(&self.rom, &self.config).expect("ROM loaded at startup failed to load again");
+        self.cpu.screen_mut().palette = self.palette;
+        self.halted = false;
+        self.diverged_at = None;
+    }
+
+    fn run_frame(&mut self) {
+        if self.halted {
+            return;
+        }
+        if let Err(why) = self.runner.run(&mut self.cpu) {
+            println!("\"{}\" stopped: {}", self.label, why);
+            self.halted = true;
+        }
+    }
+}
+
+pub struct Emulator {
+    machine: Arc<Mutex<Machine>>,
+    keys: Sender<(u8, bool)>,
+    frames: Receiver<Frame>,
+    thread: thread::JoinHandle<Result<(), String>>
+}
+
+impl Emulator {
+    pub fn start(machine: Machine) -> Emulator {
+        let machine = Arc::new(Mutex::new(machine));
+        let (key_sender, key_receiver) = channel();
+        let (frame_sender, frame_receiver) = sync_channel(FRAME_QUEUE);
+        let shared = machine.clone();
+        let thread = thread::Builder::new()
+            .name("emulation".to_string())
+            .spawn(move || emulate(&shared, &key_receiver, &frame_sender))
+            .expect("Failed to start the emulation thread");
+        Emulator {
+            machine,
+            keys: key_sender,
+            frames: frame_receiver,
+            thread
+        }
+    }
+
+    //Keeps the emulation thread waiting before its next batch until the guard is dropped
+    pub fn lock(&self) -> MutexGuard<'_, Machine> {
+        self.machine.lock().expect("The emulation thread panicked")
+    }
+
+    //Queues a keypad key press (true) or release for the next batch
+    pub fn key(&self, key: u8, pressed: bool) {
+        //Fails only once the emulation thread ended, which the next frames call reports
+        let _ = self.keys.send((key, pressed));
+    }
+
+    //Waits up to timeout for batches, None once the emulation thread ended
+    pub fn frames(&self, timeout: Duration) -> Option<Vec<Frame>> {
+        let mut frames = vec![];
+        match self.frames.recv_timeout(timeout) {
+            Ok(frame) => frames.push(frame),
+            Err(RecvTimeoutError::Timeout) => return Some(frames),
+            Err(RecvTimeoutError::Disconnected) => return None
+        }
+        loop {
+            match self.frames.try_recv() {
+                Ok(frame) => frames.push(frame),
+                Err(TryRecvError::Empty) => return Some(frames),
+                Err(TryRecvError::Disconnected) => return None
+            }
+        }
+    }
+
+    //Ends the emulation thread, giving the machine back for the recordings
+    //and reports to be finished along with why the thread ended early, if it did
+    pub fn stop(self) -> (Machine, Result<(), String>) {
+        let Emulator { machine, keys, frames, thread } = self;
+        //Wakes the thread from waiting on either channel
+        drop(keys);
+        drop(frames);
+        let result = match thread.join() {
+            Ok(result) => result,
+            Err(_) => Err("the emulation thread panicked".to_string())
+        };
+        let machine = match Arc::try_unwrap(machine) {
+            Ok(m) => m.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner()),
+            Err(_) => panic!("The machine is still shared after the emulation thread ended")
+        };
+        (machine, result)
+    }
+}
+
+fn emulate(machine: &Mutex<Machine>, keys: &Receiver<(u8, bool)>, frames: &SyncSender<Frame>) -> Result<(), String> {
+    let epoch = Instant::now();
+    loop {
+        let start = Instant::now();
+        let (frame, wait) = {
+            let mut guard = machine.lock().expect("The render thread panicked");
+            let m = &mut *guard;
+            loop {
+                match keys.try_recv() {
+                    Ok((key, pressed)) => m.key(key, pressed),
+                    Err(TryRecvError::Empty) => break,
+                    //The render thread is done
+                    Err(TryRecvError::Disconnected) => return Ok(())
+                }
+            }
+            m.runner.poll(&mut m.cpu)?;
+            if m.runner.is_killed() {
+                return Ok(());
+            }
+            let frame = m.run_due(ticks(epoch), start)?;
+            (frame, m.clock.until_next_frame(ticks(epoch)))
+        };
+        if (frame.screens.iter().any(|s| s.is_some()) || !frame.sound.is_empty()) && frames.send(frame).is_err() {
+            return Ok(());
+        }
+        if wait == 0 {
+            thread::yield_now();
+        } else {
+            //At most a frame, so speed changes and the debugger are picked up quickly
+            thread::sleep(Duration::from_nanos(wait).min(frame_period()));
+        }
+    }
+}
+
+fn ticks(epoch: Instant) -> u64 {
+    epoch.elapsed().as_nanos() as u64
+}
+
+//Real time of one frame at normal speed
+pub fn frame_period() -> Duration {
+    Duration::from_nanos(TICKS_PER_SECOND / clock::FRAME_RATE as u64)
+}
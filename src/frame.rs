@@ -0,0 +1,188 @@
+use std::path::Path;
+
+use cheat::Cheats;
+use cli::RunOptions;
+use cpu::coverage::Coverage;
+use cpu::cpu::Cpu;
+use cpu::profile::Profiler;
+use cpu::sanity::Checker;
+use cpu::trace::Tracer;
+use gdb::GdbStub;
+
+//Runs the cpu one 60Hz frame at a time, sending the instructions through the
+//tracer, the profiler, the coverage map, the strict mode checks and the
+//debugger when they are enabled. A debugger can stop the cpu part way
+//through a frame, the rest of it runs once it resumes so the timers still
+//tick every instructions_per_frame instructions.
+pub struct FrameRunner {
+    instructions_per_frame: u32,
+    //Instructions already run in the current frame
+    executed: u32,
+    pub tracer: Option<Tracer>,
+    pub profiler: Option<Profiler>,
+    pub coverage: Option<Coverage>,
+    pub checker: Option<Checker>,
+    pub gdb: Option<GdbStub>,
+    //Frozen values are written at the start of every frame
+    pub cheats: Option<Cheats>
+}
+
+impl FrameRunner {
+    pub fn new(instructions_per_frame: u32) -> FrameRunner {
+        FrameRunner {
+            instructions_per_frame,
+            executed: 0,
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            checker: None,
+            gdb: None,
+            cheats: None
+        }
+    }
+
+    //Handles debugger commands, call regularly even while stopped
+    pub fn poll(&mut self, cpu: &mut Cpu) -> Result<(), String> {
+        self.take_writes(cpu);
+        match self.gdb {
+            Some(ref mut g) => g.poll(cpu).map_err(|why| format!("debugger connection failed: {}", why)),
+            None => Ok(())
+        }
+    }
+
+    //True while a debugger holds the cpu
+    pub fn is_stopped(&self) -> bool {
+        self.gdb.as_ref().is_some_and(|g| !g.is_running())
+    }
+
+    //True once a debugger asked to end the emulator
+    pub fn is_killed(&self) -> bool {
+        self.gdb.as_ref().is_some_and(|g| g.is_killed())
+    }
+
+    //Runs the rest of the current frame and ticks the timers, false if the
+    //debugger stopped the cpu first
+    pub fn run(&mut self, cpu: &mut Cpu) -> Result<bool, String> {
+        if self.executed == 0 {
+            if let Some(ref cheats) = self.cheats {
+                cheats.apply(cpu);
+            }
+        }
+        while self.executed < self.instructions_per_frame {
+            if let Some(ref mut g) = self.gdb {
+                if !g.before_instruction(cpu).map_err(|why| format!("debugger connection failed: {}", why))? {
+                    return Ok(false);
+                }
+            }
+            if let Some(ref mut p) = self.profiler {
+                p.count(cpu);
+            }
+            if let Some(ref mut c) = self.coverage {
+                c.record(cpu);
+            }
+            self.take_writes(cpu);
+            if let Some(ref mut c) = self.checker {
+                c.check(cpu);
+            }
+            match self.tracer {
+                Some(ref mut t) => t.step(cpu).map_err(|why| format!("couldn't write trace: {}", why))?,
+                None => cpu.execute_next_instruction()
+            }
+            self.executed += 1;
+            if let Some(fault) = cpu.fault() {
+                return Err(fault.to_string());
+            }
+            if let Some(ref mut g) = self.gdb {
+                g.after_instruction().map_err(|why| format!("debugger connection failed: {}", why))?;
+            }
+        }
+        self.executed = 0;
+        if let Some(ref mut p) = self.profiler {
+            p.end_frame();
+        }
+        cpu.tick_timers();
+        Ok(true)
+    }
+
+    //Strict mode needs to know about all RAM writes, also those made while
+    //stopped or between frames. The first call starts the cpu keeping them.
+    fn take_writes(&mut self, cpu: &mut Cpu) {
+        if let Some(ref mut c) = self.checker {
+            c.wrote(&cpu.take_writes());
+        }
+    }
+
+    //Flushes the trace, writes the profile and coverage map and tells an attached debugger the program ended
+    pub fn finish(self) -> Result<(), String> {
+        if let Some(mut g) = self.gdb {
+            g.exit().map_err(|why| format!("debugger connection failed: {}", why))?;
+        }
+        if let Some(t) = self.tracer {
+            t.finish().map_err(|why| format!("couldn't write trace: {}", why))?;
+        }
+        if let Some(p) = self.profiler {
+            p.finish().map_err(|why| format!("couldn't write profile: {}", why))?;
+        }
+        if let Some(c) = self.coverage {
+            c.finish().map_err(|why| format!("couldn't write coverage map: {}", why))?;
+        }
+        Ok(())
+    }
+}
+
+//Sets up a runner with everything the options turn on, for the window, the
+//terminal and headless tests alike. Cheats are loaded from cheat_dir, None
+//leaves them out.
+pub fn new_runner(options: &RunOptions, buffer: &[u8], cheat_dir: Option<&str>) -> Result<FrameRunner, String> {
+    let mut runner = FrameRunner::new(options.instructions_per_frame);
+    if let Some(ref trace_file_name) = options.trace {
+        match Tracer::create(Path::new(trace_file_name), options.trace_filter.clone()) {
+            Ok(t) => runner.tracer = Some(t),
+            Err(why) => return Err(format!("couldn't trace to \"{}\": {}", trace_file_name, why))
+        }
+    }
+    if options.profile.is_some() || options.profile_stacks.is_some() {
+        let report = options.profile.as_ref().map(Path::new);
+        let stacks = options.profile_stacks.as_ref().map(Path::new);
+        match Profiler::create(report, stacks, options.cpu.base_address) {
+            Ok(p) => runner.profiler = Some(p),
+            Err(why) => return Err(format!("couldn't create the profile: {}", why))
+        }
+    }
+    if let Some(ref coverage_file_name) = options.coverage {
+        match Coverage::create(Path::new(coverage_file_name), buffer, options.cpu.base_address) {
+            Ok(c) => runner.coverage = Some(c),
+            Err(why) => return Err(format!("couldn't write coverage map to \"{}\": {}", coverage_file_name, why))
+        }
+    }
+    if options.strict {
+        runner.checker = Some(Checker::new(options.cpu.platform.ram_size(), options.cpu.base_address, buffer.len()));
+    }
+    if let Some(port) = options.gdb {
+        match GdbStub::listen(port) {
+            Ok(g) => runner.gdb = Some(g),
+            Err(why) => return Err(format!("couldn't start the debugger server on port {}: {}", port, why))
+        }
+    }
+    if let Some(directory) = cheat_dir {
+        runner.cheats = Some(load_cheats(directory, buffer));
+    }
+    Ok(runner)
+}
+
+//A broken cheat file is only warned about, it shouldn't keep the ROM from
+//starting
+pub fn load_cheats(directory: &str, buffer: &[u8]) -> Cheats {
+    match Cheats::load(Path::new(directory), buffer) {
+        Ok(cheats) => {
+            if !cheats.cheats.is_empty() {
+                println!("Loaded {} cheats from \"{}\"", cheats.cheats.len(), cheats.path().display());
+            }
+            cheats
+        }
+        Err(why) => {
+            println!("Warning: {}, starting without cheats", why);
+            Cheats::new(Path::new(directory), buffer)
+        }
+    }
+}
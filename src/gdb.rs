@@ -0,0 +1,649 @@
+use std::collections::HashSet;
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use cpu::cpu::{Cpu, Registers};
+
+//A GDB remote serial protocol server, so a debugger can attach to the emulator
+//over TCP. The registers are numbered
+//
+//  0-15  V0-VF  8 bits
+//  16    I      16 bits
+//  17    PC     16 bits
+//  18    SP     8 bits, the depth of the call stack
+//  19    DT     8 bits
+//  20    ST     8 bits
+//
+//and the memory is the CHIP-8 RAM. Multi byte registers are little endian
+//like the protocol expects.
+
+const REGISTER_COUNT: usize = 21;
+const PC_REGISTER: usize = 17;
+//How long a reply may wait on a debugger that stopped reading before the
+//connection counts as failed
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+static TARGET_XML: &str = "<?xml version=\"1.0\"?>\
+<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+<target version=\"1.0\"><feature name=\"org.chipy8.cpu\">\
+<reg name=\"v0\" bitsize=\"8\" regnum=\"0\"/><reg name=\"v1\" bitsize=\"8\"/>\
+<reg name=\"v2\" bitsize=\"8\"/><reg name=\"v3\" bitsize=\"8\"/>\
+<reg name=\"v4\" bitsize=\"8\"/><reg name=\"v5\" bitsize=\"8\"/>\
+<reg name=\"v6\" bitsize=\"8\"/><reg name=\"v7\" bitsize=\"8\"/>\
+<reg name=\"v8\" bitsize=\"8\"/><reg name=\"v9\" bitsize=\"8\"/>\
+<reg name=\"va\" bitsize=\"8\"/><reg name=\"vb\" bitsize=\"8\"/>\
+<reg name=\"vc\" bitsize=\"8\"/><reg name=\"vd\" bitsize=\"8\"/>\
+<reg name=\"ve\" bitsize=\"8\"/><reg name=\"vf\" bitsize=\"8\"/>\
+<reg name=\"i\" bitsize=\"16\" type=\"data_ptr\"/>\
+<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>\
+<reg name=\"sp\" bitsize=\"8\"/>\
+<reg name=\"dt\" bitsize=\"8\"/><reg name=\"st\" bitsize=\"8\"/>\
+</feature></target>";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    //Waiting for commands from the debugger
+    Stopped,
+    Running,
+    //Runs a single instruction, then stops
+    Stepping,
+    //The debugger asked to end the emulator
+    Killed
+}
+
+pub struct GdbStub {
+    listener: TcpListener,
+    stream: Option<TcpStream>,
+    input: Vec<u8>,
+    state: State,
+    breakpoints: HashSet<u16>,
+    //Resuming from a breakpoint must not hit it again straight away
+    resume_pc: Option<u16>,
+    no_ack: bool
+}
+
+impl GdbStub {
+    //Waits for a debugger to connect on the local port, the cpu starts out
+    //stopped like it would under gdbserver
+    pub fn listen(port: u16) -> io::Result<GdbStub> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let address = listener.local_addr()?;
+        println!("Waiting for a GDB connection on {}, the emulator starts once one attaches (target remote {})", address, address);
+        GdbStub::accept(listener)
+    }
+
+    fn accept(listener: TcpListener) -> io::Result<GdbStub> {
+        let (stream, address) = listener.accept()?;
+        println!("Debugger connected from {}", address);
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        listener.set_nonblocking(true)?;
+        Ok(GdbStub {
+            listener,
+            stream: Some(stream),
+            input: vec![],
+            state: State::Stopped,
+            breakpoints: HashSet::new(),
+            resume_pc: None,
+            no_ack: false
+        })
+    }
+
+    //True while the debugger lets the cpu run
+    pub fn is_running(&self) -> bool {
+        self.state == State::Running || self.state == State::Stepping
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.state == State::Killed
+    }
+
+    //Handles whatever the debugger sent since the last call, without blocking
+    pub fn poll(&mut self, cpu: &mut Cpu) -> io::Result<()> {
+        if self.stream.is_none() {
+            //A new debugger can attach after the last one went away
+            match self.listener.accept() {
+                Ok((stream, address)) => {
+                    println!("Debugger connected from {}", address);
+                    stream.set_nonblocking(true)?;
+                    stream.set_nodelay(true)?;
+                    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                    self.stream = Some(stream);
+                    self.no_ack = false;
+                    self.state = State::Stopped;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e)
+            }
+        }
+        let mut closed = false;
+        {
+            let stream = self.stream.as_mut().unwrap();
+            let mut buffer = [0u8; 1024];
+            loop {
+                match stream.read(&mut buffer) {
+                    Ok(0) => {
+                        closed = true;
+                        break;
+                    }
+                    Ok(n) => self.input.extend_from_slice(&buffer[..n]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e)
+                }
+            }
+        }
+        while let Some(packet) = self.next_packet()? {
+            let reply = self.handle(&packet, cpu);
+            if let Some(reply) = reply {
+                self.send(&reply)?;
+            }
+        }
+        if closed {
+            println!("Debugger disconnected");
+            self.detach();
+        }
+        Ok(())
+    }
+
+    //Called before each instruction, false if the cpu has to stop first
+    pub fn before_instruction(&mut self, cpu: &Cpu) -> io::Result<bool> {
+        if !self.is_running() {
+            return Ok(false);
+        }
+        let pc = cpu.registers().pc;
+        if self.resume_pc.take() != Some(pc) && self.breakpoints.contains(&pc) {
+            self.stop("S05")?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    pub fn after_instruction(&mut self) -> io::Result<()> {
+        if self.state == State::Stepping {
+            self.stop("S05")?;
+        }
+        Ok(())
+    }
+
+    //Tells the debugger the program ended, e.g. when the headless run is over
+    pub fn exit(&mut self) -> io::Result<()> {
+        if self.stream.is_some() {
+            self.send("W00")?;
+        }
+        self.detach();
+        Ok(())
+    }
+
+    fn stop(&mut self, reason: &str) -> io::Result<()> {
+        self.state = State::Stopped;
+        self.send(reason)
+    }
+
+    fn detach(&mut self) {
+        self.stream = None;
+        self.input.clear();
+        self.breakpoints.clear();
+        if self.state != State::Killed {
+            self.state = State::Running;
+        }
+    }
+
+    fn next_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let start = match self.input.first() {
+                Some(&b'$') => 0,
+                Some(&0x03) => {
+                    //Ctrl-C from the debugger
+                    self.input.remove(0);
+                    if self.is_running() {
+                        self.stop("S02")?;
+                    }
+                    continue;
+                }
+                Some(_) => {
+                    //Acks and line noise
+                    self.input.remove(0);
+                    continue;
+                }
+                None => return Ok(None)
+            };
+            let end = match self.input.iter().position(|&b| b == b'#') {
+                Some(end) if end + 2 < self.input.len() => end,
+                _ => return Ok(None)
+            };
+            let data: Vec<u8> = self.input[start + 1..end].to_vec();
+            let checksum = String::from_utf8_lossy(&self.input[end + 1..end + 3]).into_owned();
+            self.input.drain(..end + 3);
+            let valid = u8::from_str_radix(&checksum, 16).ok() == Some(checksum_of(&data));
+            if !self.no_ack {
+                self.write_raw(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    fn handle(&mut self, packet: &str, cpu: &mut Cpu) -> Option<String> {
+        //An empty reply tells the debugger a packet isn't supported
+        if !packet.is_char_boundary(1) {
+            return Some(String::new());
+        }
+        let (command, args) = packet.split_at(1);
+        match command {
+            "?" => Some("S05".to_string()),
+            "g" => Some((0..REGISTER_COUNT).map(|n| read_register(cpu, n)).collect()),
+            "G" => {
+                let mut rest = args;
+                for n in 0..REGISTER_COUNT {
+                    let width = register_size(n) * 2;
+                    if rest.len() < width {
+                        return Some("E01".to_string());
+                    }
+                    if !write_register(cpu, n, &rest[..width]) {
+                        return Some("E01".to_string());
+                    }
+                    rest = &rest[width..];
+                }
+                Some("OK".to_string())
+            }
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(n) if n < REGISTER_COUNT => Some(read_register(cpu, n)),
+                _ => Some("E01".to_string())
+            },
+            "P" => {
+                let mut parts = args.splitn(2, '=');
+                let n = parts.next().and_then(|n| usize::from_str_radix(n, 16).ok());
+                match (n, parts.next()) {
+                    (Some(n), Some(value)) if n < REGISTER_COUNT && write_register(cpu, n, value) => Some("OK".to_string()),
+                    _ => Some("E01".to_string())
+                }
+            }
+            "m" => {
+                let (address, length) = match parse_range(args) {
+                    Some(r) => r,
+                    None => return Some("E01".to_string())
+                };
+                let ram = cpu.ram();
+                if address >= ram.len() {
+                    return Some("E01".to_string());
+                }
+                let end = (address + length).min(ram.len());
+                Some(ram[address..end].iter().map(|b| format!("{:02x}", b)).collect())
+            }
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let range = parts.next().and_then(parse_range);
+                let bytes = parts.next().and_then(decode_hex);
+                match (range, bytes) {
+                    (Some((address, length)), Some(ref bytes)) if bytes.len() == length && address + length <= cpu.ram().len() => {
+                        cpu.write_ram(address, bytes);
+                        Some("OK".to_string())
+                    }
+                    _ => Some("E01".to_string())
+                }
+            }
+            "Z" | "z" => {
+                //Software and hardware breakpoints are the same thing here, watchpoints aren't supported
+                let mut parts = args.split(',');
+                let kind = parts.next();
+                let address = parts.next().and_then(|a| u16::from_str_radix(a, 16).ok());
+                match (kind, address) {
+                    (Some("0"), Some(address)) | (Some("1"), Some(address)) => {
+                        if command == "Z" {
+                            self.breakpoints.insert(address);
+                        } else {
+                            self.breakpoints.remove(&address);
+                        }
+                        Some("OK".to_string())
+                    }
+                    (Some(_), Some(_)) => Some(String::new()),
+                    _ => Some("E01".to_string())
+                }
+            }
+            "c" => self.resume(cpu, args, State::Running),
+            "s" => self.resume(cpu, args, State::Stepping),
+            "k" => {
+                self.state = State::Killed;
+                None
+            }
+            "D" => {
+                self.state = State::Running;
+                let _ = self.send("OK");
+                self.detach();
+                None
+            }
+            "H" => Some("OK".to_string()),
+            "v" => {
+                if packet == "vCont?" {
+                    Some("vCont;c;C;s;S".to_string())
+                } else if let Some(actions) = packet.strip_prefix("vCont;") {
+                    //There is only one thread, so the first action decides
+                    let action = actions.split([';', ':']).next().unwrap_or("");
+                    match action.chars().next() {
+                        Some('c') | Some('C') => self.resume(cpu, "", State::Running),
+                        Some('s') | Some('S') => self.resume(cpu, "", State::Stepping),
+                        _ => Some("E01".to_string())
+                    }
+                } else {
+                    Some(String::new())
+                }
+            }
+            "q" => Some(self.query(packet)),
+            "Q" => {
+                if packet == "QStartNoAckMode" {
+                    //The reply is still acknowledged, only later packets aren't
+                    let _ = self.send("OK");
+                    self.no_ack = true;
+                    None
+                } else {
+                    Some(String::new())
+                }
+            }
+            _ => Some(String::new())
+        }
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            "PacketSize=1000;QStartNoAckMode+;qXfer:features:read+".to_string()
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            match parse_range(range) {
+                Some((offset, length)) => {
+                    let xml = TARGET_XML.as_bytes();
+                    let start = offset.min(xml.len());
+                    let end = (offset + length).min(xml.len());
+                    let chunk = String::from_utf8_lossy(&xml[start..end]);
+                    format!("{}{}", if end == xml.len() { "l" } else { "m" }, chunk)
+                }
+                None => "E01".to_string()
+            }
+        } else {
+            String::new()
+        }
+    }
+
+    //The stop reply is only sent once the cpu stops again
+    fn resume(&mut self, cpu: &mut Cpu, args: &str, state: State) -> Option<String> {
+        if !args.is_empty() {
+            match u16::from_str_radix(args, 16) {
+                Ok(address) => {
+                    let mut registers = cpu.registers();
+                    registers.pc = address;
+                    if !cpu.set_registers(&registers) {
+                        return Some("E01".to_string());
+                    }
+                }
+                Err(_) => return Some("E01".to_string())
+            }
+        }
+        self.resume_pc = Some(cpu.registers().pc);
+        self.state = state;
+        None
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.write_raw(packet.as_bytes())
+    }
+
+    fn write_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let stream = match self.stream {
+            Some(ref mut s) => s,
+            None => return Ok(())
+        };
+        //Reads don't block, but a reply is written whole, giving up after the
+        //write timeout
+        stream.set_nonblocking(false)?;
+        let result = stream.write_all(bytes);
+        stream.set_nonblocking(true)?;
+        result
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn register_size(n: usize) -> usize {
+    match n {
+        16 | PC_REGISTER => 2,
+        _ => 1
+    }
+}
+
+fn read_register(cpu: &Cpu, n: usize) -> String {
+    let r = cpu.registers();
+    match n {
+        0..=15 => format!("{:02x}", r.gpr[n]),
+        16 => format!("{:02x}{:02x}", r.i & 0xFF, r.i >> 8),
+        PC_REGISTER => format!("{:02x}{:02x}", r.pc & 0xFF, r.pc >> 8),
+        18 => format!("{:02x}", r.sp),
+        19 => format!("{:02x}", r.delay_timer),
+        _ => format!("{:02x}", r.sound_timer)
+    }
+}
+
+fn write_register(cpu: &mut Cpu, n: usize, hex: &str) -> bool {
+    let bytes = match decode_hex(hex) {
+        Some(ref b) if b.len() == register_size(n) => b.clone(),
+        _ => return false
+    };
+    let mut r: Registers = cpu.registers();
+    let word = if bytes.len() == 2 { bytes[0] as u16 | (bytes[1] as u16) << 8 } else { bytes[0] as u16 };
+    match n {
+        0..=15 => r.gpr[n] = bytes[0],
+        16 => r.i = word,
+        PC_REGISTER => r.pc = word,
+        18 => r.sp = bytes[0] as usize,
+        19 => r.delay_timer = bytes[0],
+        _ => r.sound_timer = bytes[0]
+    }
+    cpu.set_registers(&r)
+}
+
+//"addr,length" in hex
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let mut parts = text.splitn(2, ',');
+    let address = parts.next().and_then(|a| usize::from_str_radix(a, 16).ok());
+    let length = parts.next().and_then(|l| usize::from_str_radix(l, 16).ok());
+    match (address, length) {
+        //The end has to be addressable too
+        (Some(a), Some(l)) => a.checked_add(l).map(|_| (a, l)),
+        _ => None
+    }
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    //Slicing pairs of bytes is only safe when every character is one byte
+    if !text.is_ascii() || !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len() / 2).map(|n| u8::from_str_radix(&text[n * 2..n * 2 + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    use cpu::config::{Config, Quirks};
+    use cpu::cpu::Cpu;
+    use frame::FrameRunner;
+    use super::{checksum_of, GdbStub};
+
+    //LD V0,5 then ADD V0,1 and JP back to it forever
+    static PROGRAM: [u8;6] = [0x60, 0x05, 0x70, 0x01, 0x12, 0x02];
+
+    struct Session {
+        client: TcpStream,
+        runner: FrameRunner,
+        cpu: Cpu
+    }
+
+    impl Session {
+        fn start() -> Session {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+            let mut runner = FrameRunner::new(10);
+            runner.gdb = Some(GdbStub::accept(listener).unwrap());
+            let mut config = Config::new();
+            config.quirks = Quirks::from_name("schip").unwrap();
+            Session {
+                client,
+                runner,
+                cpu: Cpu::new(&PROGRAM.to_vec(), &config).unwrap()
+            }
+        }
+
+        fn send(&mut self, data: &[u8]) {
+            self.client.write_all(b"$").unwrap();
+            self.client.write_all(data).unwrap();
+            let checksum = format!("#{:02x}", checksum_of(data));
+            self.client.write_all(checksum.as_bytes()).unwrap();
+        }
+
+        //The next packet from the stub, polling it while waiting
+        fn reply(&mut self) -> String {
+            let mut input = vec![];
+            for _ in 0..500 {
+                self.runner.poll(&mut self.cpu).unwrap();
+                let mut buffer = [0u8; 1024];
+                match self.client.read(&mut buffer) {
+                    Ok(n) => input.extend_from_slice(&buffer[..n]),
+                    Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock || e.kind() == ::std::io::ErrorKind::TimedOut => {}
+                    Err(e) => panic!("{}", e)
+                }
+                let text = String::from_utf8_lossy(&input).into_owned();
+                if let Some(start) = text.find('$') {
+                    if let Some(end) = text[start..].find('#') {
+                        if text.len() >= start + end + 3 {
+                            return text[start + 1..start + end].to_string();
+                        }
+                    }
+                }
+            }
+            panic!("no reply, got {:?}", String::from_utf8_lossy(&input));
+        }
+
+        fn command(&mut self, data: &str) -> String {
+            self.send(data.as_bytes());
+            self.reply()
+        }
+
+        //Waits for the cpu to be let go, then runs frames until the debugger stops it
+        fn run_until_stopped(&mut self) {
+            for _ in 0..500 {
+                self.runner.poll(&mut self.cpu).unwrap();
+                if !self.runner.is_stopped() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            for _ in 0..100 {
+                if !self.runner.run(&mut self.cpu).unwrap() {
+                    return;
+                }
+            }
+            panic!("the cpu never stopped");
+        }
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut session = Session::start();
+        let registers = session.command("g");
+        //V0-VF, then I and the PC little endian
+        assert_eq!(&registers[..2], "00");
+        assert_eq!(&registers[32..40], "00000002");
+        assert_eq!(session.command("m200,6"), "600570011202");
+        assert_eq!(session.command("M300,2:abcd"), "OK");
+        assert_eq!(session.command("m300,2"), "abcd");
+    }
+
+    #[test]
+    fn step_continue_and_interrupt() {
+        let mut session = Session::start();
+        session.send(b"s");
+        session.run_until_stopped();
+        assert_eq!(session.reply(), "S05");
+        assert_eq!(session.cpu.registers().pc, 0x202);
+        assert_eq!(session.cpu.registers().gpr[0], 5);
+
+        assert_eq!(session.command("Z0,204,2"), "OK");
+        session.send(b"c");
+        session.run_until_stopped();
+        assert_eq!(session.reply(), "S05");
+        assert_eq!(session.cpu.registers().pc, 0x204);
+        assert_eq!(session.cpu.registers().gpr[0], 6);
+
+        assert_eq!(session.command("z0,204,2"), "OK");
+        session.send(b"c");
+        for _ in 0..500 {
+            session.runner.poll(&mut session.cpu).unwrap();
+            if !session.runner.is_stopped() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(session.runner.run(&mut session.cpu).unwrap());
+        session.client.write_all(&[0x03]).unwrap();
+        assert_eq!(session.reply(), "S02");
+        assert!(session.runner.is_stopped());
+        assert!(session.cpu.registers().gpr[0] > 6);
+    }
+
+    #[test]
+    fn bad_packets_get_errors() {
+        let mut session = Session::start();
+        assert_eq!(session.command(""), "");
+        assert_eq!(session.command("m1,ffffffffffffffff"), "E01");
+        assert_eq!(session.command("Mffffffffffffffff,2:abcd"), "E01");
+        assert_eq!(session.command("qXfer:features:read:target.xml:ffffffffffffffff,1"), "E01");
+        session.send("M200,1:\u{e9}".as_bytes());
+        assert_eq!(session.reply(), "E01");
+        session.send("\u{e9}".as_bytes());
+        assert_eq!(session.reply(), "");
+        //The PC past the end of RAM and a deeper stack than SCHIP's 16 entries
+        assert_eq!(session.command("P11=ffff"), "E01");
+        assert_eq!(session.command("P12=20"), "E01");
+        assert_eq!(session.command("P12=02"), "OK");
+        assert_eq!(session.cpu.registers().sp, 2);
+        assert_eq!(session.command("c10000"), "E01");
+        //Still answering
+        assert_eq!(session.command("m200,2"), "6005");
+    }
+    #[test]
+    fn memory_writes_reach_cached_instructions() {
+        let mut session = Session::start();
+        for _ in 0..3 {
+            session.send(b"s");
+            session.run_until_stopped();
+            assert_eq!(session.reply(), "S05");
+        }
+        assert_eq!(session.cpu.registers().pc, 0x202);
+        assert_eq!(session.cpu.registers().gpr[0], 6);
+        //ADD V0,1 becomes ADD V0,5 after it already ran
+        assert_eq!(session.command("M202,2:7005"), "OK");
+        session.send(b"s");
+        session.run_until_stopped();
+        assert_eq!(session.reply(), "S05");
+        assert_eq!(session.cpu.registers().gpr[0], 11);
+    }
+}
+
@@ -1,7 +1,5 @@
 extern crate sdl2;
 
-use keypad;
-
 pub struct Input {
     mapping: Vec<&'static KeyMapping>
 }
@@ -31,34 +29,27 @@ impl Input {
         return ret;
     }
 
-    pub fn handle_keys(&self, keypad: &mut keypad::Keypad, event: sdl2::event::Event) {
-        match event {
-            sdl2::event::Event::KeyDown {keycode, ..} => {
-                match keycode {
-                    Some(sdlkeycode) => {
-                        for x in 0..self.mapping.len() {
-                            if sdlkeycode == self.mapping[x].key {
-                                keypad.key_down(self.mapping[x].keypad);
-                            }
-                        }
-                    }
-                    _ => {}
-                }
-            }
-            sdl2::event::Event::KeyUp {keycode, ..} => {
-                match keycode {
-                    Some(sdlkeycode) => {
-                        for x in 0..self.mapping.len() {
-                            if sdlkeycode == self.mapping[x].key {
-                                keypad.key_up(self.mapping[x].keypad);
-                            }
-                        }
-                    }
-                    _ => {}
-                }
-            }
-            _ => {}
-        }
+    //The keypad keys an SDL key event presses (true) or releases (false)
+    pub fn keypad_events(&self, event: &sdl2::event::Event) -> Vec<(u8, bool)> {
+        let (keycode, pressed) = match *event {
+            sdl2::event::Event::KeyDown {keycode: Some(sdlkeycode), ..} => (sdlkeycode, true),
+            sdl2::event::Event::KeyUp {keycode: Some(sdlkeycode), ..} => (sdlkeycode, false),
+            _ => return vec![]
+        };
+        self.mapping.iter()
+            .filter(|m| m.key == keycode)
+            .map(|m| (m.keypad, pressed))
+            .collect()
+    }
+
+    //The keypad keys a character typed in a terminal stands for. Printable
+    //keys have their lower case character as the SDL keycode.
+    pub fn keypad_keys_for_char(&self, typed: char) -> Vec<u8> {
+        let typed = typed.to_ascii_lowercase();
+        self.mapping.iter()
+            .filter(|m| m.key as i32 == typed as i32)
+            .map(|m| m.keypad)
+            .collect()
     }
 }
 
@@ -67,7 +58,7 @@ pub struct KeyMapping {
     keypad: u8
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Mappings {
     Default,
     Tetris,
@@ -1,106 +1,596 @@
 extern crate rand;
 extern crate sdl2;
 
-use std::error::Error;
-use std::fs::File;
-use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::env;
+use std::iter;
+use std::process;
+use std::time::{Duration, Instant};
 
 mod cpu;
 mod screen;
 mod keypad;
 mod sound;
 mod input;
+mod capture;
+mod clock;
+mod cli;
+mod commands;
+mod rom;
+mod patch;
+mod watch;
+mod frame;
+mod gdb;
+mod debug;
+mod cheat;
+mod console;
+mod emulator;
+mod terminal;
 
 fn main() {
-    let sdl_context = sdl2::init().expect("Failed to init SDL2");
-    let mut event_pump = sdl_context.event_pump().expect("Failed to init SDL2 event_pump");
-    let video_subsystem = sdl_context.video().expect("Failed to init SDL2 video");
-    let audio_subsystem = sdl_context.audio().expect("Failed to init SDL2 audio");
-    let timer = sdl_context.timer().expect("Failed to init SDL2 timer subsystem");
-
-    //Load the rom file
-    let rom_file_name;
-    match env::args().nth(1) {
-        Some(r) => {
-            rom_file_name = r;
+    let command = match cli::parse(env::args().skip(1).collect()) {
+        Ok(c) => c,
+        Err(why) => {
+            eprintln!("chipy8: {}\n\n{}", why, cli::usage());
+            process::exit(2);
         }
-        _ => {
-            println!("Usage: chipy romfile.ch8");
-            return;
+    };
+    let result = match command {
+        cli::Command::Run(options) => run(options),
+        cli::Command::Term { options, style, key_hold } => term(options, style, key_hold),
+        cli::Command::Disasm(options) => commands::disasm(&options),
+        cli::Command::Asm { source, output, base } => commands::asm(&source, &output, base),
+        cli::Command::Test { options, frames, screenshot } => commands::test(&options, frames, screenshot.as_ref()),
+        cli::Command::Info(options) => commands::info(&options),
+        cli::Command::Cfg { options, calls, output } => commands::cfg(&options, calls, output.as_ref()),
+        cli::Command::Bench { options, frames } => commands::bench(&options, frames),
+        cli::Command::Help => {
+            println!("{}", cli::usage());
+            Ok(())
         }
+    };
+    if let Err(why) = result {
+        eprintln!("chipy8: {}", why);
+        process::exit(1);
+    }
+}
+
+fn run(options: cli::RunOptions) -> Result<(), String> {
+    //Load the rom file
+    let path = Path::new(&options.rom);
+    let mut buffer = rom::load(path, &options.cpu, &options.patch)?;
+    println!("\"{}\" read successfully", path.display());
+    if let Some(patch_path) = rom::find_patch(path, &options.patch) {
+        println!("Applied patch \"{}\"", patch_path.display());
     }
-    let path = Path::new(&rom_file_name);
-    let display = path.display();
-    let mut file = match File::open(&path) {
-        Err(why) => panic!("couldn't open {}: {}", display, Error::description(&why)),
-        Ok(file) => file,
+    let initial_state = match options.load_state {
+        Some(ref state_file_name) => Some(cpu::state::State::load(Path::new(state_file_name))?),
+        None => None
+    };
+    let state_path = path.with_extension("state");
+    let mut watcher = if options.watch {
+        let files = watched_files(path, &options);
+        let names: Vec<String> = files.iter().map(|f| format!("\"{}\"", f.display())).collect();
+        println!("Watching {} for changes", names.join(" and "));
+        Some(watch::Watcher::new(&files))
+    } else {
+        None
     };
-    let mut buffer = Vec::new();
-    let _ = file.read_to_end(&mut buffer);
-    println!("\"{}\" read successfully", display);
 
-    //Create the window
-    let window = video_subsystem.window(&format!("Chipy8 - Current key mappings: {:?}", input::Mappings::Default), 1024, 512).resizable().build().expect("Failed to create window");
+    let sdl_context = sdl2::init().expect("Failed to init SDL2");
+    let mut event_pump = sdl_context.event_pump().expect("Failed to init SDL2 event_pump");
+    let video_subsystem = sdl_context.video().expect("Failed to init SDL2 video");
+    let audio_subsystem = sdl_context.audio().expect("Failed to init SDL2 audio");
 
-    //Setup timers
-    let mut last_tick = 0;
-    let mut cycles = 0;
+    //Create the window, with room for the instances to compare
+    let instances = 1 + options.compare.len();
+    let (window_width, window_height) = screen::tiled_size(instances, options.scale);
+    let window = video_subsystem.window("Chipy8", window_width, window_height).resizable().build().expect("Failed to create window");
 
-    //let audio_device = sound::setup_audio(&audio_subsystem);
-    let mut sound = sound::Sound::new(&audio_subsystem);
-    let current_mapping = input::Mappings::Default;
+    //Timers, audio and recordings all advance once per frame
+    let frame_rate = clock::FRAME_RATE;
+    let beeper = options.beeper;
+    let mut sound = sound::device::Sound::new(&audio_subsystem, beeper, frame_rate);
+    let mut current_mapping = options.mapping;
     let mut input = input::Input::new(current_mapping);
     let mut renderer = window.renderer().present_vsync().build().expect("Failed to create SDL2 renderer");
 
-    let mut cpu = cpu::cpu::Cpu::new(&buffer);
+    let mut cpu = new_cpu(&buffer, &options)?;
+    if let Some(ref state) = initial_state {
+        cpu.load_state(state)?;
+    }
+
+    let runner = frame::new_runner(&options, &buffer, Some(cheat_dir(&options)))?;
+    let mut console = if options.console {
+        Some(console::Console::start())
+    } else {
+        None
+    };
+
+    let mut machine = emulator::Machine::new(cpu, runner);
+    machine.compared = compared_instances(path, &options)?;
+    for instance in &mut machine.compared {
+        println!("Comparing with {}", instance.label);
+        //All the instances start from the same point, like F9 loads into all of them
+        if let Some(ref state) = initial_state {
+            if let Err(why) = instance.cpu.load_state(state) {
+                return Err(format!("couldn't load the state into {}: {}", instance.label, why));
+            }
+        }
+    }
+    if options.paused {
+        machine.clock.toggle_pause();
+    }
+    update_title(&mut renderer, current_mapping, &machine);
+    if let Some(ref record_file_name) = options.record {
+        let scale = screen_scale(&renderer, instances);
+        machine.recorder = start_recording(Path::new(&record_file_name), &machine.cpu, scale, frame_rate, beeper);
+    }
+    if let Some(ref wav_file_name) = options.wav {
+        match sound::wav::WavCapture::create(Path::new(wav_file_name), 44100, beeper) {
+            Ok(w) => {
+                println!("Recording sound to \"{}\"", wav_file_name);
+                machine.wav_capture = Some(w);
+            }
+            Err(why) => println!("Failed to start recording sound to \"{}\": {}", wav_file_name, why)
+        }
+    }
+
+    let mut debug_view = None;
+    if options.debug {
+        debug_view = Some(debug::view::DebugView::open(&video_subsystem)?);
+        machine.frame_start_ram = Some(vec![]);
+    }
+
+    //The latest screens handed over by the emulation thread
+    let mut displays = vec![machine.cpu.screen().clone()];
+    displays.extend(machine.compared.iter().map(|i| i.cpu.screen().clone()));
+    let mut diverged = false;
+    let mut redraw = true;
+    let emulator = emulator::Emulator::start(machine);
 
     'running: loop {
+        //Sleeps until the emulation thread finished a batch of frames
+        match emulator.frames(emulator::frame_period()) {
+            Some(frames) => {
+                for frame in frames {
+                    for &beeping in &frame.sound {
+                        sound.add_frame(beeping);
+                    }
+                    for (display, screen) in displays.iter_mut().zip(frame.screens) {
+                        if let Some(s) = screen {
+                            *display = s;
+                            redraw = true;
+                        }
+                    }
+                    if frame.diverged != diverged {
+                        diverged = frame.diverged;
+                        redraw = true;
+                        update_title(&mut renderer, current_mapping, &emulator.lock());
+                    }
+                }
+            }
+            //Failed, or the debugger ended the program
+            None => break 'running
+        }
         for event in event_pump.poll_iter() {
+            if let Some(ref mut v) = debug_view {
+                //Keys typed into the debug window edit memory and registers while paused
+                let mut m = emulator.lock();
+                let editable = m.clock.is_paused() || m.runner.is_stopped();
+                if v.handle_event(&event, &mut m.cpu, editable) {
+                    continue;
+                }
+            }
             match event {
                 sdl2::event::Event::Quit {..} | sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::Escape), .. } => {
                     break 'running
                 },
+                sdl2::event::Event::Window { win_event_id: sdl2::event::WindowEventId::Close, window_id, .. } => {
+                    //With the debug window open, closing a window doesn't quit on its own
+                    if debug_view.as_ref().is_some_and(|v| v.window_id() == window_id) {
+                        debug_view = None;
+                        emulator.lock().frame_start_ram = None;
+                    } else {
+                        break 'running
+                    }
+                },
+                sdl2::event::Event::Window { .. } => redraw = true,
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::F6), .. } => {
+                    //The keypad goes to all the instances, then to each in turn
+                    let mut m = emulator.lock();
+                    if !m.compared.is_empty() {
+                        m.focus = match m.focus {
+                            None => Some(0),
+                            Some(i) if i < m.compared.len() => Some(i + 1),
+                            Some(_) => None
+                        };
+                        update_title(&mut renderer, current_mapping, &m);
+                    }
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::F8), .. } => {
+                    // Debug window key pressed
+                    if debug_view.take().is_none() {
+                        match debug::view::DebugView::open(&video_subsystem) {
+                            Ok(v) => debug_view = Some(v),
+                            Err(why) => println!("{}", why)
+                        }
+                    }
+                    emulator.lock().frame_start_ram = debug_view.as_ref().map(|_| vec![]);
+                },
                 sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::F1), .. } => {
-                    let mut window = renderer.window_mut().unwrap();
-                    window.set_title(&format!("Chipy8 - Current key mappings: {:?}", input::Mappings::Default));
-                    input = input::Input::new(input::Mappings::Default);
+                    current_mapping = input::Mappings::Default;
+                    input = input::Input::new(current_mapping);
+                    update_title(&mut renderer, current_mapping, &emulator.lock());
                 },
                 sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::F2), .. } => {
-                    let mut window = renderer.window_mut().unwrap();
-                    window.set_title(&format!("Chipy8 - Current key mappings: {:?}", input::Mappings::Alt));
-                    input = input::Input::new(input::Mappings::Alt);
+                    current_mapping = input::Mappings::Alt;
+                    input = input::Input::new(current_mapping);
+                    update_title(&mut renderer, current_mapping, &emulator.lock());
                 },
                 sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::F3), .. } => {
-                    let mut window = renderer.window_mut().unwrap();
-                    window.set_title(&format!("Chipy8 - Current key mappings: {:?}", input::Mappings::Tetris));
-                    input = input::Input::new(input::Mappings::Tetris);
+                    current_mapping = input::Mappings::Tetris;
+                    input = input::Input::new(current_mapping);
+                    update_title(&mut renderer, current_mapping, &emulator.lock());
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::P), .. } |
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::Pause), .. } => {
+                    let mut m = emulator.lock();
+                    m.clock.toggle_pause();
+                    update_title(&mut renderer, current_mapping, &m);
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::N), .. } => {
+                    // Frame advance while paused
+                    emulator.lock().clock.advance_frame();
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::Minus), .. } |
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::KpMinus), .. } => {
+                    let mut m = emulator.lock();
+                    m.clock.slower();
+                    update_title(&mut renderer, current_mapping, &m);
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::Equals), .. } |
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::KpPlus), .. } => {
+                    let mut m = emulator.lock();
+                    m.clock.faster();
+                    update_title(&mut renderer, current_mapping, &m);
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::Tab), .. } => {
+                    let mut m = emulator.lock();
+                    m.clock.toggle_turbo();
+                    update_title(&mut renderer, current_mapping, &m);
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::Backquote), repeat: false, .. } => {
+                    let mut m = emulator.lock();
+                    m.clock.set_fast_forward(true);
+                    update_title(&mut renderer, current_mapping, &m);
+                },
+                sdl2::event::Event::KeyUp { keycode: Some(sdl2::keyboard::Keycode::Backquote), .. } => {
+                    let mut m = emulator.lock();
+                    m.clock.set_fast_forward(false);
+                    update_title(&mut renderer, current_mapping, &m);
                 },
                 sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::F10), .. } |
                 sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::Period), .. } => {
                     // Reset key pressed
-                    cpu = cpu::cpu::Cpu::new(&buffer);
+                    emulator.lock().reset(new_cpu(&buffer, &options).expect("ROM loaded at startup failed to load again"));
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::F5), .. } => {
+                    match emulator.lock().cpu.save_state().save(&state_path) {
+                        Ok(_) => println!("State saved to \"{}\"", state_path.display()),
+                        Err(why) => println!("Failed to save state: {}", why)
+                    }
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::F9), .. } => {
+                    //Into every instance, so they go on from the same point
+                    let loaded = cpu::state::State::load(&state_path).and_then(|state| {
+                        let mut m = emulator.lock();
+                        m.cpu.load_state(&state)?;
+                        for instance in &mut m.compared {
+                            instance.cpu.load_state(&state)?;
+                        }
+                        Ok(())
+                    });
+                    match loaded {
+                        Ok(_) => println!("State loaded from \"{}\"", state_path.display()),
+                        Err(why) => println!("Failed to load state: {}", why)
+                    }
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::F12), .. } => {
+                    // Screenshot key pressed
+                    let scale = screen_scale(&renderer, instances);
+                    match capture::screenshot::save(emulator.lock().cpu.screen(), path, scale) {
+                        Ok(files) => {
+                            for file in files {
+                                println!("Screenshot saved to \"{}\"", file.display());
+                            }
+                        }
+                        Err(why) => println!("Failed to save screenshot: {}", why)
+                    }
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(sdl2::keyboard::Keycode::F11), keymod, .. } => {
+                    // Record key pressed, shift selects a Y4M video stream instead of a GIF
+                    let mut m = emulator.lock();
+                    match m.recorder.take() {
+                        Some(r) => stop_recording(r),
+                        None => {
+                            let extension = if keymod.intersects(sdl2::keyboard::LSHIFTMOD | sdl2::keyboard::RSHIFTMOD) {
+                                "y4m"
+                            } else {
+                                "gif"
+                            };
+                            let scale = screen_scale(&renderer, instances);
+                            let record_path = capture::recorder::Recorder::default_path(path, extension);
+                            m.recorder = start_recording(&record_path, &m.cpu, scale, frame_rate, beeper);
+                        }
+                    }
                 },
             _ => {}
             }
-            input.handle_keys(&mut cpu.keypad, event);
+            for (key, pressed) in input.keypad_events(&event) {
+                emulator.key(key, pressed);
+            }
+        }
+        if watcher.as_mut().is_some_and(|w| w.changed()) {
+            //Keep running the old program if the new one doesn't load
+            let reload = rom::load(path, &options.cpu, &options.patch)
+                .and_then(|b| new_cpu(&b, &options).map(|c| (b, c)))
+                .and_then(|(b, c)| compared_instances(path, &options).map(|i| (b, c, i)));
+            match reload {
+                Ok((new_buffer, mut reloaded, compared)) => {
+                    let mut guard = emulator.lock();
+                    let m = &mut *guard;
+                    if options.watch_fresh {
+                        current_mapping = options.mapping;
+                        input = input::Input::new(current_mapping);
+                        update_title(&mut renderer, current_mapping, m);
+                    } else {
+                        reloaded.screen_mut().palette = m.cpu.screen().palette;
+                    }
+                    buffer = new_buffer;
+                    m.compared = compared;
+                    m.reset(reloaded);
+                    if let Some(ref mut c) = m.runner.coverage {
+                        c.reset(&buffer);
+                    }
+                    if options.strict {
+                        m.runner.checker = Some(cpu::sanity::Checker::new(options.cpu.platform.ram_size(), options.cpu.base_address, buffer.len()));
+                    }
+                    //A rebuilt ROM has a different CRC32, so different cheats
+                    m.runner.cheats = Some(frame::load_cheats(cheat_dir(&options), &buffer));
+                    //A patch may have been added next to the ROM or removed
+                    watcher = Some(watch::Watcher::new(&watched_files(path, &options)));
+                    println!("A watched file changed, reloaded {} bytes of \"{}\"", buffer.len(), path.display());
+                }
+                Err(why) => println!("Failed to reload: {}", why)
+            }
         }
-        let now = timer.performance_counter();
+        if let Some(ref mut c) = console {
+            let mut guard = emulator.lock();
+            let m = &mut *guard;
+            if let Some(ref mut cheats) = m.runner.cheats {
+                c.poll(&mut m.cpu, cheats);
+            }
+        }
+        if redraw {
+            screen::draw_tiled(&mut renderer, &displays, diverged);
+            redraw = false;
+        }
+        if let Some(ref mut v) = debug_view {
+            let m = emulator.lock();
+            if let Some(ref ram) = m.frame_start_ram {
+                v.set_frame_start(ram);
+            }
+            v.draw(&m.cpu, m.clock.is_paused() || m.runner.is_stopped());
+        }
+    }
+    let (machine, outcome) = emulator.stop();
+    if let Some(r) = machine.recorder {
+        stop_recording(r);
+    }
+    if let Some(w) = machine.wav_capture {
+        match w.finish() {
+            Ok(samples) => println!("Recorded {} samples of sound", samples),
+            Err(why) => println!("Failed to finish recording sound: {}", why)
+        }
+    }
+    machine.runner.finish()?;
+    outcome
+}
+
+//Runs in the terminal, for when there is no display
+fn term(options: cli::RunOptions, style: terminal::render::Style, key_hold: u64) -> Result<(), String> {
+    let path = Path::new(&options.rom);
+    let buffer = rom::load(path, &options.cpu, &options.patch)?;
+    let mut cpu = new_cpu(&buffer, &options)?;
+    if let Some(ref state_file_name) = options.load_state {
+        cpu.load_state(&cpu::state::State::load(Path::new(state_file_name))?)?;
+    }
+    let mut machine = emulator::Machine::new(cpu, frame::new_runner(&options, &buffer, Some(cheat_dir(&options)))?);
+    if options.paused {
+        machine.clock.toggle_pause();
+    }
+    let input = input::Input::new(options.mapping);
+    let name = path.file_name().map_or(options.rom.clone(), |n| n.to_string_lossy().into_owned());
 
-        //No set speed for the chip8 CPU
-        //This seems about right
-        if now - cycles > ((timer.performance_frequency()/1000/1000)*555) {
-            cpu.execute_next_instruction();
-            cycles = timer.performance_counter();
+    let tty = match terminal::raw::RawTerminal::enable() {
+        Ok(t) => t,
+        Err(why) => return Err(format!("couldn't take over the terminal: {}", why))
+    };
+    let mut display = machine.cpu.screen().clone();
+    let emulator = emulator::Emulator::start(machine);
+    //Terminals only send a key when it's typed and as it repeats, so it's
+    //let go of once it stops coming for a while
+    let hold = Duration::from_millis(key_hold);
+    let mut held: [Option<Instant>;16] = [None;16];
+    let mut status = String::new();
+    let mut redraw = true;
+    let mut failure = None;
+
+    'running: loop {
+        match emulator.frames(emulator::frame_period()) {
+            Some(frames) => {
+                for frame in frames {
+                    if let Some(Some(s)) = frame.screens.into_iter().next() {
+                        display = s;
+                        redraw = true;
+                    }
+                }
+            }
+            None => break 'running
+        }
+        let typed = match tty.read() {
+            Ok(t) => t,
+            Err(why) => {
+                failure = Some(format!("couldn't read the terminal: {}", why));
+                break 'running;
+            }
+        };
+        let now = Instant::now();
+        let mut bytes = typed.into_iter().peekable();
+        while let Some(byte) = bytes.next() {
+            match byte {
+                //Ctrl+C
+                3 => break 'running,
+                0x1B => {
+                    //Escape starts the codes of keys like the arrows, on its own it quits
+                    match bytes.peek() {
+                        Some(&b'[') | Some(&b'O') => {
+                            bytes.next();
+                            for code in bytes.by_ref() {
+                                if (0x40..=0x7E).contains(&code) {
+                                    break;
+                                }
+                            }
+                        }
+                        _ => break 'running
+                    }
+                }
+                b'p' | b'P' => emulator.lock().clock.toggle_pause(),
+                b'n' | b'N' => emulator.lock().clock.advance_frame(),
+                b'-' => emulator.lock().clock.slower(),
+                b'=' | b'+' => emulator.lock().clock.faster(),
+                b'\t' => emulator.lock().clock.toggle_turbo(),
+                b'.' => emulator.lock().reset(new_cpu(&buffer, &options).expect("ROM loaded at startup failed to load again")),
+                _ => {
+                    for key in input.keypad_keys_for_char(byte as char) {
+                        emulator.key(key, true);
+                        held[key as usize] = Some(now + hold);
+                    }
+                }
+            }
+        }
+        for (key, release) in held.iter_mut().enumerate() {
+            if release.is_some_and(|r| r <= now) {
+                emulator.key(key as u8, false);
+                *release = None;
+            }
         }
 
-        //Cap at 120FPS
-        if ((now - last_tick)*1000)/timer.performance_frequency() > (1000/120) {
-            last_tick = now;
-            cpu.draw(&mut renderer);
-            cpu.tick_timers();
+        let speed = emulator.lock().clock.describe();
+        let new_status = format!("{} - {:?} keys - Speed: {} - Esc quits", name, options.mapping, speed);
+        if new_status != status {
+            status = new_status;
+            redraw = true;
+        }
+        if redraw {
+            let mut lines = terminal::render::render(&display, style);
+            lines.push(status.clone());
+            if let Err(why) = tty.draw(&lines) {
+                failure = Some(format!("couldn't draw to the terminal: {}", why));
+                break 'running;
+            }
+            redraw = false;
         }
-        sound.set_state(cpu.get_sound_state());
+    }
+    //Back to the normal screen before anything else is printed
+    drop(tty);
+    let (machine, outcome) = emulator.stop();
+    machine.runner.finish()?;
+    match failure {
+        Some(why) => Err(why),
+        None => outcome
+    }
+}
+
+fn new_cpu(buffer: &Vec<u8>, options: &cli::RunOptions) -> Result<cpu::cpu::Cpu, String> {
+    let mut cpu = match cpu::cpu::Cpu::new(buffer, &options.cpu) {
+        Ok(c) => c,
+        Err(why) => return Err(format!("couldn't load \"{}\": {}", options.rom, why))
+    };
+    cpu.screen_mut().palette = options.palette;
+    Ok(cpu)
+}
+
+//The instances added with --compare, each loading the ROM with its own patch and platform
+fn compared_instances(path: &Path, options: &cli::RunOptions) -> Result<Vec<emulator::Instance>, String> {
+    let mut instances = vec![];
+    for compare in &options.compare {
+        let buffer = rom::load(path, &compare.cpu, &compare.patch)?;
+        instances.push(emulator::Instance::new(&compare.label, buffer, compare.cpu, compare.palette, compare.instructions_per_frame)?);
+    }
+    Ok(instances)
+}
+
+//The ROM and the patches the instances apply to it, which --watch reloads on
+fn watched_files(path: &Path, options: &cli::RunOptions) -> Vec<PathBuf> {
+    let mut files = vec![path.to_path_buf()];
+    let patches = iter::once(&options.patch).chain(options.compare.iter().map(|c| &c.patch));
+    for patch in patches.filter_map(|p| rom::find_patch(path, p)) {
+        if !files.contains(&patch) {
+            files.push(patch);
+        }
+    }
+    files
+}
+
+//--cheat-dir, or the default directory next to where chipy8 runs
+fn cheat_dir(options: &cli::RunOptions) -> &str {
+    options.cheat_dir.as_deref().unwrap_or(cheat::DEFAULT_DIR)
+}
+
+fn update_title(renderer: &mut sdl2::render::Renderer, mapping: input::Mappings, machine: &emulator::Machine) {
+    let mut title = format!("Chipy8 - Current key mappings: {:?} - Speed: {}", mapping, machine.clock.describe());
+    if !machine.compared.is_empty() {
+        let keypad = match machine.focus {
+            None => "all".to_string(),
+            Some(0) => "1".to_string(),
+            Some(i) => format!("{} ({})", i + 1, machine.compared[i - 1].label)
+        };
+        title.push_str(&format!(" - Keypad: {}", keypad));
+        if machine.diverged() {
+            title.push_str(" - Screens differ");
+        }
+    }
+    let window = renderer.window_mut().unwrap();
+    window.set_title(&title);
+}
+
+//Pixel scale of the first screen, for screenshots and recordings
+fn screen_scale(renderer: &sdl2::render::Renderer, instances: usize) -> (u32, u32) {
+    let output_size = renderer.output_size().expect("Renderer output_size error");
+    let (_, _, width, height) = screen::tile_area(output_size, instances, 0);
+    screen::Screen::scale_for((width, height))
+}
+
+fn start_recording(record_path: &Path, cpu: &cpu::cpu::Cpu, scale: (u32, u32), frame_rate: u32, beeper: sound::generator::BeeperConfig) -> Option<capture::recorder::Recorder> {
+    match capture::recorder::Recorder::start(record_path, cpu.screen(), scale, frame_rate, beeper) {
+        Ok(r) => {
+            let (width, height) = r.frame_size();
+            println!("Recording {}x{} at {} fps to \"{}\"", width, height, frame_rate, record_path.display());
+            Some(r)
+        }
+        Err(why) => {
+            println!("Failed to start recording to \"{}\": {}", record_path.display(), why);
+            None
+        }
+    }
+}
+
+fn stop_recording(recorder: capture::recorder::Recorder) {
+    let record_path = recorder.path().to_path_buf();
+    match recorder.finish() {
+        Ok(frames) => println!("Recorded {} frames to \"{}\"", frames, record_path.display()),
+        Err(why) => println!("Failed to finish recording to \"{}\": {}", record_path.display(), why)
     }
 }
@@ -0,0 +1,330 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use capture::png::Crc32;
+
+//Applies IPS and BPS patches to a ROM in memory. The format is taken from
+//the header, so the file extension doesn't matter.
+
+//A patched ROM may be at most max_size bytes, what fits in the platform's RAM
+pub fn apply(patch_path: &Path, rom: &[u8], max_size: usize) -> Result<Vec<u8>, String> {
+    let display = patch_path.display();
+    let mut patch = Vec::new();
+    match File::open(patch_path).and_then(|mut f| f.read_to_end(&mut patch)) {
+        Ok(_) => {}
+        Err(why) => return Err(format!("couldn't read patch \"{}\": {}", display, why))
+    }
+    let result = if patch.starts_with(b"PATCH") {
+        ips(rom, &patch)
+    } else if patch.starts_with(b"BPS1") {
+        bps(rom, &patch, max_size)
+    } else {
+        Err("not an IPS or BPS patch".to_string())
+    };
+    result.map_err(|why| format!("couldn't apply patch \"{}\": {}", display, why))
+}
+
+//IPS is a list of records, each either bytes to write at an offset or a
+//run of one repeated byte, ending with "EOF" and an optional new length
+pub fn ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = rom.to_vec();
+    let mut pos = 5;
+    loop {
+        let header = read(patch, pos, 3)?;
+        pos += 3;
+        if header == b"EOF" {
+            break;
+        }
+        let offset = (header[0] as usize) << 16 | (header[1] as usize) << 8 | header[2] as usize;
+        let size = read(patch, pos, 2)?;
+        let size = (size[0] as usize) << 8 | size[1] as usize;
+        pos += 2;
+        let data = if size == 0 {
+            let run = read(patch, pos, 3)?;
+            pos += 3;
+            vec![run[2]; (run[0] as usize) << 8 | run[1] as usize]
+        } else {
+            let data = read(patch, pos, size)?.to_vec();
+            pos += size;
+            data
+        };
+        if out.len() < offset + data.len() {
+            out.resize(offset + data.len(), 0);
+        }
+        out[offset..offset + data.len()].copy_from_slice(&data);
+    }
+    if let Ok(length) = read(patch, pos, 3) {
+        out.truncate((length[0] as usize) << 16 | (length[1] as usize) << 8 | length[2] as usize);
+    }
+    Ok(out)
+}
+
+//BPS builds the new ROM from copies out of the old ROM, the patch and the
+//output written so far, and carries CRC32s of all three so a patch for a
+//different ROM or a damaged patch is caught
+pub fn bps(rom: &[u8], patch: &[u8], max_size: usize) -> Result<Vec<u8>, String> {
+    if patch.len() < 4 + 12 {
+        return Err("the patch is truncated".to_string());
+    }
+    let footer = patch.len() - 12;
+    let source_crc = le32(&patch[footer..]);
+    let target_crc = le32(&patch[footer + 4..]);
+    let patch_crc = le32(&patch[footer + 8..]);
+    if crc32(&patch[..footer + 8]) != patch_crc {
+        return Err("the patch is damaged, its checksum doesn't match".to_string());
+    }
+    if crc32(rom) != source_crc {
+        return Err(format!("the patch is for a different ROM, expected CRC32 {:08X} but the ROM has {:08X}", source_crc, crc32(rom)));
+    }
+
+    let mut pos = 4;
+    let source_size = varint(patch, &mut pos, footer)?;
+    let target_size = varint(patch, &mut pos, footer)?;
+    let metadata_size = varint(patch, &mut pos, footer)?;
+    pos = pos.saturating_add(metadata_size);
+    if source_size != rom.len() {
+        return Err(format!("the patch expects a {} byte ROM", source_size));
+    }
+    //The size comes from the file, so check it before allocating for it
+    if target_size > max_size {
+        return Err(format!("the patched ROM would be {} bytes, at most {} bytes fit", target_size, max_size));
+    }
+    let mut out = Vec::with_capacity(target_size);
+    let mut source_offset: isize = 0;
+    let mut target_offset: isize = 0;
+    while pos < footer {
+        let data = varint(patch, &mut pos, footer)?;
+        let length = (data >> 2) + 1;
+        match data & 3 {
+            //SourceRead, the same bytes as in the ROM at this position
+            0 => {
+                let start = out.len();
+                out.extend_from_slice(read(rom, start, length)?);
+            }
+            //TargetRead, new bytes from the patch
+            1 => {
+                out.extend_from_slice(read(patch, pos, length)?);
+                pos += length;
+            }
+            //SourceCopy, bytes from elsewhere in the ROM
+            2 => {
+                source_offset = move_offset(source_offset, signed_varint(patch, &mut pos, footer)?)?;
+                if source_offset < 0 {
+                    return Err("the patch reads before the start of the ROM".to_string());
+                }
+                out.extend_from_slice(read(rom, source_offset as usize, length)?);
+                source_offset = move_offset(source_offset, length as isize)?;
+            }
+            //TargetCopy, bytes from the output, which may overlap what is being written
+            _ => {
+                target_offset = move_offset(target_offset, signed_varint(patch, &mut pos, footer)?)?;
+                for _ in 0..length {
+                    if target_offset < 0 || target_offset as usize >= out.len() {
+                        return Err("the patch copies from outside the output".to_string());
+                    }
+                    let byte = out[target_offset as usize];
+                    out.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+        if out.len() > target_size {
+            return Err("the patch writes past the end of the output".to_string());
+        }
+    }
+    if out.len() != target_size || crc32(&out) != target_crc {
+        return Err("the patched ROM doesn't match the patch's checksum".to_string());
+    }
+    Ok(out)
+}
+
+fn read(buffer: &[u8], pos: usize, length: usize) -> Result<&[u8], String> {
+    match pos.checked_add(length) {
+        Some(end) if end <= buffer.len() => Ok(&buffer[pos..end]),
+        _ => Err("the patch is truncated".to_string())
+    }
+}
+
+//Moves a BPS copy offset, which a damaged patch could push past the range of isize
+fn move_offset(offset: isize, delta: isize) -> Result<isize, String> {
+    offset.checked_add(delta).ok_or_else(|| "the patch copies from too far away".to_string())
+}
+
+fn varint(patch: &[u8], pos: &mut usize, end: usize) -> Result<usize, String> {
+    let mut data: usize = 0;
+    let mut shift: usize = 1;
+    loop {
+        if *pos >= end || shift > usize::MAX >> 8 {
+            return Err("the patch is truncated".to_string());
+        }
+        let x = patch[*pos] as usize;
+        *pos += 1;
+        data += (x & 0x7F) * shift;
+        if x & 0x80 != 0 {
+            return Ok(data);
+        }
+        shift <<= 7;
+        data += shift;
+    }
+}
+
+//The lowest bit is the sign
+fn signed_varint(patch: &[u8], pos: &mut usize, end: usize) -> Result<isize, String> {
+    let data = varint(patch, pos, end)?;
+    let magnitude = (data >> 1) as isize;
+    Ok(if data & 1 != 0 { -magnitude } else { magnitude })
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bps, crc32, ips};
+
+    //Same encoding that varint reads
+    fn push_varint(out: &mut Vec<u8>, mut data: usize) {
+        loop {
+            let x = (data & 0x7F) as u8;
+            data >>= 7;
+            if data == 0 {
+                out.push(0x80 | x);
+                return;
+            }
+            out.push(x);
+            data -= 1;
+        }
+    }
+
+    fn push_le32(out: &mut Vec<u8>, value: u32) {
+        out.extend_from_slice(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]);
+    }
+
+    fn bps_patch(source: &[u8], target: &[u8], target_size: usize, commands: &[u8]) -> Vec<u8> {
+        bps_patch_sized(source, source.len(), target, target_size, commands)
+    }
+
+    fn bps_patch_sized(source: &[u8], source_size: usize, target: &[u8], target_size: usize, commands: &[u8]) -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        push_varint(&mut patch, source_size);
+        push_varint(&mut patch, target_size);
+        push_varint(&mut patch, 0);
+        patch.extend_from_slice(commands);
+        push_le32(&mut patch, crc32(source));
+        push_le32(&mut patch, crc32(target));
+        let patch_crc = crc32(&patch);
+        push_le32(&mut patch, patch_crc);
+        patch
+    }
+
+    //A command for length bytes
+    fn command(out: &mut Vec<u8>, action: usize, length: usize) {
+        push_varint(out, (length - 1) << 2 | action);
+    }
+
+    static SOURCE: [u8;8] = [1, 2, 3, 4, 5, 6, 7, 8];
+    static TARGET: [u8;11] = [1, 2, 9, 9, 8, 6, 7, 6, 7, 6, 7];
+
+    //Every kind of command, ending with a TargetCopy that overlaps what it writes
+    fn commands() -> Vec<u8> {
+        let mut commands = vec![];
+        command(&mut commands, 0, 2);
+        command(&mut commands, 1, 3);
+        commands.extend_from_slice(&[9, 9, 8]);
+        command(&mut commands, 2, 2);
+        push_varint(&mut commands, 5 << 1);
+        command(&mut commands, 3, 4);
+        push_varint(&mut commands, 5 << 1);
+        commands
+    }
+
+    #[test]
+    fn ips_records_and_runs() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0, 0, 1, 0, 2, 0xAA, 0xBB]);
+        //A run of three 0xCC
+        patch.extend_from_slice(&[0, 0, 5, 0, 0, 0, 3, 0xCC]);
+        //Past the end of the ROM, which grows to fit
+        patch.extend_from_slice(&[0, 0, 10, 0, 1, 0xDD]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(ips(&[0;8], &patch).unwrap(), vec![0, 0xAA, 0xBB, 0, 0, 0xCC, 0xCC, 0xCC, 0, 0, 0xDD]);
+        //The optional length after EOF cuts the ROM
+        patch.extend_from_slice(&[0, 0, 6]);
+        assert_eq!(ips(&[0;8], &patch).unwrap(), vec![0, 0xAA, 0xBB, 0, 0, 0xCC]);
+    }
+
+    #[test]
+    fn ips_truncated_records() {
+        assert!(ips(&[0;8], b"PATCH\x00\x00\x01\x00\x04\xAA\xBB").is_err());
+        assert!(ips(&[0;8], b"PATCH\x00\x00\x01\x00\x00\x00").is_err());
+        assert!(ips(&[0;8], b"PATCH\x00\x00").is_err());
+    }
+
+    #[test]
+    fn bps_commands() {
+        let patch = bps_patch(&SOURCE, &TARGET, TARGET.len(), &commands());
+        assert_eq!(bps(&SOURCE, &patch, 0x1000), Ok(TARGET.to_vec()));
+    }
+
+    #[test]
+    fn bps_checksums() {
+        let mut patch = bps_patch(&SOURCE, &TARGET, TARGET.len(), &commands());
+        assert!(bps(&[1, 2, 3, 4, 5, 6, 7, 9], &patch, 0x1000).unwrap_err().contains("different ROM"));
+        patch[6] ^= 1;
+        assert!(bps(&SOURCE, &patch, 0x1000).unwrap_err().contains("damaged"));
+        //Commands that don't build the target the footer describes
+        let mut commands = commands();
+        commands[4] = 7;
+        let patch = bps_patch(&SOURCE, &TARGET, TARGET.len(), &commands);
+        assert!(bps(&SOURCE, &patch, 0x1000).unwrap_err().contains("doesn't match"));
+    }
+
+    #[test]
+    fn bps_sizes_and_bounds() {
+        let patch = bps_patch(&SOURCE, &TARGET, TARGET.len(), &commands());
+        assert!(bps(&SOURCE, &patch, 10).unwrap_err().contains("at most 10 bytes"));
+        let wrong_source = bps_patch_sized(&SOURCE, 7, &TARGET, TARGET.len(), &commands());
+        assert!(bps(&SOURCE, &wrong_source, 0x1000).unwrap_err().contains("expects a 7 byte ROM"));
+        let too_long = bps_patch(&SOURCE, &TARGET, 3, &commands());
+        assert!(bps(&SOURCE, &too_long, 0x1000).unwrap_err().contains("past the end"));
+
+        //SourceCopy from before the start of the ROM
+        let mut commands = vec![];
+        command(&mut commands, 2, 1);
+        push_varint(&mut commands, 1 << 1 | 1);
+        assert!(bps(&SOURCE, &bps_patch(&SOURCE, &TARGET, 1, &commands), 0x1000).unwrap_err().contains("before the start"));
+        //TargetCopy from output that isn't written yet
+        let mut commands = vec![];
+        command(&mut commands, 3, 1);
+        push_varint(&mut commands, 0);
+        assert!(bps(&SOURCE, &bps_patch(&SOURCE, &TARGET, 1, &commands), 0x1000).unwrap_err().contains("outside the output"));
+        //SourceCopy from far past the end of the ROM
+        let mut commands = vec![];
+        command(&mut commands, 2, 1);
+        push_varint(&mut commands, 0);
+        command(&mut commands, 2, 1);
+        push_varint(&mut commands, 1 << 62);
+        assert!(bps(&SOURCE, &bps_patch(&SOURCE, &TARGET, 2, &commands), 0x1000).unwrap_err().contains("truncated"));
+    }
+
+    #[test]
+    fn bps_truncated_records() {
+        //A command cut off by the footer
+        let patch = bps_patch(&SOURCE, &TARGET, 1, &[0x04]);
+        assert!(bps(&SOURCE, &patch, 0x1000).unwrap_err().contains("truncated"));
+        //A SourceCopy without its offset
+        let mut commands = vec![];
+        command(&mut commands, 2, 1);
+        let patch = bps_patch(&SOURCE, &TARGET, 1, &commands);
+        assert!(bps(&SOURCE, &patch, 0x1000).unwrap_err().contains("truncated"));
+        assert!(bps(&SOURCE, b"BPS1\x80\x80", 0x1000).unwrap_err().contains("truncated"));
+    }
+}
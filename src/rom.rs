@@ -0,0 +1,80 @@
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use cpu::config::Config;
+use patch;
+
+#[derive(Debug, Clone)]
+pub enum Patch {
+    //Use an .ips or .bps file next to the ROM if there is one
+    Auto,
+    File(String),
+    Off
+}
+
+//Reads a ROM file, applies its patch, and checks that it fits the configured
+//platform and load address
+pub fn load(path: &Path, config: &Config, patch: &Patch) -> Result<Vec<u8>, String> {
+    let display = path.display();
+    let max = config.platform.max_rom_size(config.base_address);
+    let metadata = match fs::metadata(path) {
+        Ok(m) => m,
+        Err(why) => return Err(format!("couldn't open ROM \"{}\": {}", display, why))
+    };
+    if metadata.is_dir() {
+        return Err(format!("\"{}\" is a directory, not a ROM file", display));
+    }
+    let mut buffer = Vec::new();
+    match File::open(path).and_then(|mut f| f.read_to_end(&mut buffer)) {
+        Ok(_) => {}
+        Err(why) => return Err(format!("couldn't read ROM \"{}\": {}", display, why))
+    }
+    if let Some(patch_path) = find_patch(path, patch) {
+        buffer = patch::apply(&patch_path, &buffer, max)?;
+    }
+    if buffer.len() > max {
+        return Err(format!("ROM \"{}\" is {} bytes, the maximum for {:?} loaded at {:#05X} is {} bytes",
+                           display, buffer.len(), config.platform, config.base_address, max));
+    }
+    if buffer.is_empty() {
+        return Err(format!("ROM \"{}\" is empty", display));
+    }
+    //Only a warning, a binary ROM can happen to be all printable bytes
+    if looks_like_text(&buffer) {
+        if buffer.iter().all(|&b| b.is_ascii_hexdigit() || b.is_ascii_whitespace()) {
+            println!("Warning: ROM \"{}\" looks like a hex dump, it may need converting to a binary file first", display);
+        } else {
+            println!("Warning: ROM \"{}\" looks like a text file, it may need assembling with \"chipy8 asm\" first", display);
+        }
+    }
+    Ok(buffer)
+}
+
+//The patch load applies to the ROM, "GAME.ips" or "GAME.ch8.ips" for "GAME.ch8"
+pub fn find_patch(path: &Path, patch: &Patch) -> Option<PathBuf> {
+    match *patch {
+        Patch::Auto => {
+            for extension in &["ips", "bps"] {
+                let mut appended = path.as_os_str().to_owned();
+                appended.push(".");
+                appended.push(extension);
+                for candidate in [path.with_extension(extension), PathBuf::from(appended)] {
+                    if candidate.is_file() {
+                        return Some(candidate);
+                    }
+                }
+            }
+            None
+        }
+        Patch::File(ref file) => Some(PathBuf::from(file)),
+        Patch::Off => None
+    }
+}
+
+//Binary ROMs practically always contain bytes outside printable ASCII,
+//multi line text that doesn't is most likely source code or a hex dump
+fn looks_like_text(buffer: &[u8]) -> bool {
+    buffer.contains(&b'\n') && buffer.iter().all(|&b| b == b'\n' || b == b'\r' || b == b'\t' || (0x20..0x7F).contains(&b))
+}
@@ -1,21 +1,109 @@
 extern crate sdl2;
 
-const WIDTH: usize = 64;
-const HEIGHT: usize = 32;
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
 
+//Pixels between tiled screens, in the separator colour
+pub const TILE_GAP: u32 = 4;
+static SEPARATOR: (u8, u8, u8) = (0x40, 0x40, 0x40);
+static DIVERGED: (u8, u8, u8) = (0xE0, 0x30, 0x30);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Palette {
+    pub background: (u8, u8, u8),
+    pub foreground: (u8, u8, u8)
+}
+
+pub static PALETTES: [&str;5] = ["default", "amber", "green", "lcd", "blue"];
+
+impl Palette {
+    pub fn new() -> Palette {
+        Palette {
+            background: (0, 0, 0),
+            foreground: (255, 255, 255)
+        }
+    }
+
+    //One of the named palettes, or a custom "RRGGBB,RRGGBB" background,foreground pair
+    pub fn from_name(name: &str) -> Option<Palette> {
+        let (background, foreground) = match &name.to_lowercase()[..] {
+            "default" => return Some(Palette::new()),
+            "amber" => ((0x1A, 0x0F, 0x00), (0xFF, 0xB0, 0x00)),
+            "green" => ((0x00, 0x14, 0x00), (0x33, 0xFF, 0x33)),
+            "lcd" => ((0x9B, 0xBC, 0x0F), (0x0F, 0x38, 0x0F)),
+            "blue" => ((0x00, 0x00, 0x80), (0xFF, 0xFF, 0xFF)),
+            custom => {
+                let colors: Vec<&str> = custom.split(',').collect();
+                if colors.len() != 2 {
+                    return None;
+                }
+                match (parse_color(colors[0]), parse_color(colors[1])) {
+                    (Some(bg), Some(fg)) => (bg, fg),
+                    _ => return None
+                }
+            }
+        };
+        Some(Palette {
+            background,
+            foreground
+        })
+    }
+}
+
+fn parse_color(text: &str) -> Option<(u8, u8, u8)> {
+    let text = text.trim().trim_start_matches('#');
+    if text.len() != 6 {
+        return None;
+    }
+    match u32::from_str_radix(text, 16) {
+        Ok(rgb) => Some(((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)),
+        Err(_) => None
+    }
+}
+
+#[derive(Clone)]
 pub struct Screen {
     vram: [[bool;HEIGHT];WIDTH],
-    updated: bool
+    updated: bool,
+    pub palette: Palette
 }
 
 impl Screen {
     pub fn new() -> Screen {
         Screen {
             vram: [[false;HEIGHT];WIDTH],
-            updated: true
+            updated: true,
+            palette: Palette::new()
         }
     }
 
+    pub fn get_pixel(&self, x: usize, y: usize) -> bool {
+        self.vram[x][y]
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, lit: bool) {
+        self.vram[x][y] = lit;
+        self.updated = true;
+    }
+
+    //A copy to draw elsewhere when the screen changed since the last call
+    pub fn take_update(&mut self) -> Option<Screen> {
+        if !self.updated {
+            return None;
+        }
+        self.updated = false;
+        let mut copy = self.clone();
+        copy.updated = true;
+        Some(copy)
+    }
+
+    //Integer scale used when drawing to a renderer of the given output size
+    pub fn scale_for(output_size: (u32, u32)) -> (u32, u32) {
+        let x_scale = (output_size.0 as f64 / WIDTH as f64) as u32;
+        let y_scale = (output_size.1 as f64 / HEIGHT as f64) as u32;
+        (x_scale, y_scale)
+    }
+
     pub fn clear(&mut self) {
         for y in 0 .. HEIGHT {
             for x in 0 .. WIDTH {
@@ -50,27 +138,69 @@ impl Screen {
         return cleared;
     }
 
-    pub fn draw(&mut self, renderer: &mut sdl2::render::Renderer) {
-        if !self.updated {
-            return;
+    //Lit pixels are the same, whatever the palettes
+    pub fn same_pixels(&self, other: &Screen) -> bool {
+        self.vram == other.vram
+    }
+
+    //Draws into area, the left, top, width and height of a part of the renderer
+    fn draw_at(&self, renderer: &mut sdl2::render::Renderer, area: (i32, i32, u32, u32)) {
+        let (left, top, width, height) = area;
+        let (bg_r, bg_g, bg_b) = self.palette.background;
+        let (fg_r, fg_g, fg_b) = self.palette.foreground;
+        renderer.set_draw_color(sdl2::pixels::Color::RGB(bg_r, bg_g, bg_b));
+        if let Ok(Some(rect)) = sdl2::rect::Rect::new(left, top, width, height) {
+            renderer.fill_rect(rect);
         }
-        self.updated=false;
-        renderer.set_draw_color(sdl2::pixels::Color::RGB(0, 0, 0));
-        renderer.clear();
-        renderer.set_draw_color(sdl2::pixels::Color::RGB(255, 255, 255));
-        let output_size = renderer.output_size().expect("Renderer output_size error");
-
-        let x_scale = (output_size.0 as f64 / WIDTH as f64) as i32;
-        let y_scale = (output_size.1 as f64 / HEIGHT as f64) as i32;
+        renderer.set_draw_color(sdl2::pixels::Color::RGB(fg_r, fg_g, fg_b));
+
+        let (x_scale, y_scale) = Screen::scale_for((width, height));
+        let x_scale = x_scale as i32;
+        let y_scale = y_scale as i32;
         for y in 0 .. HEIGHT {
             for x in 0 .. WIDTH {
                 if self.vram[x as usize][y as usize] == true {
-                    renderer.set_draw_color(sdl2::pixels::Color::RGB(255, 255, 255));
-                    let rect = sdl2::rect::Rect::new(x as i32 * x_scale,(y as i32 * y_scale), x_scale as u32, y_scale as u32).unwrap().unwrap();
-                    renderer.fill_rect(rect);
+                    if let Ok(Some(rect)) = sdl2::rect::Rect::new(left + x as i32 * x_scale, top + y as i32 * y_scale, x_scale as u32, y_scale as u32) {
+                        renderer.fill_rect(rect);
+                    }
                 }
             }
         }
-        renderer.present();
     }
 }
+
+//Columns and rows to tile count screens in, two side by side and more in a grid
+pub fn tiles(count: usize) -> (u32, u32) {
+    let columns = ((count as f64).sqrt().ceil() as u32).max(1);
+    let rows = (count as u32).div_ceil(columns);
+    (columns, rows.max(1))
+}
+
+//Window size for count screens drawn at scale
+pub fn tiled_size(count: usize, scale: u32) -> (u32, u32) {
+    let (columns, rows) = tiles(count);
+    (WIDTH as u32 * scale * columns + TILE_GAP * (columns - 1),
+     HEIGHT as u32 * scale * rows + TILE_GAP * (rows - 1))
+}
+
+//Left, top, width and height of screen index when count screens share a
+//renderer of output_size
+pub fn tile_area(output_size: (u32, u32), count: usize, index: usize) -> (i32, i32, u32, u32) {
+    let (columns, rows) = tiles(count);
+    let width = output_size.0.saturating_sub(TILE_GAP * (columns - 1)) / columns;
+    let height = output_size.1.saturating_sub(TILE_GAP * (rows - 1)) / rows;
+    let (column, row) = (index as u32 % columns, index as u32 / columns);
+    ((column * (width + TILE_GAP)) as i32, (row * (height + TILE_GAP)) as i32, width, height)
+}
+
+//Draws the screens tiled, the gaps between them turn red while they differ
+pub fn draw_tiled(renderer: &mut sdl2::render::Renderer, screens: &[Screen], diverged: bool) {
+    let (r, g, b) = if diverged { DIVERGED } else { SEPARATOR };
+    renderer.set_draw_color(sdl2::pixels::Color::RGB(r, g, b));
+    renderer.clear();
+    let output_size = renderer.output_size().expect("Renderer output_size error");
+    for (index, screen) in screens.iter().enumerate() {
+        screen.draw_at(renderer, tile_area(output_size, screens.len(), index));
+    }
+    renderer.present();
+}
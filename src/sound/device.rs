@@ -0,0 +1,130 @@
+extern crate sdl2;
+
+use sound::generator::{BeeperConfig, Generator};
+use sound::queue;
+use sound::queue::{Consumer, Event, Producer};
+
+//How far the audio output may fall behind emulation before it skips ahead
+const MAX_LATENCY_FRAMES: u64 = 4;
+//How long a beep is held when the emulator has not produced the next frame yet
+const STARVE_GRACE_FRAMES: u64 = 2;
+
+pub struct Sound {
+    //Kept alive for as long as the beeper should play
+    _device: sdl2::audio::AudioDevice<Beeper>,
+    events: Producer,
+    frame: u64,
+    playing: bool
+}
+
+impl Sound {
+    pub fn new (audio_subsystem: &sdl2::AudioSubsystem, config: BeeperConfig, frame_rate: u32) -> Sound {
+        let (producer, consumer) = queue::channel(1024);
+        let device = setup_audio(audio_subsystem, config, frame_rate, consumer);
+        //The device keeps running, the beeper is switched by the queued events
+        device.resume();
+        Sound {
+            _device: device,
+            events: producer,
+            frame: 0,
+            playing: false
+        }
+    }
+
+    //Called once per emulated frame with the sound timer state for that frame
+    pub fn add_frame(&mut self, playing: bool) {
+        if playing != self.playing {
+            //If the queue is full the change is retried on the next frame
+            if self.events.push(Event { frame: self.frame, playing }) {
+                self.playing = playing;
+            }
+        }
+        self.frame += 1;
+        self.events.set_frames(self.frame);
+    }
+}
+
+fn setup_audio(audio_subsystem: &sdl2::AudioSubsystem, config: BeeperConfig, frame_rate: u32, events: Consumer) -> sdl2::audio::AudioDevice<Beeper>{
+    let desired_spec = sdl2::audio::AudioSpecDesired {
+        freq: Some(44100),
+        channels: Some(1),  // mono
+        samples: None       // default sample size
+    };
+
+    let device = audio_subsystem.open_playback(None, &desired_spec, |spec| {
+    // initialize the audio callback
+        let mut generator = Generator::new(spec.freq, config);
+        generator.set_playing(false);
+        Beeper {
+            generator,
+            events,
+            sample_rate: spec.freq as u64,
+            frame_rate: frame_rate as u64,
+            position: 0,
+            starved: 0,
+            gate: false
+        }}).unwrap();
+    return device;
+}
+
+//Plays the queued events back on the emulated timeline, so a beep lasts
+//exactly as many frames as the sound timer was set for regardless of how
+//the host loop or the audio device are scheduled.
+struct Beeper {
+    generator: Generator,
+    events: Consumer,
+    sample_rate: u64,
+    frame_rate: u64,
+    //Emulated time reached by the output, in samples
+    position: u64,
+    //Samples output while waiting for the emulator
+    starved: u64,
+    //Beeper state at the current position
+    gate: bool
+}
+
+impl Beeper {
+    fn frame_to_sample(&self, frame: u64) -> u64 {
+        frame * self.sample_rate / self.frame_rate
+    }
+
+    fn apply_events(&mut self) {
+        while let Some(event) = self.events.peek() {
+            if self.frame_to_sample(event.frame) > self.position {
+                break;
+            }
+            self.gate = event.playing;
+            self.events.pop();
+        }
+    }
+}
+
+impl sdl2::audio::AudioCallback for Beeper {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        let available = self.frame_to_sample(self.events.frames());
+        let frame_samples = self.frame_to_sample(1);
+
+        //Emulation ran ahead, e.g. after a stall, so drop the backlog
+        let target = out.len() as u64 + frame_samples;
+        if available > self.position + target + MAX_LATENCY_FRAMES * frame_samples {
+            self.position = available - target;
+        }
+
+        for x in out.iter_mut() {
+            if self.position < available {
+                self.apply_events();
+                self.generator.set_playing(self.gate);
+                self.position += 1;
+                self.starved = 0;
+            } else {
+                self.starved += 1;
+                if self.starved > STARVE_GRACE_FRAMES * frame_samples {
+                    self.generator.set_playing(false);
+                }
+            }
+            *x = self.generator.next_sample();
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use std::f32::consts::PI;
+
+//Produces the beeper samples independently of any audio device, so the same
+//output can be played through SDL or written to a file.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Waveform {
+    Square,
+    Triangle,
+    Sine,
+    Noise
+}
+
+impl Waveform {
+    pub fn from_name(name: &str) -> Option<Waveform> {
+        match &name.to_lowercase()[..] {
+            "square" => Some(Waveform::Square),
+            "triangle" => Some(Waveform::Triangle),
+            "sine" => Some(Waveform::Sine),
+            "noise" => Some(Waveform::Noise),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BeeperConfig {
+    pub frequency: f32,
+    pub waveform: Waveform,
+    pub volume: f32,
+    //Envelope times in milliseconds, ramping the tone in and out avoids a
+    //click every time the sound timer starts or stops
+    pub attack: f32,
+    pub release: f32
+}
+
+impl BeeperConfig {
+    pub fn new() -> BeeperConfig {
+        BeeperConfig {
+            frequency: 440.0,
+            waveform: Waveform::Square,
+            volume: 0.25,
+            attack: 2.0,
+            release: 10.0
+        }
+    }
+}
+
+pub struct Generator {
+    config: BeeperConfig,
+    phase_inc: f32,
+    phase: f32,
+    attack_inc: f32,
+    release_dec: f32,
+    gain: f32,
+    noise: u32,
+    noise_value: f32,
+    playing: bool
+}
+
+impl Generator {
+    pub fn new(sample_rate: i32, config: BeeperConfig) -> Generator {
+        Generator {
+            config,
+            phase_inc: config.frequency / sample_rate as f32,
+            phase: 0.0,
+            attack_inc: envelope_step(config.attack, sample_rate),
+            release_dec: envelope_step(config.release, sample_rate),
+            gain: 0.0,
+            noise: 0xACE1,
+            noise_value: 1.0,
+            playing: true
+        }
+    }
+
+    pub fn set_playing(&mut self, playing: bool) {
+        self.playing = playing;
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        if self.playing {
+            self.gain = (self.gain + self.attack_inc).min(1.0);
+        } else {
+            self.gain = (self.gain - self.release_dec).max(0.0);
+        }
+        if self.gain <= 0.0 {
+            return 0.0;
+        }
+
+        let wave = match self.config.waveform {
+            Waveform::Square => {
+                if self.phase <= 0.5 { 1.0 } else { -1.0 }
+            }
+            Waveform::Triangle => {
+                1.0 - 4.0 * (self.phase - 0.5).abs()
+            }
+            Waveform::Sine => {
+                (self.phase * 2.0 * PI).sin()
+            }
+            Waveform::Noise => {
+                self.noise_value
+            }
+        };
+        let next_phase = self.phase + self.phase_inc;
+        if next_phase >= 1.0 && self.config.waveform == Waveform::Noise {
+            //Pick a new level once per period so the frequency sets the pitch of the noise
+            self.noise ^= self.noise << 13;
+            self.noise ^= self.noise >> 17;
+            self.noise ^= self.noise << 5;
+            self.noise_value = if self.noise & 1 != 0 { 1.0 } else { -1.0 };
+        }
+        self.phase = next_phase % 1.0;
+        wave * self.config.volume * self.gain
+    }
+
+    pub fn fill(&mut self, out: &mut [f32]) {
+        for x in out.iter_mut() {
+            *x = self.next_sample();
+        }
+    }
+}
+
+//Per sample gain change for an envelope of the given length
+fn envelope_step(ms: f32, sample_rate: i32) -> f32 {
+    let samples = ms * sample_rate as f32 / 1000.0;
+    if samples < 1.0 {
+        1.0
+    } else {
+        1.0 / samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BeeperConfig, Generator, Waveform};
+
+    //At 1000 Hz every sample is a millisecond, and the square wave stays
+    //high long enough that the samples are the envelope's gain
+    fn generator(attack: f32, release: f32) -> Generator {
+        let config = BeeperConfig {
+            frequency: 1.0,
+            waveform: Waveform::Square,
+            volume: 1.0,
+            attack,
+            release
+        };
+        Generator::new(1000, config)
+    }
+
+    fn samples(generator: &mut Generator, count: usize) -> Vec<f32> {
+        let mut out = vec![0.0; count];
+        generator.fill(&mut out);
+        out
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((a - e).abs() < 1e-5, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn attack_ramps_up_then_holds() {
+        let mut generator = generator(4.0, 10.0);
+        assert_close(&samples(&mut generator, 6), &[0.25, 0.5, 0.75, 1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn release_ramps_down_to_silence() {
+        let mut generator = generator(1.0, 4.0);
+        samples(&mut generator, 2);
+        generator.set_playing(false);
+        assert_close(&samples(&mut generator, 6), &[0.75, 0.5, 0.25, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn release_starts_from_the_current_gain() {
+        let mut generator = generator(4.0, 4.0);
+        samples(&mut generator, 2);
+        generator.set_playing(false);
+        assert_close(&samples(&mut generator, 3), &[0.25, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn envelopes_shorter_than_a_sample_switch_at_once() {
+        let mut generator = generator(0.0, 0.5);
+        assert_close(&samples(&mut generator, 2), &[1.0, 1.0]);
+        generator.set_playing(false);
+        assert_close(&samples(&mut generator, 2), &[0.0, 0.0]);
+    }
+}
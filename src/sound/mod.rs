@@ -0,0 +1,4 @@
+pub mod device;
+pub mod generator;
+pub mod queue;
+pub mod wav;
@@ -0,0 +1,98 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+//Single producer, single consumer queue of beeper events.
+//The emulator pushes a timestamped event whenever the sound timer turns the
+//beeper on or off, and the audio callback pops them without ever blocking on
+//a lock held by the emulation loop.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Event {
+    //Emulated frame the change takes effect on
+    pub frame: u64,
+    pub playing: bool
+}
+
+impl Event {
+    fn pack(&self) -> u64 {
+        (self.frame << 1) | self.playing as u64
+    }
+
+    fn unpack(value: u64) -> Event {
+        Event {
+            frame: value >> 1,
+            playing: value & 1 != 0
+        }
+    }
+}
+
+struct Shared {
+    slots: Vec<AtomicU64>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    //Number of frames the emulator has produced so far
+    frames: AtomicU64
+}
+
+pub struct Producer {
+    shared: Arc<Shared>
+}
+
+pub struct Consumer {
+    shared: Arc<Shared>
+}
+
+pub fn channel(capacity: usize) -> (Producer, Consumer) {
+    let mut slots = Vec::with_capacity(capacity + 1);
+    for _ in 0..capacity + 1 {
+        slots.push(AtomicU64::new(0));
+    }
+    let shared = Arc::new(Shared {
+        slots,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        frames: AtomicU64::new(0)
+    });
+    (Producer { shared: shared.clone() }, Consumer { shared })
+}
+
+impl Producer {
+    //Returns false if the queue is full and the event was not added
+    pub fn push(&mut self, event: Event) -> bool {
+        let tail = self.shared.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % self.shared.slots.len();
+        if next == self.shared.head.load(Ordering::Acquire) {
+            return false;
+        }
+        self.shared.slots[tail].store(event.pack(), Ordering::Relaxed);
+        self.shared.tail.store(next, Ordering::Release);
+        true
+    }
+
+    pub fn set_frames(&mut self, frames: u64) {
+        self.shared.frames.store(frames, Ordering::Release);
+    }
+}
+
+impl Consumer {
+    pub fn peek(&self) -> Option<Event> {
+        let head = self.shared.head.load(Ordering::Relaxed);
+        if head == self.shared.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        Some(Event::unpack(self.shared.slots[head].load(Ordering::Relaxed)))
+    }
+
+    pub fn pop(&mut self) -> Option<Event> {
+        let event = self.peek();
+        if event.is_some() {
+            let head = self.shared.head.load(Ordering::Relaxed);
+            self.shared.head.store((head + 1) % self.shared.slots.len(), Ordering::Release);
+        }
+        event
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.shared.frames.load(Ordering::Acquire)
+    }
+}
@@ -0,0 +1,154 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::path::Path;
+
+use sound::generator::{BeeperConfig, Generator};
+
+//16 bit mono PCM WAV writer.
+//The chunk sizes are patched in when the writer is finished.
+
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    samples: u32
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> io::Result<WavWriter<W>> {
+        out.write_all(b"RIFF")?;
+        out.write_all(&le32(0))?;
+        out.write_all(b"WAVE")?;
+        out.write_all(b"fmt ")?;
+        out.write_all(&le32(16))?;
+        out.write_all(&le16(1))?; //PCM
+        out.write_all(&le16(1))?; //mono
+        out.write_all(&le32(sample_rate))?;
+        out.write_all(&le32(sample_rate * 2))?; //byte rate
+        out.write_all(&le16(2))?; //block align
+        out.write_all(&le16(16))?; //bits per sample
+        out.write_all(b"data")?;
+        out.write_all(&le32(0))?;
+        Ok(WavWriter {
+            out,
+            samples: 0
+        })
+    }
+
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(samples.len() * 2);
+        for &sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * 32767.0) as i16 as u16;
+            bytes.extend_from_slice(&le16(value));
+        }
+        self.samples += samples.len() as u32;
+        self.out.write_all(&bytes)
+    }
+
+    pub fn samples_written(&self) -> u32 {
+        self.samples
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        let data_size = self.samples * 2;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&le32(36 + data_size))?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&le32(data_size))?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+//Renders the beeper into a WAV file one emulated frame at a time.
+//Works the same whether frames come from the realtime loop or a headless run,
+//the file length only depends on how many frames were emulated.
+pub struct WavCapture {
+    writer: WavWriter<BufWriter<File>>,
+    generator: Generator,
+    sample_rate: u32,
+    frames: u64
+}
+
+impl WavCapture {
+    pub fn create(path: &Path, sample_rate: u32, config: BeeperConfig) -> io::Result<WavCapture> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(WavCapture {
+            writer: WavWriter::new(file, sample_rate)?,
+            generator: Generator::new(sample_rate as i32, config),
+            sample_rate,
+            frames: 0
+        })
+    }
+
+    //Appends one frame of audio with the beeper on or off
+    pub fn add_frame(&mut self, playing: bool, frame_rate: u32) -> io::Result<()> {
+        //Work out the frame boundaries in samples so that rates which do not
+        //divide the sample rate evenly do not drift
+        let start = self.frames * self.sample_rate as u64 / frame_rate as u64;
+        self.frames += 1;
+        let end = self.frames * self.sample_rate as u64 / frame_rate as u64;
+        let mut samples = vec![0.0; (end - start) as usize];
+        self.generator.set_playing(playing);
+        self.generator.fill(&mut samples);
+        self.writer.write_samples(&samples)
+    }
+
+    pub fn finish(self) -> io::Result<u32> {
+        let samples = self.writer.samples_written();
+        self.writer.finish()?;
+        Ok(samples)
+    }
+}
+
+fn le16(value: u16) -> [u8;2] {
+    [value as u8, (value >> 8) as u8]
+}
+
+fn le32(value: u32) -> [u8;4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use sound::generator::BeeperConfig;
+    use super::WavCapture;
+
+    fn frame_lengths(sample_rate: u32, frame_rate: u32, frames: u32) -> (Vec<u64>, u32) {
+        let path = env::temp_dir().join(format!("chipy8-wav-test-{}-{}-{}.wav", process::id(), sample_rate, frame_rate));
+        let mut capture = WavCapture::create(&path, sample_rate, BeeperConfig::new()).unwrap();
+        let mut lengths = vec![];
+        let mut written = 0;
+        for frame in 0..frames {
+            capture.add_frame(frame % 2 == 0, frame_rate).unwrap();
+            let total = capture.writer.samples_written() as u64;
+            lengths.push(total - written);
+            written = total;
+        }
+        let samples = capture.finish().unwrap();
+        let size = fs::metadata(&path).unwrap().len();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(size, 44 + samples as u64 * 2);
+        (lengths, samples)
+    }
+
+    #[test]
+    fn frames_divide_the_sample_rate_evenly() {
+        let (lengths, samples) = frame_lengths(44100, 60, 60);
+        assert!(lengths.iter().all(|&l| l == 735));
+        assert_eq!(samples, 44100);
+    }
+
+    #[test]
+    fn uneven_frames_do_not_drift() {
+        //16.67 samples a frame
+        let (lengths, samples) = frame_lengths(1000, 60, 120);
+        assert!(lengths.iter().all(|&l| l == 16 || l == 17));
+        assert_eq!(samples, 2000);
+    }
+}
@@ -0,0 +1,89 @@
+extern crate libc;
+
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+
+//Takes over the terminal for the emulator: keys are read one at a time as
+//they are typed, without echo, and the screen is drawn on the alternate
+//screen with the cursor hidden. Everything is put back when dropped.
+
+const ENTER: &str = "\x1b[?1049h\x1b[?25l\x1b[2J";
+const LEAVE: &str = "\x1b[0m\x1b[?25h\x1b[?1049l";
+const HOME: &str = "\x1b[H";
+//Clears the rest of the line
+const CLEAR_LINE: &str = "\x1b[K";
+
+pub struct RawTerminal {
+    original: libc::termios
+}
+
+impl RawTerminal {
+    pub fn enable() -> io::Result<RawTerminal> {
+        let mut termios: libc::termios = unsafe { mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let original = termios;
+        unsafe { libc::cfmakeraw(&mut termios) };
+        //Keep turning \n into \r\n, so messages printed while running still start a line
+        termios.c_oflag |= libc::OPOST;
+        //Reads return straight away with whatever was typed
+        termios.c_cc[libc::VMIN] = 0;
+        termios.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let terminal = RawTerminal { original };
+        terminal.write(ENTER)?;
+        Ok(terminal)
+    }
+
+    //The bytes typed since the last call, without waiting for more
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![];
+        let mut buffer = [0u8; 64];
+        loop {
+            let count = unsafe { libc::read(libc::STDIN_FILENO, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+            if count < 0 {
+                let why = io::Error::last_os_error();
+                if why.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(why);
+            }
+            if count == 0 {
+                return Ok(bytes);
+            }
+            bytes.extend_from_slice(&buffer[..count as usize]);
+        }
+    }
+
+    //Draws the lines from the top left corner over what was there
+    pub fn draw(&self, lines: &[String]) -> io::Result<()> {
+        let mut text = HOME.to_string();
+        for (index, line) in lines.iter().enumerate() {
+            if index > 0 {
+                text.push('\n');
+            }
+            text.push_str(line);
+            text.push_str(CLEAR_LINE);
+        }
+        self.write(&text)
+    }
+
+    fn write(&self, text: &str) -> io::Result<()> {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        out.write_all(text.as_bytes())?;
+        out.flush()
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        //Nothing more can be done if the terminal is gone
+        let _ = self.write(LEAVE);
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original) };
+    }
+}
@@ -0,0 +1,80 @@
+use screen::{Screen, HEIGHT, WIDTH};
+
+//Draws the screen as text with 24 bit colour escape codes, either with
+//half blocks, where the upper half of a character is one pixel and the lower
+//half the one below it, or with braille, a 2x4 block of pixels per character
+//for small terminals.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Style {
+    //64x16 characters in the palette's colours
+    HalfBlock,
+    //32x8 characters
+    Braille
+}
+
+const UPPER_HALF: char = '\u{2580}';
+const BRAILLE: u32 = 0x2800;
+//Dot bits of a braille character, by row then column
+static DOTS: [[u32;2];4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+const RESET: &str = "\x1b[0m";
+
+//One string per line of text
+pub fn render(screen: &Screen, style: Style) -> Vec<String> {
+    match style {
+        Style::HalfBlock => half_blocks(screen),
+        Style::Braille => braille(screen)
+    }
+}
+
+fn half_blocks(screen: &Screen) -> Vec<String> {
+    let colour = |lit: bool| if lit { screen.palette.foreground } else { screen.palette.background };
+    let mut lines = vec![];
+    for row in 0..HEIGHT / 2 {
+        let mut line = String::new();
+        //Colours are only given when they change along the line
+        let mut last = None;
+        for x in 0..WIDTH {
+            let pixels = (screen.get_pixel(x, row * 2), screen.get_pixel(x, row * 2 + 1));
+            if last != Some(pixels) {
+                line.push_str(&foreground(colour(pixels.0)));
+                line.push_str(&background(colour(pixels.1)));
+                last = Some(pixels);
+            }
+            line.push(UPPER_HALF);
+        }
+        line.push_str(RESET);
+        lines.push(line);
+    }
+    lines
+}
+
+fn braille(screen: &Screen) -> Vec<String> {
+    let mut lines = vec![];
+    for row in 0..HEIGHT / 4 {
+        let mut line = foreground(screen.palette.foreground);
+        line.push_str(&background(screen.palette.background));
+        for column in 0..WIDTH / 2 {
+            let mut dots = 0;
+            for (y, bits) in DOTS.iter().enumerate() {
+                for (x, bit) in bits.iter().enumerate() {
+                    if screen.get_pixel(column * 2 + x, row * 4 + y) {
+                        dots |= bit;
+                    }
+                }
+            }
+            line.push(::std::char::from_u32(BRAILLE + dots).unwrap_or(' '));
+        }
+        line.push_str(RESET);
+        lines.push(line);
+    }
+    lines
+}
+
+fn foreground((r, g, b): (u8, u8, u8)) -> String {
+    format!("\x1b[38;2;{};{};{}m", r, g, b)
+}
+
+fn background((r, g, b): (u8, u8, u8)) -> String {
+    format!("\x1b[48;2;{};{};{}m", r, g, b)
+}
@@ -0,0 +1,102 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+//Polls files for changes, the ROM and the patches applied to it. Polling
+//works the same everywhere and checking the metadata of a few files a few
+//times a second costs nothing.
+
+const POLL_INTERVAL_MS: u64 = 250;
+
+#[derive(PartialEq)]
+struct Stamp {
+    modified: Option<SystemTime>,
+    len: u64
+}
+
+struct File {
+    path: PathBuf,
+    last: Option<Stamp>,
+    //A change that is only reported once the file stops changing, so a
+    //ROM that is still being written by the assembler isn't picked up
+    pending: Option<Stamp>
+}
+
+pub struct Watcher {
+    files: Vec<File>,
+    last_poll: Instant
+}
+
+impl Watcher {
+    pub fn new(paths: &[PathBuf]) -> Watcher {
+        Watcher {
+            files: paths.iter().map(|path| File { path: path.clone(), last: stamp(path), pending: None }).collect(),
+            last_poll: Instant::now()
+        }
+    }
+
+    //True once after any of the files was modified and has settled
+    pub fn changed(&mut self) -> bool {
+        if self.last_poll.elapsed() < Duration::from_millis(POLL_INTERVAL_MS) {
+            return false;
+        }
+        self.last_poll = Instant::now();
+        //A reload starts a new watcher, so the other files needn't be polled
+        self.files.iter_mut().any(|file| file.changed())
+    }
+}
+
+impl File {
+    fn changed(&mut self) -> bool {
+        let current = stamp(&self.path);
+        if current.is_none() || current == self.last {
+            //Deleted files are ignored until they come back, editors often
+            //replace a file by removing and recreating it
+            self.pending = None;
+            return false;
+        }
+        if current == self.pending {
+            self.last = self.pending.take();
+            return true;
+        }
+        self.pending = current;
+        false
+    }
+}
+
+fn stamp(path: &Path) -> Option<Stamp> {
+    match fs::metadata(path) {
+        Ok(metadata) => Some(Stamp { modified: metadata.modified().ok(), len: metadata.len() }),
+        Err(_) => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+    use super::{Watcher, POLL_INTERVAL_MS};
+
+    #[test]
+    fn patch_changes_are_seen() {
+        let directory = env::temp_dir().join(format!("chipy8-watch-{}", ::std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let rom = directory.join("GAME");
+        let patch = directory.join("GAME.ips");
+        fs::write(&rom, [0x12, 0x00]).unwrap();
+        fs::write(&patch, b"PATCHEOF").unwrap();
+        let mut watcher = Watcher::new(&[rom, patch.clone()]);
+        fs::write(&patch, b"PATCH\0\0\0\0\x01\0EOF").unwrap();
+        //Seen on one poll, reported on the next once it stayed the same
+        let mut polls = 0;
+        while !watcher.changed() {
+            polls += 1;
+            assert!(polls < 100, "the patch change was never reported");
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS / 5));
+        }
+        assert!(!watcher.changed());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}